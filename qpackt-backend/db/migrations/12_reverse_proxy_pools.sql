@@ -0,0 +1,32 @@
+-- SPDX-License-Identifier: AGPL-3.0
+--
+--   qpackt: Web & Analytics Server
+--   Copyright (C) 2023 Łukasz Wojtów
+
+--   This program is free software: you can redistribute it and/or modify
+--   it under the terms of the GNU Affero General Public License as
+--   published by the Free Software Foundation, either version 3 of the
+--   License.
+
+--   This program is distributed in the hope that it will be useful,
+--   but WITHOUT ANY WARRANTY; without even the implied warranty of
+--   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+--   GNU Affero General Public License for more details.
+
+--   You should have received a copy of the GNU Affero General Public License
+--   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+CREATE TABLE reverse_proxy_target
+(
+    id       INTEGER PRIMARY KEY AUTOINCREMENT,
+    proxy_id INTEGER NOT NULL,
+    target   TEXT    NOT NULL
+);
+
+INSERT INTO reverse_proxy_target (proxy_id, target)
+SELECT id, target
+FROM reverse_proxy;
+
+ALTER TABLE reverse_proxy DROP COLUMN target;
+ALTER TABLE reverse_proxy ADD COLUMN balancing TEXT NOT NULL DEFAULT 'round_robin';
+ALTER TABLE reverse_proxy ADD COLUMN health_check_path TEXT;
+ALTER TABLE reverse_proxy ADD COLUMN max_failures INTEGER NOT NULL DEFAULT 3;
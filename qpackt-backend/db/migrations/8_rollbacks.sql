@@ -0,0 +1,30 @@
+-- SPDX-License-Identifier: AGPL-3.0
+--
+--   qpackt: Web & Analytics Server
+--   Copyright (C) 2023 Łukasz Wojtów
+
+--   This program is free software: you can redistribute it and/or modify
+--   it under the terms of the GNU Affero General Public License as
+--   published by the Free Software Foundation, either version 3 of the
+--   License.
+
+--   This program is distributed in the hope that it will be useful,
+--   but WITHOUT ANY WARRANTY; without even the implied warranty of
+--   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+--   GNU Affero General Public License for more details.
+
+--   You should have received a copy of the GNU Affero General Public License
+--   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+ALTER TABLE requests ADD COLUMN status INTEGER NOT NULL DEFAULT 200;
+CREATE INDEX request_time_idx ON requests (time);
+
+ALTER TABLE rollouts ADD COLUMN max_bounce_rate_increase REAL;
+ALTER TABLE rollouts ADD COLUMN max_error_rate_increase REAL;
+
+CREATE TABLE rollbacks
+(
+    version TEXT    NOT NULL,
+    time    INTEGER NOT NULL,
+    reason  TEXT    NOT NULL
+);
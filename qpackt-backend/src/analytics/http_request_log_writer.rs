@@ -89,19 +89,22 @@ async fn save_requests(dao: &Dao, buffer: &mut Vec<CreateHttpRequestLog>) {
 }
 
 /// 'Merges' [CreateHttpRequestLog]s into separate [Visit]s so that they can be shown in analytics.
-/// Uses [VisitorHash] to recognize requests from the same client.
+/// Uses [VisitorHash] to recognize requests from the same client. Reverse proxy requests (e.g. API calls made by
+/// the page) are not page views, so they don't make visits.
 fn merge_requests(requests: Vec<CreateHttpRequestLog>) -> Vec<Visit> {
     let mut visits = HashMap::with_capacity(requests.len());
-    for r in requests {
+    for r in requests.into_iter().filter(|r| !r.proxied) {
         let visit = visits.entry(r.visitor).or_insert_with(|| Visit {
             first_request_time: r.time,
             last_request_time: r.time,
             request_count: 0,
             visitor: r.visitor,
             version: r.version,
+            experiments: Default::default(),
         });
         visit.request_count += 1;
         visit.last_request_time = r.time;
+        visit.experiments = r.experiments;
     }
     visits.into_values().collect()
 }
@@ -0,0 +1,174 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Browser caching of versions' files. `Cache-Control` comes from per-version rules keyed by glob (e.g. `assets/**`
+//! -> `public, max-age=31536000, immutable`). ETags include version's name, so that a client switched to another
+//! version never revalidates a file of the previous one, and responses vary by `Cookie` that holds the version.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fs::Metadata;
+use std::hash::{Hash, Hasher};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::http::header::{EntityTag, Header, HeaderValue, HttpDate, IfModifiedSince, IfNoneMatch, IF_NONE_MATCH};
+use actix_web::HttpRequest;
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::compression::Encoding;
+use crate::dao::version::VersionName;
+use crate::error::{QpacktError, Result};
+
+/// `Cache-Control` for files matching the glob. `*` matches within a segment, `**` matches any number of segments.
+/// Globs without `/` match file names in any directory (`*.html`), others match paths from web root (`assets/**`).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct CacheRule {
+    pub(crate) pattern: String,
+    pub(crate) cache_control: String,
+}
+
+impl CacheRule {
+    pub(crate) fn validate(&self) -> Result<()> {
+        glob_regex(&self.pattern)?;
+        HeaderValue::from_str(&self.cache_control)
+            .map_err(|_| QpacktError::InvalidRequest(format!("Invalid Cache-Control `{}`", self.cache_control)))?;
+        Ok(())
+    }
+}
+
+/// Compiled [CacheRule]s of a version, the first matching rule wins.
+#[derive(Default)]
+pub(crate) struct CacheRules(Vec<(Regex, bool, HeaderValue)>);
+
+impl CacheRules {
+    /// Invalid rules (rejected when saved anyway) are skipped.
+    pub(crate) fn new(rules: &[CacheRule]) -> Self {
+        let compiled = rules
+            .iter()
+            .filter_map(|rule| match (glob_regex(&rule.pattern), HeaderValue::from_str(&rule.cache_control)) {
+                (Ok(regex), Ok(value)) => Some((regex, !rule.pattern.trim_start_matches('/').contains('/'), value)),
+                _ => {
+                    warn!("Skipping invalid cache rule {:?}", rule);
+                    None
+                }
+            })
+            .collect();
+        Self(compiled)
+    }
+
+    /// `Cache-Control` for a path relative to web root.
+    pub(crate) fn cache_control(&self, path: &str) -> Option<&HeaderValue> {
+        let name = path.rsplit('/').next().unwrap_or(path);
+        self.0.iter().find(|(regex, by_name, _)| regex.is_match(if *by_name { name } else { path })).map(|(_, _, value)| value)
+    }
+}
+
+pub(crate) fn glob_regex(pattern: &str) -> Result<Regex> {
+    let mut regex = String::from("^");
+    let mut chars = pattern.trim_start_matches('/').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                if chars.next_if_eq(&'/').is_some() {
+                    regex.push_str("(?:.*/)?");
+                } else {
+                    regex.push_str(".*");
+                }
+            }
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    regex.push('$');
+    Regex::new(&regex).map_err(|e| QpacktError::InvalidRequest(format!("Invalid cache rule pattern `{}`: {}", pattern, e)))
+}
+
+/// Strong ETag of a file as served by a version, in given encoding.
+pub(crate) fn etag(version: &VersionName, metadata: &Metadata, encoding: Option<Encoding>) -> EntityTag {
+    let mut hasher = DefaultHasher::new();
+    version.hash(&mut hasher);
+    metadata.len().hash(&mut hasher);
+    metadata.modified().ok().hash(&mut hasher);
+    encoding.hash(&mut hasher);
+    EntityTag::new_strong(format!("{:016x}", hasher.finish()))
+}
+
+/// Modification time in whole seconds, as sent in `Last-Modified`.
+pub(crate) fn last_modified(metadata: &Metadata) -> Option<SystemTime> {
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(modified.as_secs()))
+}
+
+/// Whether the client's copy is current. `If-Modified-Since` is only checked without `If-None-Match`.
+pub(crate) fn is_not_modified(request: &HttpRequest, etag: &EntityTag, last_modified: Option<SystemTime>) -> bool {
+    if let Some(if_none_match) = request.headers().contains_key(IF_NONE_MATCH).then(|| IfNoneMatch::parse(request).ok()).flatten() {
+        return match if_none_match {
+            IfNoneMatch::Any => true,
+            IfNoneMatch::Items(tags) => tags.iter().any(|tag| tag.weak_eq(etag)),
+        };
+    }
+    match (IfModifiedSince::parse(request), last_modified) {
+        (Ok(IfModifiedSince(since)), Some(modified)) => HttpDate::from(modified) <= since,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::header::{self, EntityTag, HttpDate};
+    use actix_web::test::TestRequest;
+    use std::time::{Duration, SystemTime};
+
+    use crate::cache_rules::{is_not_modified, CacheRule, CacheRules};
+
+    fn rule(pattern: &str, cache_control: &str) -> CacheRule {
+        CacheRule { pattern: pattern.into(), cache_control: cache_control.into() }
+    }
+
+    #[test]
+    fn matches_globs() {
+        let rules = CacheRules::new(&[rule("assets/**", "immutable"), rule("*.html", "no-cache"), rule("/img/*.png", "max-age=60")]);
+        assert_eq!(rules.cache_control("assets/js/app.1a2b.js").unwrap(), "immutable");
+        assert_eq!(rules.cache_control("docs/index.html").unwrap(), "no-cache");
+        assert_eq!(rules.cache_control("img/logo.png").unwrap(), "max-age=60");
+        assert_eq!(rules.cache_control("img/icons/logo.png"), None);
+        assert_eq!(rules.cache_control("app.js"), None);
+        assert!(rule("**/*.css", "x").validate().is_ok());
+        assert!(rule("*.css", "bad\nvalue").validate().is_err());
+    }
+
+    #[test]
+    fn evaluates_preconditions() {
+        let etag = EntityTag::new_strong("abc".into());
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let request = TestRequest::default().insert_header((header::IF_NONE_MATCH, "W/\"abc\", \"def\"")).to_http_request();
+        assert!(is_not_modified(&request, &etag, Some(modified)));
+        let request = TestRequest::default()
+            .insert_header((header::IF_NONE_MATCH, "\"def\""))
+            .insert_header((header::IF_MODIFIED_SINCE, HttpDate::from(modified)))
+            .to_http_request();
+        assert!(!is_not_modified(&request, &etag, Some(modified)));
+        let request = TestRequest::default().insert_header((header::IF_MODIFIED_SINCE, HttpDate::from(modified))).to_http_request();
+        assert!(is_not_modified(&request, &etag, Some(modified)));
+        assert!(!is_not_modified(&request, &etag, Some(modified + Duration::from_secs(1))));
+    }
+}
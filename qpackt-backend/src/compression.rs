@@ -0,0 +1,283 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Compression of static files. Clients get brotli or gzip according to `Accept-Encoding`: precompressed `.br` and
+//! `.gz` siblings of files are served when they exist, other compressible files are compressed on the fly and kept
+//! in [CompressionCache].
+
+use std::collections::{HashMap, VecDeque};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use actix_web::http::header::{ContentEncoding, HeaderMap, ACCEPT_ENCODING};
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::debug;
+
+/// Smaller files don't get smaller enough to be worth it.
+pub(crate) const MIN_COMPRESSED_SIZE: u64 = 256;
+/// Bigger files are not compressed on the fly.
+pub(crate) const MAX_COMPRESSED_SIZE: u64 = 4 * 1024 * 1024;
+/// Bytes of compressed files kept per version.
+const CACHE_SIZE: usize = 16 * 1024 * 1024;
+/// Fast enough to compress on the fly.
+const BROTLI_QUALITY: u32 = 5;
+/// Used when files are compressed once, at upload.
+const BROTLI_BEST_QUALITY: u32 = 11;
+const BROTLI_WINDOW: u32 = 22;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum Encoding {
+    Brotli,
+    Gzip,
+}
+
+impl Encoding {
+    /// Extension of precompressed sibling files.
+    pub(crate) fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Gzip => "gz",
+        }
+    }
+
+    pub(crate) fn content_encoding(&self) -> ContentEncoding {
+        match self {
+            Encoding::Brotli => ContentEncoding::Brotli,
+            Encoding::Gzip => ContentEncoding::Gzip,
+        }
+    }
+
+    /// Path of precompressed sibling, e.g. `app.js.br`.
+    pub(crate) fn sibling(&self, path: &Path) -> PathBuf {
+        let mut path = path.as_os_str().to_owned();
+        path.push(".");
+        path.push(self.extension());
+        path.into()
+    }
+
+    fn compress(&self, content: &[u8], best: bool) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let quality = if best { BROTLI_BEST_QUALITY } else { BROTLI_QUALITY };
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, quality, BROTLI_WINDOW);
+                writer.write_all(content)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Gzip => {
+                let level = if best { Compression::best() } else { Compression::default() };
+                let mut encoder = GzEncoder::new(Vec::new(), level);
+                encoder.write_all(content)?;
+                encoder.finish()
+            }
+        }
+    }
+}
+
+/// Picks encoding from `Accept-Encoding`, brotli wins ties. Encodings with `q=0` are refused.
+pub(crate) fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+    let mut best: Option<(f32, Encoding)> = None;
+    let values = headers.get_all(ACCEPT_ENCODING).filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(','));
+    for value in values {
+        let mut parts = value.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default().to_ascii_lowercase();
+        let quality = parts.find_map(|p| p.strip_prefix("q=")).map_or(Some(1.0), |q| q.parse::<f32>().ok()).unwrap_or(0.0);
+        let encodings: &[Encoding] = match name.as_str() {
+            "br" => &[Encoding::Brotli],
+            "gzip" | "x-gzip" => &[Encoding::Gzip],
+            "*" => &[Encoding::Brotli, Encoding::Gzip],
+            _ => &[],
+        };
+        for encoding in encodings {
+            let better = best.is_none_or(|(q, e)| quality > q || (quality == q && *encoding == Encoding::Brotli && e != Encoding::Brotli));
+            if quality > 0.0 && better {
+                best = Some((quality, *encoding));
+            }
+        }
+    }
+    best.map(|(_, encoding)| encoding)
+}
+
+/// Text based types, images and archives are already compressed.
+pub(crate) fn is_compressible(content_type: &str) -> bool {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+    essence.starts_with("text/")
+        || essence.ends_with("+xml")
+        || essence.ends_with("+json")
+        || matches!(essence, "application/javascript" | "application/json" | "application/xml" | "application/wasm" | "image/x-icon")
+}
+
+/// File's version, so that a replaced file isn't served from cache.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct FileKey {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl FileKey {
+    pub(crate) fn new(path: &Path, metadata: &std::fs::Metadata) -> Self {
+        Self { path: path.to_path_buf(), modified: metadata.modified().ok(), len: metadata.len() }
+    }
+}
+
+/// Files compressed on the fly. The oldest are dropped when the cache is full.
+#[derive(Default)]
+pub(crate) struct CompressionCache {
+    inner: Mutex<CacheInner>,
+}
+
+#[derive(Default)]
+struct CacheInner {
+    entries: HashMap<(FileKey, Encoding), Bytes>,
+    order: VecDeque<(FileKey, Encoding)>,
+    size: usize,
+}
+
+impl CompressionCache {
+    /// Compressed content of the file, compressed now if it's not cached yet.
+    pub(crate) async fn get(&self, key: FileKey, encoding: Encoding) -> std::io::Result<Bytes> {
+        let cache_key = (key, encoding);
+        if let Some(content) = self.inner.lock().unwrap().entries.get(&cache_key) {
+            return Ok(content.clone());
+        }
+        let path = cache_key.0.path.clone();
+        let content = tokio::task::spawn_blocking(move || encoding.compress(&std::fs::read(path)?, false)).await??;
+        let content = Bytes::from(content);
+        debug!("Compressed {:?} with {:?}", cache_key.0.path, encoding);
+        let mut inner = self.inner.lock().unwrap();
+        if content.len() <= CACHE_SIZE && !inner.entries.contains_key(&cache_key) {
+            while inner.size + content.len() > CACHE_SIZE {
+                let Some(oldest) = inner.order.pop_front() else {
+                    break;
+                };
+                if let Some(removed) = inner.entries.remove(&oldest) {
+                    inner.size -= removed.len();
+                }
+            }
+            inner.size += content.len();
+            inner.order.push_back(cache_key.clone());
+            inner.entries.insert(cache_key, content.clone());
+        }
+        Ok(content)
+    }
+}
+
+/// Writes `.br` and `.gz` siblings of every compressible file in the directory (and subdirectories) that doesn't
+/// have them yet. Symlinks are skipped. Used once, when a version is uploaded, on a blocking thread.
+pub(crate) fn precompress_dir(dir: &Path) -> std::io::Result<()> {
+    for entry in dir.read_dir()? {
+        let entry = entry?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            // Links can point outside of web root (or back to its parent), only real files are compressed.
+            continue;
+        }
+        if file_type.is_dir() {
+            precompress_dir(&path)?;
+            continue;
+        }
+        let len = entry.metadata()?.len();
+        let compressible = path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| e != "br" && e != "gz" && is_compressible(actix_files::file_extension_to_mime(e).essence_str()));
+        if !compressible || !(MIN_COMPRESSED_SIZE..=MAX_COMPRESSED_SIZE).contains(&len) {
+            continue;
+        }
+        let content = std::fs::read(&path)?;
+        for encoding in [Encoding::Brotli, Encoding::Gzip] {
+            let sibling = encoding.sibling(&path);
+            if !sibling.exists() {
+                std::fs::write(&sibling, encoding.compress(&content, true)?)?;
+            }
+        }
+        debug!("Precompressed {:?}", path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use actix_web::http::header::{HeaderMap, HeaderValue, ACCEPT_ENCODING};
+    use flate2::read::GzDecoder;
+
+    use tmpdir::TmpDir;
+
+    use crate::compression::{is_compressible, negotiate, precompress_dir, Encoding};
+
+    fn accept(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(ACCEPT_ENCODING, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn negotiates_encodings() {
+        assert_eq!(negotiate(&HeaderMap::new()), None);
+        assert_eq!(negotiate(&accept("gzip, deflate, br")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&accept("br;q=0.5, gzip")), Some(Encoding::Gzip));
+        assert_eq!(negotiate(&accept("br;q=0, gzip;q=0")), None);
+        assert_eq!(negotiate(&accept("*")), Some(Encoding::Brotli));
+        assert_eq!(negotiate(&accept("identity")), None);
+    }
+
+    #[test]
+    fn compresses_text_types() {
+        assert!(is_compressible("text/html; charset=utf-8"));
+        assert!(is_compressible("application/javascript"));
+        assert!(is_compressible("image/svg+xml"));
+        assert!(!is_compressible("image/png"));
+        assert!(!is_compressible("application/zip"));
+    }
+
+    #[test]
+    fn compresses_content() {
+        let content = "qpackt ".repeat(100);
+        let gzip = Encoding::Gzip.compress(content.as_bytes(), false).unwrap();
+        let mut decoded = String::new();
+        GzDecoder::new(gzip.as_slice()).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+        let brotli = Encoding::Brotli.compress(content.as_bytes(), true).unwrap();
+        let mut decoded = String::new();
+        brotli::Decompressor::new(brotli.as_slice(), 4096).read_to_string(&mut decoded).unwrap();
+        assert_eq!(decoded, content);
+    }
+
+    #[actix_web::test]
+    async fn precompresses_only_real_files() {
+        let dir = TmpDir::new("precompress").await.unwrap();
+        let root = dir.to_path_buf();
+        let outside = TmpDir::new("outside").await.unwrap();
+        std::fs::write(outside.to_path_buf().join("secret.txt"), "secret ".repeat(500)).unwrap();
+        std::fs::write(root.join("index.html"), "<p>qpackt</p>".repeat(500)).unwrap();
+        std::os::unix::fs::symlink(outside.to_path_buf(), root.join("linked")).unwrap();
+        precompress_dir(&root).unwrap();
+        assert!(root.join("index.html.br").exists());
+        assert!(root.join("index.html.gz").exists());
+        assert!(!outside.to_path_buf().join("secret.txt.br").exists());
+        assert!(!outside.to_path_buf().join("secret.txt.gz").exists());
+    }
+}
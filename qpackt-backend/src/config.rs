@@ -18,17 +18,60 @@
 */
 
 use crate::error::{QpacktError, Result};
+use crate::manager::assignment::Assignment;
 use crate::panel::auth::password::hash_password;
+use crate::proxy::cache::CacheLimits;
+use crate::proxy::cookie::{parse_same_site, CookiePolicy};
+use crate::proxy::forwarded::TrustedProxies;
+use crate::proxy::rate_limit::{RateLimit, RateLimits};
+use crate::proxy::upstream::{TargetPolicy, UpstreamPolicy};
+use crate::response_headers::{HeaderRule, ResponseHeaders};
+use std::collections::BTreeMap;
 use std::fmt::Write;
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
 use tokio::fs;
 use yaml_rust::{Yaml, YamlLoader};
 
 const DOMAIN: &str = "domain";
 const HTTP_PROXY: &str = "http_proxy";
 const HTTPS_PROXY: &str = "https_proxy";
+const HTTP_PROXY_PROTOCOL: &str = "http_proxy_protocol";
+const HTTPS_PROXY_PROTOCOL: &str = "https_proxy_protocol";
 const PASSWORD: &str = "password";
 const RUN_DIR: &str = "run_directory";
+const VERSION_ASSIGNMENT: &str = "version_assignment";
+const COOKIE_LIFETIME_DAYS: &str = "cookie_lifetime_days";
+const COOKIE_SECURE: &str = "cookie_secure";
+const COOKIE_HTTP_ONLY: &str = "cookie_http_only";
+const COOKIE_SAME_SITE: &str = "cookie_same_site";
+const COOKIE_PATH: &str = "cookie_path";
+const COOKIE_DOMAIN: &str = "cookie_domain";
+const UPSTREAM_CONNECT_TIMEOUT_MS: &str = "upstream_connect_timeout_ms";
+const UPSTREAM_READ_TIMEOUT_MS: &str = "upstream_read_timeout_ms";
+const UPSTREAM_RETRIES: &str = "upstream_retries";
+const UPSTREAM_MAX_CONNECTIONS: &str = "upstream_max_connections";
+const UPSTREAM_KEEP_ALIVE_SECONDS: &str = "upstream_keep_alive_seconds";
+const UPSTREAM_IDLE_TIMEOUT_SECONDS: &str = "upstream_idle_timeout_seconds";
+const UPSTREAM_DNS_CACHE_SECONDS: &str = "upstream_dns_cache_seconds";
+const UPSTREAM_TARGETS: &str = "upstream_targets";
+const TRUSTED_PROXIES: &str = "trusted_proxies";
+const CACHE_MEMORY_MB: &str = "cache_memory_mb";
+const CACHE_DISK_MB: &str = "cache_disk_mb";
+const PRECOMPRESS_UPLOADS: &str = "precompress_uploads";
+const RATE_LIMITS: &str = "rate_limits";
+const RATE_LIMIT_MAX_CLIENTS: &str = "rate_limit_max_clients";
+const REQUESTS_PER_SECOND: &str = "requests_per_second";
+const BURST: &str = "burst";
+const KEY: &str = "key";
+const SECURITY_HEADERS: &str = "security_headers";
+const RESPONSE_HEADERS: &str = "response_headers";
+const CONNECT_TIMEOUT_MS: &str = "connect_timeout_ms";
+const READ_TIMEOUT_MS: &str = "read_timeout_ms";
+const MAX_CONNECTIONS: &str = "max_connections";
+const KEEP_ALIVE_SECONDS: &str = "keep_alive_seconds";
+const MB: u64 = 1024 * 1024;
 
 /// Main qpackt config.
 #[derive(Clone, Debug)]
@@ -39,10 +82,30 @@ pub(crate) struct QpacktConfig {
     http_proxy: String, // TODO change this to sockaddr or something.
     /// Host and port for HTTPS traffic
     https_proxy: Option<String>,
+    /// Whether HTTP listener expects PROXY protocol header from a TCP load balancer.
+    http_proxy_protocol: bool,
+    /// Whether HTTPS listener expects PROXY protocol header from a TCP load balancer.
+    https_proxy_protocol: bool,
     /// Administrator's password encoded in `scrypt` format
     password: String,
     /// Directory to hold database, docker images etc...
     run_directory: PathBuf,
+    /// How new visitors are assigned to versions (random, visitor hash, header or cookie).
+    version_assignment: Assignment,
+    /// Attributes of cookies that keep visitors on their versions.
+    cookie_policy: CookiePolicy,
+    /// Timeouts, retries and connection pools for reverse proxy requests.
+    upstream_policy: UpstreamPolicy,
+    /// Proxies (e.g. load balancers) whose forwarding headers tell the real client address.
+    trusted_proxies: TrustedProxies,
+    /// Sizes of reverse proxy response cache.
+    cache_limits: CacheLimits,
+    /// Token bucket limits of clients per route group.
+    rate_limits: RateLimits,
+    /// Whether `.br` and `.gz` siblings of text files are written when a version is uploaded.
+    precompress_uploads: bool,
+    /// Headers of all versions' files, versions can override them.
+    response_headers: ResponseHeaders,
 }
 
 impl QpacktConfig {
@@ -62,6 +125,12 @@ impl QpacktConfig {
         if let Some(https_proxy) = self.https_proxy.as_ref() {
             write!(&mut config, "{}: {}\r\n", HTTPS_PROXY, https_proxy)?;
         }
+        if self.http_proxy_protocol {
+            write!(&mut config, "{}: true\r\n", HTTP_PROXY_PROTOCOL)?;
+        }
+        if self.https_proxy_protocol {
+            write!(&mut config, "{}: true\r\n", HTTPS_PROXY_PROTOCOL)?;
+        }
         write!(&mut config, "{}: {}\r\n", PASSWORD, self.password)?;
         write!(
             &mut config,
@@ -69,6 +138,29 @@ impl QpacktConfig {
             RUN_DIR,
             self.run_directory.to_str().ok_or(QpacktError::InvalidConfig("Invalid run directory".to_string()))?
         )?;
+        if self.version_assignment != Assignment::Random {
+            write!(&mut config, "{}: {}\r\n", VERSION_ASSIGNMENT, self.version_assignment)?;
+        }
+        self.save_cookie_policy(&mut config)?;
+        self.save_upstream_policy(&mut config)?;
+        let default = CacheLimits::default();
+        if self.cache_limits.memory != default.memory {
+            write!(&mut config, "{}: {}\r\n", CACHE_MEMORY_MB, self.cache_limits.memory / MB as usize)?;
+        }
+        if self.cache_limits.disk != default.disk {
+            write!(&mut config, "{}: {}\r\n", CACHE_DISK_MB, self.cache_limits.disk / MB)?;
+        }
+        if self.precompress_uploads {
+            write!(&mut config, "{}: true\r\n", PRECOMPRESS_UPLOADS)?;
+        }
+        self.save_rate_limits(&mut config)?;
+        self.save_response_headers(&mut config)?;
+        if !self.trusted_proxies.networks().is_empty() {
+            write!(&mut config, "{}:\r\n", TRUSTED_PROXIES)?;
+            for network in self.trusted_proxies.networks() {
+                write!(&mut config, "  - {}\r\n", network)?;
+            }
+        }
         fs::write(path, config).await?;
         Ok(())
     }
@@ -82,14 +174,133 @@ impl QpacktConfig {
             http_proxy: from_yaml(HTTP_PROXY, yaml)?
                 .ok_or(QpacktError::InvalidConfig(format!("Missing config value `{}`", HTTP_PROXY).to_string()))?,
             https_proxy: from_yaml(HTTPS_PROXY, yaml)?,
+            http_proxy_protocol: read_bool(HTTP_PROXY_PROTOCOL, yaml)?,
+            https_proxy_protocol: read_bool(HTTPS_PROXY_PROTOCOL, yaml)?,
             password: from_yaml(PASSWORD, yaml)?
                 .ok_or(QpacktError::InvalidConfig(format!("Missing config value `{}`", PASSWORD).to_string()))?,
             run_directory: from_yaml(RUN_DIR, yaml)?
                 .ok_or(QpacktError::InvalidConfig(format!("Missing config value `{}`", RUN_DIR).to_string()))?
                 .into(),
+            version_assignment: from_yaml(VERSION_ASSIGNMENT, yaml)?.map(|v| v.parse()).transpose()?.unwrap_or_default(),
+            cookie_policy: read_cookie_policy(yaml)?,
+            upstream_policy: read_upstream_policy(yaml)?,
+            trusted_proxies: read_trusted_proxies(yaml)?,
+            cache_limits: read_cache_limits(yaml)?,
+            rate_limits: read_rate_limits(yaml)?,
+            precompress_uploads: read_bool(PRECOMPRESS_UPLOADS, yaml)?,
+            response_headers: read_response_headers(yaml)?,
         })
     }
 
+    /// Writes security header preset and header rules, if any.
+    fn save_response_headers(&self, config: &mut String) -> Result<()> {
+        let headers = &self.response_headers;
+        if let Some(preset) = headers.preset {
+            write!(config, "{}: {}\r\n", SECURITY_HEADERS, preset)?;
+        }
+        if headers.rules.is_empty() {
+            return Ok(());
+        }
+        write!(config, "{}:\r\n", RESPONSE_HEADERS)?;
+        for rule in &headers.rules {
+            write!(config, "  \"{}\":\r\n", rule.pattern)?;
+            for (name, value) in &rule.headers {
+                write!(config, "    {}: \"{}\"\r\n", name, value.replace('\\', "\\\\").replace('"', "\\\""))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes rate limits, if any.
+    fn save_rate_limits(&self, config: &mut String) -> Result<()> {
+        let limits = &self.rate_limits;
+        if limits.max_clients != RateLimits::default().max_clients {
+            write!(config, "{}: {}\r\n", RATE_LIMIT_MAX_CLIENTS, limits.max_clients)?;
+        }
+        if limits.groups.is_empty() {
+            return Ok(());
+        }
+        write!(config, "{}:\r\n", RATE_LIMITS)?;
+        for (group, limit) in &limits.groups {
+            write!(config, "  {}:\r\n", group)?;
+            write!(config, "    {}: {}\r\n", REQUESTS_PER_SECOND, limit.requests_per_second)?;
+            write!(config, "    {}: {}\r\n", BURST, limit.burst)?;
+            write!(config, "    {}: {}\r\n", KEY, limit.key)?;
+        }
+        Ok(())
+    }
+
+    /// Writes cookie attributes that differ from defaults.
+    fn save_cookie_policy(&self, config: &mut String) -> Result<()> {
+        let policy = &self.cookie_policy;
+        let default = CookiePolicy::default();
+        if policy.lifetime_days != default.lifetime_days {
+            write!(config, "{}: {}\r\n", COOKIE_LIFETIME_DAYS, policy.lifetime_days)?;
+        }
+        if policy.secure {
+            write!(config, "{}: true\r\n", COOKIE_SECURE)?;
+        }
+        if policy.http_only {
+            write!(config, "{}: true\r\n", COOKIE_HTTP_ONLY)?;
+        }
+        if let Some(same_site) = policy.same_site {
+            write!(config, "{}: {}\r\n", COOKIE_SAME_SITE, same_site.to_string().to_lowercase())?;
+        }
+        if policy.path != default.path {
+            write!(config, "{}: {}\r\n", COOKIE_PATH, policy.path)?;
+        }
+        if let Some(domain) = &policy.domain {
+            write!(config, "{}: {}\r\n", COOKIE_DOMAIN, domain)?;
+        }
+        Ok(())
+    }
+
+    /// Writes upstream settings that differ from defaults.
+    fn save_upstream_policy(&self, config: &mut String) -> Result<()> {
+        let policy = &self.upstream_policy;
+        let default = UpstreamPolicy::default();
+        if policy.connect_timeout != default.connect_timeout {
+            write!(config, "{}: {}\r\n", UPSTREAM_CONNECT_TIMEOUT_MS, policy.connect_timeout.as_millis())?;
+        }
+        if policy.read_timeout != default.read_timeout {
+            write!(config, "{}: {}\r\n", UPSTREAM_READ_TIMEOUT_MS, policy.read_timeout.as_millis())?;
+        }
+        if policy.retries != default.retries {
+            write!(config, "{}: {}\r\n", UPSTREAM_RETRIES, policy.retries)?;
+        }
+        if policy.max_connections != default.max_connections {
+            write!(config, "{}: {}\r\n", UPSTREAM_MAX_CONNECTIONS, policy.max_connections)?;
+        }
+        if policy.keep_alive != default.keep_alive {
+            write!(config, "{}: {}\r\n", UPSTREAM_KEEP_ALIVE_SECONDS, policy.keep_alive.as_secs())?;
+        }
+        if policy.idle_timeout != default.idle_timeout {
+            write!(config, "{}: {}\r\n", UPSTREAM_IDLE_TIMEOUT_SECONDS, policy.idle_timeout.as_secs())?;
+        }
+        if policy.dns_cache_ttl != default.dns_cache_ttl {
+            write!(config, "{}: {}\r\n", UPSTREAM_DNS_CACHE_SECONDS, policy.dns_cache_ttl.as_secs())?;
+        }
+        if !policy.targets.is_empty() {
+            write!(config, "{}:\r\n", UPSTREAM_TARGETS)?;
+        }
+        for (target, target_policy) in &policy.targets {
+            write!(config, "  \"{}\":\r\n", target)?;
+            if let Some(timeout) = target_policy.connect_timeout {
+                write!(config, "    {}: {}\r\n", CONNECT_TIMEOUT_MS, timeout.as_millis())?;
+            }
+            if let Some(timeout) = target_policy.read_timeout {
+                write!(config, "    {}: {}\r\n", READ_TIMEOUT_MS, timeout.as_millis())?;
+            }
+            if let Some(max_connections) = target_policy.max_connections {
+                write!(config, "    {}: {}\r\n", MAX_CONNECTIONS, max_connections)?;
+            }
+            if let Some(keep_alive) = target_policy.keep_alive {
+                write!(config, "    {}: {}\r\n", KEEP_ALIVE_SECONDS, keep_alive.as_secs())?;
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn app_run_directory(&self) -> &PathBuf {
         &self.run_directory
     }
@@ -110,8 +321,18 @@ impl QpacktConfig {
             domain,
             http_proxy: if_empty_then(http_proxy, "0.0.0.0:8080"),
             https_proxy: if https_proxy.is_empty() { None } else { Some(https_proxy) },
+            http_proxy_protocol: false,
+            https_proxy_protocol: false,
             password: hash_password(password)?,
             run_directory: if_empty_then(run_directory, "/usr/share/qpackt/run").into(),
+            version_assignment: Assignment::Random,
+            cookie_policy: CookiePolicy::default(),
+            upstream_policy: UpstreamPolicy::default(),
+            trusted_proxies: TrustedProxies::default(),
+            cache_limits: CacheLimits::default(),
+            rate_limits: RateLimits::default(),
+            precompress_uploads: false,
+            response_headers: ResponseHeaders::default(),
         })
     }
 
@@ -121,13 +342,179 @@ impl QpacktConfig {
     pub(crate) fn https_proxy_addr(&self) -> Option<&String> {
         self.https_proxy.as_ref()
     }
+    pub(crate) fn http_proxy_protocol(&self) -> bool {
+        self.http_proxy_protocol
+    }
+    pub(crate) fn https_proxy_protocol(&self) -> bool {
+        self.https_proxy_protocol
+    }
     pub(crate) fn password(&self) -> &str {
         &self.password
     }
+    pub(crate) fn version_assignment(&self) -> &Assignment {
+        &self.version_assignment
+    }
+    pub(crate) fn cookie_policy(&self) -> &CookiePolicy {
+        &self.cookie_policy
+    }
+    pub(crate) fn upstream_policy(&self) -> &UpstreamPolicy {
+        &self.upstream_policy
+    }
+    pub(crate) fn trusted_proxies(&self) -> &TrustedProxies {
+        &self.trusted_proxies
+    }
+    pub(crate) fn cache_limits(&self) -> &CacheLimits {
+        &self.cache_limits
+    }
+    pub(crate) fn rate_limits(&self) -> &RateLimits {
+        &self.rate_limits
+    }
+    pub(crate) fn precompress_uploads(&self) -> bool {
+        self.precompress_uploads
+    }
+    pub(crate) fn response_headers(&self) -> &ResponseHeaders {
+        &self.response_headers
+    }
+}
+
+fn read_cookie_policy(yaml: &Yaml) -> Result<CookiePolicy> {
+    let default = CookiePolicy::default();
+    Ok(CookiePolicy {
+        lifetime_days: read_number(COOKIE_LIFETIME_DAYS, yaml)?.unwrap_or(default.lifetime_days),
+        secure: read_bool(COOKIE_SECURE, yaml)?,
+        http_only: read_bool(COOKIE_HTTP_ONLY, yaml)?,
+        same_site: from_yaml(COOKIE_SAME_SITE, yaml)?.map(|v| parse_same_site(&v)).transpose()?,
+        path: from_yaml(COOKIE_PATH, yaml)?.unwrap_or(default.path),
+        domain: from_yaml(COOKIE_DOMAIN, yaml)?,
+    })
+}
+
+fn read_upstream_policy(yaml: &Yaml) -> Result<UpstreamPolicy> {
+    let default = UpstreamPolicy::default();
+    Ok(UpstreamPolicy {
+        connect_timeout: read_number(UPSTREAM_CONNECT_TIMEOUT_MS, yaml)?.map(Duration::from_millis).unwrap_or(default.connect_timeout),
+        read_timeout: read_number(UPSTREAM_READ_TIMEOUT_MS, yaml)?.map(Duration::from_millis).unwrap_or(default.read_timeout),
+        retries: read_number(UPSTREAM_RETRIES, yaml)?.unwrap_or(default.retries),
+        max_connections: read_number(UPSTREAM_MAX_CONNECTIONS, yaml)?.unwrap_or(default.max_connections),
+        keep_alive: read_number(UPSTREAM_KEEP_ALIVE_SECONDS, yaml)?.map(Duration::from_secs).unwrap_or(default.keep_alive),
+        idle_timeout: read_number(UPSTREAM_IDLE_TIMEOUT_SECONDS, yaml)?.map(Duration::from_secs).unwrap_or(default.idle_timeout),
+        dns_cache_ttl: read_number(UPSTREAM_DNS_CACHE_SECONDS, yaml)?.map(Duration::from_secs).unwrap_or(default.dns_cache_ttl),
+        targets: read_upstream_targets(&yaml[UPSTREAM_TARGETS])?,
+    })
+}
+
+/// Reads per-target settings, e.g. `upstream_targets: {"localhost:8080": {max_connections: 10}}`.
+fn read_upstream_targets(yaml: &Yaml) -> Result<BTreeMap<String, TargetPolicy>> {
+    let Some(targets) = yaml.as_hash() else {
+        return Ok(BTreeMap::new());
+    };
+    let mut policies = BTreeMap::new();
+    for (target, yaml) in targets {
+        let target = target.as_str().ok_or(QpacktError::InvalidConfig(format!("Invalid target in `{}`", UPSTREAM_TARGETS)))?;
+        let policy = TargetPolicy {
+            connect_timeout: read_number(CONNECT_TIMEOUT_MS, yaml)?.map(Duration::from_millis),
+            read_timeout: read_number(READ_TIMEOUT_MS, yaml)?.map(Duration::from_millis),
+            max_connections: read_number(MAX_CONNECTIONS, yaml)?,
+            keep_alive: read_number(KEEP_ALIVE_SECONDS, yaml)?.map(Duration::from_secs),
+        };
+        policies.insert(target.to_string(), policy);
+    }
+    Ok(policies)
+}
+
+fn read_cache_limits(yaml: &Yaml) -> Result<CacheLimits> {
+    let default = CacheLimits::default();
+    Ok(CacheLimits {
+        memory: read_number::<usize>(CACHE_MEMORY_MB, yaml)?.map(|mb| mb * MB as usize).unwrap_or(default.memory),
+        disk: read_number::<u64>(CACHE_DISK_MB, yaml)?.map(|mb| mb * MB).unwrap_or(default.disk),
+    })
+}
+
+/// Reads limits per route group, e.g. `rate_limits: {event: {requests_per_second: 5, burst: 20, key: ip}}`.
+fn read_rate_limits(yaml: &Yaml) -> Result<RateLimits> {
+    let default = RateLimits::default();
+    let max_clients = read_number(RATE_LIMIT_MAX_CLIENTS, yaml)?.unwrap_or(default.max_clients);
+    let Some(groups) = yaml[RATE_LIMITS].as_hash() else {
+        return Ok(RateLimits { max_clients, ..default });
+    };
+    let mut limits = BTreeMap::new();
+    for (group, yaml) in groups {
+        let group = group.as_str().ok_or(QpacktError::InvalidConfig(format!("Invalid route group in `{}`", RATE_LIMITS)))?;
+        let limit = RateLimit {
+            requests_per_second: read_number(REQUESTS_PER_SECOND, yaml)?
+                .ok_or(QpacktError::InvalidConfig(format!("Missing `{}` of `{}` rate limit", REQUESTS_PER_SECOND, group)))?,
+            burst: read_number(BURST, yaml)?.ok_or(QpacktError::InvalidConfig(format!("Missing `{}` of `{}` rate limit", BURST, group)))?,
+            key: from_yaml(KEY, yaml)?.map(|v| v.parse()).transpose()?.unwrap_or_default(),
+        };
+        limit.validate()?;
+        limits.insert(group.parse()?, limit);
+    }
+    Ok(RateLimits { groups: limits, max_clients })
+}
+
+/// Reads preset and headers per path glob, e.g. `response_headers: {"*.html": {Content-Security-Policy: "default-src 'self'"}}`.
+fn read_response_headers(yaml: &Yaml) -> Result<ResponseHeaders> {
+    let preset = from_yaml(SECURITY_HEADERS, yaml)?.map(|v| v.parse()).transpose()?;
+    let Some(patterns) = yaml[RESPONSE_HEADERS].as_hash() else {
+        return Ok(ResponseHeaders { preset, rules: vec![] });
+    };
+    let mut rules = Vec::with_capacity(patterns.len());
+    for (pattern, yaml) in patterns {
+        let pattern = pattern.as_str().ok_or(QpacktError::InvalidConfig(format!("Invalid path pattern in `{}`", RESPONSE_HEADERS)))?;
+        let names = yaml.as_hash().ok_or(QpacktError::InvalidConfig(format!("Invalid headers of `{}`", pattern)))?;
+        let mut headers = BTreeMap::new();
+        for name in names.keys() {
+            let name = name.as_str().ok_or(QpacktError::InvalidConfig(format!("Invalid header name of `{}`", pattern)))?;
+            let value = from_yaml(name, yaml)?.ok_or(QpacktError::InvalidConfig(format!("Invalid value of `{}` for `{}`", name, pattern)))?;
+            headers.insert(name.to_string(), value);
+        }
+        let rule = HeaderRule { pattern: pattern.to_string(), headers };
+        rule.validate()?;
+        rules.push(rule);
+    }
+    Ok(ResponseHeaders { preset, rules })
+}
+
+/// Reads a list of CIDRs, e.g. `trusted_proxies: [10.0.0.0/8, 172.16.0.1]`.
+fn read_trusted_proxies(yaml: &Yaml) -> Result<TrustedProxies> {
+    let values = match &yaml[TRUSTED_PROXIES] {
+        Yaml::BadValue => vec![],
+        Yaml::Array(values) => values
+            .iter()
+            .map(|v| {
+                v.as_str().map(str::to_string).ok_or(QpacktError::InvalidConfig(format!("Invalid entry {:?} of `{}`", v, TRUSTED_PROXIES)))
+            })
+            .collect::<Result<Vec<_>>>()?,
+        Yaml::String(value) => vec![value.clone()],
+        _ => return Err(QpacktError::InvalidConfig(format!("Invalid `{}`, expected a list", TRUSTED_PROXIES))),
+    };
+    TrustedProxies::parse(&values)
+}
+
+fn read_number<T: FromStr>(name: &str, yaml: &Yaml) -> Result<Option<T>> {
+    from_yaml(name, yaml)?
+        .map(|value| value.parse().map_err(|_| QpacktError::InvalidConfig(format!("Invalid value `{}` for `{}`, expected a number", value, name))))
+        .transpose()
+}
+
+/// Reads `true` or `false`, missing value means `false`.
+fn read_bool(name: &str, yaml: &Yaml) -> Result<bool> {
+    match from_yaml(name, yaml)? {
+        None => Ok(false),
+        Some(value) => value
+            .parse()
+            .map_err(|_| QpacktError::InvalidConfig(format!("Invalid value `{}` for `{}`, expected true or false", value, name))),
+    }
 }
 
+/// Reads a value as string. Numbers and booleans are accepted as well, so that `cookie_secure: true` works unquoted.
 fn from_yaml(value: &str, yaml: &Yaml) -> Result<Option<String>> {
-    Ok(yaml[value].clone().into_string())
+    Ok(match &yaml[value] {
+        Yaml::Integer(i) => Some(i.to_string()),
+        Yaml::Real(r) => Some(r.clone()),
+        Yaml::Boolean(b) => Some(b.to_string()),
+        other => other.clone().into_string(),
+    })
 }
 
 fn read_stdin(prompt: &str) -> Result<String> {
@@ -147,3 +534,35 @@ fn if_empty_then(value: String, default: &str) -> String {
         default.to_string()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use tmpdir::TmpDir;
+
+    use crate::config::QpacktConfig;
+    use crate::proxy::rate_limit::RouteGroup;
+
+    #[actix_web::test]
+    async fn reads_fractional_rate_limits() {
+        let dir = TmpDir::new("config").await.unwrap();
+        let path = dir.to_path_buf().join("qpackt.yaml");
+        let config = "domain: localhost\nhttp_proxy: 0.0.0.0:8080\npassword: x\nrun_directory: /tmp\n\
+            rate_limits:\n  event:\n    requests_per_second: 0.5\n    burst: 2\n";
+        tokio::fs::write(&path, config).await.unwrap();
+        let config = QpacktConfig::read(&path).await.unwrap();
+        assert_eq!(config.rate_limits().groups[&RouteGroup::Event].requests_per_second, 0.5);
+
+        config.save(&path).await.unwrap();
+        assert_eq!(QpacktConfig::read(&path).await.unwrap().rate_limits(), config.rate_limits());
+    }
+
+    #[actix_web::test]
+    async fn rejects_invalid_trusted_proxies() {
+        let dir = TmpDir::new("config").await.unwrap();
+        let path = dir.to_path_buf().join("qpackt.yaml");
+        let config = "domain: localhost\nhttp_proxy: 0.0.0.0:8080\npassword: x\nrun_directory: /tmp\n\
+            trusted_proxies:\n  - 10.0.0.0/8\n  - [192.168.1.1]\n";
+        tokio::fs::write(&path, config).await.unwrap();
+        assert!(QpacktConfig::read(&path).await.is_err());
+    }
+}
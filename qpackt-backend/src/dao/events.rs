@@ -34,6 +34,7 @@ use crate::analytics::hash::VisitorHash;
 use crate::dao::{Dao, get_sqlite_connection};
 use crate::dao::version::VersionName;
 use crate::error::{QpacktError, Result};
+use crate::experiment::ExperimentAssignments;
 
 pub(crate) struct EventData {
     pub(crate) time: u64,
@@ -43,6 +44,7 @@ pub(crate) struct EventData {
     pub(crate) params: String,
     pub(crate) path: String,
     pub(crate) payload: String,
+    pub(crate) experiments: ExperimentAssignments,
 }
 
 pub(crate) struct SavedEventData {
@@ -63,14 +65,15 @@ impl Dao {
         let url = self.inner.get_read_write_url().await;
         let mut conn = get_sqlite_connection(&url).await?;
         for event in events {
-            let q = sqlx::query("INSERT INTO events (time, visitor, version, name, params, path, payload) values ($1, $2, $3, $4, $5, $6, $7)")
+            let q = sqlx::query("INSERT INTO events (time, visitor, version, name, params, path, payload, experiments) values ($1, $2, $3, $4, $5, $6, $7, $8)")
                 .bind(event.time as i64)
                 .bind::<i64>(event.visitor.into())
                 .bind(event.version)
                 .bind(event.name)
                 .bind(event.params)
                 .bind(event.path)
-                .bind(event.payload);
+                .bind(event.payload)
+                .bind(event.experiments.to_string());
             q.execute(&mut conn).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
         }
         info!("Saved {} events", len);
@@ -99,7 +102,7 @@ impl Dao {
                 .map_err(|_| QpacktError::DatabaseError("No column 'version' in visits table".into()))?;
             total_visit_count.push((version.to_string().into(), total_visits as u64));
         }
-        let q = sqlx::query("SELECT id, time, visitor, version, name, params, path, payload FROM events WHERE time >= $1 AND time < $2")
+        let q = sqlx::query("SELECT id, time, visitor, version, name, params, path, payload, experiments FROM events WHERE time >= $1 AND time < $2")
             .bind(filter.time_from as i64)
             .bind(filter.time_to as i64);
         let rows = q.fetch_all(&mut *transaction).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
@@ -117,8 +120,39 @@ impl Dao {
         })
     }
 
+    /// Counts visitors of each version whose visit started between from_ts and to_ts,
+    /// and how many of them fired `goal_event` on that version since from_ts.
+    pub(crate) async fn get_conversions(&self, from_ts: u64, to_ts: u64, goal_event: &str) -> Result<Vec<Conversions>> {
+        let q = sqlx::query("SELECT v.version, COUNT(DISTINCT v.visitor) AS visitors, COUNT(DISTINCT e.visitor) AS converted
+                                                            FROM visits v
+                                                            LEFT JOIN (SELECT DISTINCT visitor, version FROM events WHERE name = $3 AND time >= $1) e
+                                                                ON e.visitor = v.visitor AND e.version = v.version
+                                                            WHERE v.first_request_time >= $1 AND v.first_request_time <= $2
+                                                            GROUP BY v.version")
+            .bind(from_ts as i64)
+            .bind(to_ts as i64)
+            .bind(goal_event);
+        let url = self.inner.get_read_only_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let rows = q.fetch_all(&mut conn).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        let mut conversions = Vec::with_capacity(rows.len());
+        for row in rows {
+            let version = row
+                .try_get::<&str, _>("version")
+                .map_err(|_| QpacktError::DatabaseError("No column 'version' in visits table".into()))?;
+            let visitors = row
+                .try_get::<i64, _>("visitors")
+                .map_err(|_| QpacktError::DatabaseError("Unable to get visitors from visits table".into()))?;
+            let converted = row
+                .try_get::<i64, _>("converted")
+                .map_err(|_| QpacktError::DatabaseError("Unable to get converted from events table".into()))?;
+            conversions.push(Conversions { version: version.to_string().into(), visitors: visitors as u64, converted: converted as u64 });
+        }
+        Ok(conversions)
+    }
+
     pub(crate) async fn get_events(&self, filter: GetEventsFilter, sender: Sender<SavedEventData>) -> Result<()> {
-        let q = sqlx::query("SELECT id, time, visitor, version, name, params, path, payload FROM events WHERE time >= $1 AND time < $2")
+        let q = sqlx::query("SELECT id, time, visitor, version, name, params, path, payload, experiments FROM events WHERE time >= $1 AND time < $2")
             .bind(filter.time_from as i64)
             .bind(filter.time_to as i64);
         let url = self.inner.get_read_only_url().await;
@@ -150,6 +184,14 @@ impl From<String> for EventName {
 }
 
 
+/// How many visitors of a version fired the goal event.
+#[derive(Debug)]
+pub(crate) struct Conversions {
+    pub(crate) version: VersionName,
+    pub(crate) visitors: u64,
+    pub(crate) converted: u64,
+}
+
 #[derive(Debug)]
 pub(crate) struct EventStats {
     pub(crate) total_visit_count: Vec<(VersionName, u64)>,
@@ -181,6 +223,9 @@ fn saved_event_from_row(row: SqliteRow) -> Result<SavedEventData> {
     let payload = row
         .try_get::<&str, _>("payload")
         .map_err(|_| QpacktError::DatabaseError("No column 'payload' in versions table".into()))?;
+    let experiments = row
+        .try_get::<&str, _>("experiments")
+        .map_err(|_| QpacktError::DatabaseError("No column 'experiments' in events table".into()))?;
     let saved_event = SavedEventData {
         id,
         event: EventData {
@@ -191,7 +236,56 @@ fn saved_event_from_row(row: SqliteRow) -> Result<SavedEventData> {
             params: params.to_string(),
             path: path.to_string(),
             payload: payload.to_string(),
+            experiments: ExperimentAssignments::parse(experiments),
         },
     };
     Ok(saved_event)
 }
+
+#[cfg(test)]
+mod test {
+    use tmpdir::TmpDir;
+
+    use crate::dao::events::EventData;
+    use crate::dao::visits::Visit;
+    use crate::dao::Dao;
+    use crate::experiment::ExperimentAssignments;
+
+    fn visit(visitor: i64, version: &str) -> Visit {
+        Visit {
+            first_request_time: 100,
+            last_request_time: 100,
+            request_count: 1,
+            visitor: visitor.into(),
+            version: version.to_string().into(),
+            experiments: ExperimentAssignments::default(),
+        }
+    }
+
+    fn goal(visitor: i64, version: &str) -> EventData {
+        EventData {
+            time: 200,
+            visitor: visitor.into(),
+            version: version.into(),
+            name: "signup".into(),
+            params: String::new(),
+            path: "/".into(),
+            payload: String::new(),
+            experiments: ExperimentAssignments::default(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn credits_conversions_to_the_converting_version() {
+        let dir = TmpDir::new("dao").await.unwrap();
+        let dao = Dao::init(&dir.to_path_buf()).await.unwrap();
+        dao.update_visits(&[visit(1, "blue"), visit(2, "red")]).await.unwrap();
+        // Visitor 1 started on blue, was moved to red (e.g. blue was drained) and converted there, twice.
+        dao.update_visits(&[visit(1, "red")]).await.unwrap();
+        dao.save_event_data(vec![goal(1, "red"), goal(1, "red"), goal(2, "red")]).await.unwrap();
+        let conversions = dao.get_conversions(0, 1_000, "signup").await.unwrap();
+        let mut counts = conversions.iter().map(|c| (c.version.to_string(), c.visitors, c.converted)).collect::<Vec<_>>();
+        counts.sort();
+        assert_eq!(counts, vec![("blue".to_string(), 1, 0), ("red".to_string(), 1, 1)]);
+    }
+}
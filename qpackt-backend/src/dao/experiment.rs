@@ -0,0 +1,75 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::dao::{get_sqlite_connection, Dao};
+use crate::error::{QpacktError, Result};
+use crate::experiment::{Experiment, Variant};
+use sqlx::Row;
+
+impl Dao {
+    pub(crate) async fn list_experiments(&self) -> Result<Vec<Experiment>> {
+        let url = self.inner.get_read_only_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let rows = sqlx::query("SELECT id, name, path_prefix, variants FROM experiments ORDER BY path_prefix")
+            .fetch_all(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        let mut experiments = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id = row.try_get::<i32, _>("id").map_err(|_| QpacktError::DatabaseError("No column 'id' in experiments table".into()))?;
+            let name =
+                row.try_get::<String, _>("name").map_err(|_| QpacktError::DatabaseError("No column 'name' in experiments table".into()))?;
+            let path_prefix = row
+                .try_get::<String, _>("path_prefix")
+                .map_err(|_| QpacktError::DatabaseError("No column 'path_prefix' in experiments table".into()))?;
+            let variants = row
+                .try_get::<String, _>("variants")
+                .map_err(|_| QpacktError::DatabaseError("No column 'variants' in experiments table".into()))?;
+            let variants = serde_json::from_str::<Vec<Variant>>(&variants)
+                .map_err(|_| QpacktError::DatabaseError(format!("Unable to deserialize experiment variants '{}' from json", variants)))?;
+            experiments.push(Experiment { id, name, path_prefix, variants })
+        }
+        Ok(experiments)
+    }
+
+    pub(crate) async fn create_experiment(&self, name: &str, path_prefix: &str, variants: &[Variant]) -> Result<()> {
+        let variants = serde_json::to_string(variants).map_err(|_| QpacktError::SerializationError)?;
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        sqlx::query("INSERT INTO experiments (name, path_prefix, variants) VALUES ($1, $2, $3)")
+            .bind(name)
+            .bind(path_prefix)
+            .bind(variants)
+            .execute(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(format!("Unable to insert experiment: {}", e)))?;
+        Ok(())
+    }
+
+    pub(crate) async fn delete_experiment(&self, id: i32) -> Result<()> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        sqlx::query("DELETE FROM experiments WHERE id = $1")
+            .bind(id)
+            .execute(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(format!("Unable to delete experiment `{}`: {}", id, e)))?;
+        Ok(())
+    }
+}
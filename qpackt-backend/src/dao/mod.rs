@@ -29,10 +29,14 @@ use crate::error::{QpacktError, Result};
 mod inner;
 pub(crate) mod requests;
 pub(crate) mod reverse_proxy;
+pub(crate) mod rewrite;
+pub(crate) mod rollback;
+pub(crate) mod rollout;
 mod state;
 pub(crate) mod version;
 pub(crate) mod visits;
 pub(crate) mod events;
+pub(crate) mod experiment;
 
 /// Default file name with main qpackt's database.
 const SQLITE_FILE: &str = "qpackt.sqlite";
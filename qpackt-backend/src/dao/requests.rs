@@ -25,12 +25,14 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use actix_web::http::Uri;
 use log::debug;
 use serde::{Deserialize, Serialize};
+use sqlx::Row;
 
 use crate::analytics::hash::VisitorHash;
 use crate::dao::{Dao, get_sqlite_connection};
 use crate::dao::state::State;
 use crate::dao::version::VersionName;
 use crate::error::{QpacktError, Result};
+use crate::experiment::ExperimentAssignments;
 
 /// Daily seed used to creating visitors' hashes ([VisitorHash]).
 /// It isn't really used every time a new hash is needed. Instead, [crate::analytics::hash::CURRENT_INIT] in hash module is used.
@@ -46,11 +48,28 @@ pub(crate) struct CreateHttpRequestLog {
     pub(crate) visitor: VisitorHash,
     pub(crate) version: VersionName,
     pub(crate) uri: Uri,
+    /// Status code of the response served to the visitor.
+    pub(crate) status: u16,
+    pub(crate) experiments: ExperimentAssignments,
+    /// Response of a reverse proxy target (or qpackt's 502/503/504 when targets failed), not a version's file.
+    pub(crate) proxied: bool,
+}
+
+/// Number of all reverse proxy responses and responses with 5xx status for visitors of a single version.
+pub(crate) struct RequestErrors {
+    pub(crate) version: VersionName,
+    pub(crate) request_count: u32,
+    pub(crate) error_count: u32,
 }
 
 impl CreateHttpRequestLog {
-    pub(crate) fn new(visitor: VisitorHash, version: VersionName, uri: Uri) -> Self {
-        Self { time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(), visitor, version, uri }
+    pub(crate) fn new(visitor: VisitorHash, version: VersionName, uri: Uri, status: u16, experiments: ExperimentAssignments) -> Self {
+        Self { time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(), visitor, version, uri, status, experiments, proxied: false }
+    }
+
+    /// Marks the request as served by a reverse proxy.
+    pub(crate) fn proxied(self) -> Self {
+        Self { proxied: true, ..self }
     }
 }
 
@@ -69,14 +88,71 @@ impl Dao {
         let url = self.inner.get_read_write_url().await;
         let mut conn = get_sqlite_connection(&url).await?;
         for request in requests {
-            let q = sqlx::query("INSERT INTO requests (time, visitor, version, uri) values ($1, $2, $3, $4)")
+            let q = sqlx::query("INSERT INTO requests (time, visitor, version, uri, status, experiments, proxied) values ($1, $2, $3, $4, $5, $6, $7)")
                 .bind(request.time as i64)
                 .bind::<i64>(request.visitor.into())
                 .bind(request.version.to_string())
-                .bind(request.uri.to_string());
+                .bind(request.uri.to_string())
+                .bind(request.status)
+                .bind(request.experiments.to_string())
+                .bind(request.proxied);
             q.execute(&mut conn).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
         }
         debug!("Saved {} requests", requests.len());
         Ok(())
     }
+
+    /// Counts reverse proxy responses and 5xx ones per visitor's version, for requests that happened between from_ts
+    /// and to_ts. Versions' files and responses served from [crate::proxy::cache::ResponseCache] are not counted,
+    /// so this is the upstream 5xx rate.
+    pub(crate) async fn get_request_errors(&self, from_ts: u64, to_ts: u64) -> Result<Vec<RequestErrors>> {
+        let url = self.inner.get_read_only_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let rows = sqlx::query(
+            "SELECT version, COUNT(*) AS request_count, SUM(status >= 500) AS error_count FROM requests WHERE time >= $1 AND time <= $2 AND proxied = 1 GROUP BY version",
+        )
+        .bind(from_ts as i64)
+        .bind(to_ts as i64)
+        .fetch_all(&mut conn)
+        .await
+        .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        let mut errors = Vec::with_capacity(rows.len());
+        for row in rows {
+            let version = row
+                .try_get::<String, _>("version")
+                .map_err(|_| QpacktError::DatabaseError("No column 'version' in requests table".into()))?;
+            let request_count = row
+                .try_get::<u32, _>("request_count")
+                .map_err(|_| QpacktError::DatabaseError("No column 'request_count' in requests query".into()))?;
+            let error_count = row
+                .try_get::<u32, _>("error_count")
+                .map_err(|_| QpacktError::DatabaseError("No column 'error_count' in requests query".into()))?;
+            errors.push(RequestErrors { version: version.into(), request_count, error_count })
+        }
+        Ok(errors)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::Uri;
+    use tmpdir::TmpDir;
+
+    use crate::dao::requests::CreateHttpRequestLog;
+    use crate::dao::Dao;
+
+    fn request(status: u16) -> CreateHttpRequestLog {
+        CreateHttpRequestLog::new(1.into(), "blue".to_string().into(), Uri::from_static("/api"), status, Default::default())
+    }
+
+    #[actix_web::test]
+    async fn counts_only_proxied_errors() {
+        let dir = TmpDir::new("dao").await.unwrap();
+        let dao = Dao::init(&dir.to_path_buf()).await.unwrap();
+        let requests = vec![request(200), request(404), request(500), request(200).proxied(), request(502).proxied()];
+        dao.save_requests(&requests).await.unwrap();
+        let errors = dao.get_request_errors(0, u32::MAX as u64).await.unwrap();
+        assert_eq!(errors.len(), 1);
+        assert_eq!((errors[0].version.to_string().as_str(), errors[0].request_count, errors[0].error_count), ("blue", 2, 1));
+    }
 }
@@ -17,48 +17,155 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::dao::version::VersionName;
 use crate::dao::{get_sqlite_connection, Dao};
 use crate::error::{QpacktError, Result};
-use crate::reverse_proxy::ReverseProxy;
-use sqlx::Row;
+use crate::reverse_proxy::{Balancing, HeaderRule, Matcher, ProxyCache, ProxyHeaders, ReverseProxy, Target};
+use std::time::Duration;
+use sqlx::{Row, SqliteConnection};
 
 impl Dao {
     pub(crate) async fn list_reverse_proxies(&self) -> Result<Vec<ReverseProxy>> {
         let url = self.inner.get_read_only_url().await;
         let mut conn = get_sqlite_connection(&url).await?;
-        let rows = sqlx::query("SELECT id, prefix, target FROM reverse_proxy ORDER BY prefix DESC")
+        let rows = sqlx::query("SELECT id, prefix, host, methods, path_regex, rewrite, priority, preserve_host, header_rules, cache_enabled, cache_ttl, balancing, health_check_path, max_failures FROM reverse_proxy")
             .fetch_all(&mut conn)
             .await
             .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        let targets = list_reverse_proxy_targets(&mut conn).await?;
         let mut proxies = Vec::with_capacity(rows.len());
         for row in rows {
             let id = row.try_get::<i32, _>("id").map_err(|_| QpacktError::DatabaseError("No column 'id' in reverse_proxy table".into()))?;
             let prefix = row
                 .try_get::<String, _>("prefix")
                 .map_err(|_| QpacktError::DatabaseError("No column 'prefix' in reverse_proxy table".into()))?;
-            let target = row
-                .try_get::<String, _>("target")
-                .map_err(|_| QpacktError::DatabaseError("No column 'target' in reverse_proxy table".into()))?;
-            proxies.push(ReverseProxy { id, prefix, target })
+            let host = row
+                .try_get::<Option<String>, _>("host")
+                .map_err(|_| QpacktError::DatabaseError("No column 'host' in reverse_proxy table".into()))?;
+            let methods = row
+                .try_get::<String, _>("methods")
+                .map_err(|_| QpacktError::DatabaseError("No column 'methods' in reverse_proxy table".into()))?;
+            let path_regex = row
+                .try_get::<Option<String>, _>("path_regex")
+                .map_err(|_| QpacktError::DatabaseError("No column 'path_regex' in reverse_proxy table".into()))?;
+            let rewrite = row
+                .try_get::<Option<String>, _>("rewrite")
+                .map_err(|_| QpacktError::DatabaseError("No column 'rewrite' in reverse_proxy table".into()))?;
+            let priority = row
+                .try_get::<i32, _>("priority")
+                .map_err(|_| QpacktError::DatabaseError("No column 'priority' in reverse_proxy table".into()))?;
+            let matcher = Matcher::new(host, &methods, path_regex.as_deref(), rewrite, priority)
+                .map_err(|e| QpacktError::DatabaseError(format!("Invalid rule of reverse proxy {}: {}", id, e)))?;
+            let preserve_host = row
+                .try_get::<bool, _>("preserve_host")
+                .map_err(|_| QpacktError::DatabaseError("No column 'preserve_host' in reverse_proxy table".into()))?;
+            let header_rules = row
+                .try_get::<String, _>("header_rules")
+                .map_err(|_| QpacktError::DatabaseError("No column 'header_rules' in reverse_proxy table".into()))?;
+            let rules = serde_json::from_str::<Vec<HeaderRule>>(&header_rules)
+                .map_err(|_| QpacktError::DatabaseError(format!("Unable to deserialize header rules '{}' from json", header_rules)))?;
+            let headers = ProxyHeaders { preserve_host, rules };
+            let cache_enabled = row
+                .try_get::<bool, _>("cache_enabled")
+                .map_err(|_| QpacktError::DatabaseError("No column 'cache_enabled' in reverse_proxy table".into()))?;
+            let cache_ttl = row
+                .try_get::<Option<i64>, _>("cache_ttl")
+                .map_err(|_| QpacktError::DatabaseError("No column 'cache_ttl' in reverse_proxy table".into()))?;
+            let cache = ProxyCache { enabled: cache_enabled, ttl: cache_ttl.map(|ttl| Duration::from_secs(ttl as u64)) };
+            let balancing = row
+                .try_get::<String, _>("balancing")
+                .map_err(|_| QpacktError::DatabaseError("No column 'balancing' in reverse_proxy table".into()))?;
+            let balancing = balancing
+                .parse::<Balancing>()
+                .map_err(|_| QpacktError::DatabaseError(format!("Invalid balancing '{}' in reverse_proxy table", balancing)))?;
+            let health_check_path = row
+                .try_get::<Option<String>, _>("health_check_path")
+                .map_err(|_| QpacktError::DatabaseError("No column 'health_check_path' in reverse_proxy table".into()))?;
+            let max_failures = row
+                .try_get::<u32, _>("max_failures")
+                .map_err(|_| QpacktError::DatabaseError("No column 'max_failures' in reverse_proxy table".into()))?;
+            let proxy_targets = targets.iter().filter(|(proxy_id, _)| *proxy_id == id).map(|(_, target)| target.clone()).collect();
+            proxies.push(ReverseProxy::new(id, prefix, matcher, headers, cache, proxy_targets, balancing, health_check_path, max_failures))
         }
         Ok(proxies)
     }
 
-    pub(crate) async fn create_reverse_proxy(&self, prefix: &str, target: &str) -> Result<()> {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) async fn create_reverse_proxy(
+        &self,
+        prefix: &str,
+        matcher: &Matcher,
+        headers: &ProxyHeaders,
+        cache: ProxyCache,
+        targets: &[(String, Option<VersionName>)],
+        balancing: Balancing,
+        health_check_path: Option<&str>,
+        max_failures: u32,
+    ) -> Result<()> {
+        let header_rules = serde_json::to_string(&headers.rules).map_err(|_| QpacktError::SerializationError)?;
         let url = self.inner.get_read_write_url().await;
         let mut conn = get_sqlite_connection(&url).await?;
-        sqlx::query("INSERT INTO reverse_proxy (prefix, target) VALUES ($1, $2)")
-            .bind(prefix)
-            .bind(target)
-            .execute(&mut conn)
-            .await
-            .map_err(|e| QpacktError::DatabaseError(format!("Unable to insert reverse_proxy: {}", e)))?;
+        let id = sqlx::query(
+            "INSERT INTO reverse_proxy (prefix, host, methods, path_regex, rewrite, priority, preserve_host, header_rules, cache_enabled, \
+            cache_ttl, balancing, health_check_path, max_failures) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13)",
+        )
+        .bind(prefix)
+        .bind(&matcher.host)
+        .bind(matcher.methods_string())
+        .bind(matcher.path_regex.as_ref().map(|r| r.as_str()))
+        .bind(&matcher.rewrite)
+        .bind(matcher.priority)
+        .bind(headers.preserve_host)
+        .bind(header_rules)
+        .bind(cache.enabled)
+        .bind(cache.ttl.map(|ttl| ttl.as_secs() as i64))
+        .bind(balancing.to_string())
+        .bind(health_check_path)
+        .bind(max_failures)
+        .execute(&mut conn)
+        .await
+        .map_err(|e| QpacktError::DatabaseError(format!("Unable to insert reverse_proxy: {}", e)))?
+        .last_insert_rowid();
+        for (target, version) in targets {
+            insert_reverse_proxy_target(&mut conn, id as i32, target, version.as_ref()).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn add_reverse_proxy_target(&self, proxy_id: i32, target: &str, version: Option<&VersionName>) -> Result<()> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        insert_reverse_proxy_target(&mut conn, proxy_id, target, version).await
+    }
+
+    /// Deletes a target, unless it's the last fallback target (one without a version) of the proxy.
+    pub(crate) async fn delete_reverse_proxy_target(&self, proxy_id: i32, id: i32) -> Result<()> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let deleted = sqlx::query(
+            "DELETE FROM reverse_proxy_target WHERE proxy_id = $1 AND id = $2 AND (version IS NOT NULL OR \
+            (SELECT COUNT(*) FROM reverse_proxy_target WHERE proxy_id = $1 AND version IS NULL) > 1)",
+        )
+        .bind(proxy_id)
+        .bind(id)
+        .execute(&mut conn)
+        .await
+        .map_err(|e| QpacktError::DatabaseError(format!("Unable to delete reverse_proxy_target `{}`: {}", id, e)))?
+        .rows_affected();
+        if deleted == 0 {
+            return Err(QpacktError::InvalidRequest(format!("No target {} of proxy {} or it's the last fallback target", id, proxy_id)));
+        }
         Ok(())
     }
 
     pub(crate) async fn delete_reverse_proxy(&self, id: i32) -> Result<()> {
         let url = self.inner.get_read_write_url().await;
         let mut conn = get_sqlite_connection(&url).await?;
+        sqlx::query("DELETE FROM reverse_proxy_target WHERE proxy_id = $1")
+            .bind(id)
+            .execute(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(format!("Unable to delete targets of reverse_proxy `{}`: {}", id, e)))?;
         sqlx::query("DELETE FROM reverse_proxy WHERE id = $1")
             .bind(id)
             .execute(&mut conn)
@@ -67,3 +174,62 @@ impl Dao {
         Ok(())
     }
 }
+
+/// Lists targets of all proxies, with ids of their proxies.
+async fn list_reverse_proxy_targets(conn: &mut SqliteConnection) -> Result<Vec<(i32, Target)>> {
+    let rows = sqlx::query("SELECT id, proxy_id, target, version FROM reverse_proxy_target ORDER BY id")
+        .fetch_all(conn)
+        .await
+        .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+    let mut targets = Vec::with_capacity(rows.len());
+    for row in rows {
+        let id = row.try_get::<i32, _>("id").map_err(|_| QpacktError::DatabaseError("No column 'id' in reverse_proxy_target table".into()))?;
+        let proxy_id = row
+            .try_get::<i32, _>("proxy_id")
+            .map_err(|_| QpacktError::DatabaseError("No column 'proxy_id' in reverse_proxy_target table".into()))?;
+        let url = row
+            .try_get::<String, _>("target")
+            .map_err(|_| QpacktError::DatabaseError("No column 'target' in reverse_proxy_target table".into()))?;
+        let version = row
+            .try_get::<Option<String>, _>("version")
+            .map_err(|_| QpacktError::DatabaseError("No column 'version' in reverse_proxy_target table".into()))?;
+        targets.push((proxy_id, Target { id, url, version: version.map(VersionName::from), state: Default::default() }))
+    }
+    Ok(targets)
+}
+
+/// Uses caller's connection, so that the write lock isn't taken twice.
+async fn insert_reverse_proxy_target(conn: &mut SqliteConnection, proxy_id: i32, target: &str, version: Option<&VersionName>) -> Result<()> {
+    sqlx::query("INSERT INTO reverse_proxy_target (proxy_id, target, version) VALUES ($1, $2, $3)")
+        .bind(proxy_id)
+        .bind(target)
+        .bind(version.map(|v| v.to_string()))
+        .execute(conn)
+        .await
+        .map_err(|e| QpacktError::DatabaseError(format!("Unable to insert reverse_proxy_target: {}", e)))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use tmpdir::TmpDir;
+
+    use crate::dao::Dao;
+    use crate::reverse_proxy::{Balancing, Matcher, ProxyCache, ProxyHeaders};
+
+    #[actix_web::test]
+    async fn keeps_last_fallback_target() {
+        let dir = TmpDir::new("dao").await.unwrap();
+        let dao = Dao::init(&dir.to_path_buf()).await.unwrap();
+        let matcher = Matcher::new(None, "", None, None, 0).unwrap();
+        let headers = ProxyHeaders { preserve_host: true, rules: vec![] };
+        let targets = [("http://localhost:9001".to_string(), None), ("http://localhost:9002".to_string(), None)];
+        dao.create_reverse_proxy("/api", &matcher, &headers, ProxyCache::default(), &targets, Balancing::RoundRobin, None, 3).await.unwrap();
+        let proxy = dao.list_reverse_proxies().await.unwrap().remove(0);
+        let (first, second) = (proxy.targets[0].id, proxy.targets[1].id);
+        dao.delete_reverse_proxy_target(proxy.id, first).await.unwrap();
+        assert!(dao.delete_reverse_proxy_target(proxy.id, second).await.is_err());
+        assert!(dao.delete_reverse_proxy_target(proxy.id, first).await.is_err());
+        assert_eq!(dao.list_reverse_proxies().await.unwrap()[0].targets.len(), 1);
+    }
+}
@@ -0,0 +1,100 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::dao::{get_sqlite_connection, Dao};
+use crate::error::{QpacktError, Result};
+use crate::rewrite::Rewrite;
+use sqlx::Row;
+
+impl Dao {
+    pub(crate) async fn list_rewrites(&self) -> Result<Vec<Rewrite>> {
+        let url = self.inner.get_read_only_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let rows = sqlx::query("SELECT id, source, destination, status, preserve_query, priority FROM rewrites ORDER BY id")
+            .fetch_all(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        let mut rewrites = Vec::with_capacity(rows.len());
+        for row in rows {
+            let id = row.try_get::<i32, _>("id").map_err(|_| QpacktError::DatabaseError("No column 'id' in rewrites table".into()))?;
+            let source = row
+                .try_get::<String, _>("source")
+                .map_err(|_| QpacktError::DatabaseError("No column 'source' in rewrites table".into()))?;
+            let destination = row
+                .try_get::<String, _>("destination")
+                .map_err(|_| QpacktError::DatabaseError("No column 'destination' in rewrites table".into()))?;
+            let status =
+                row.try_get::<u16, _>("status").map_err(|_| QpacktError::DatabaseError("No column 'status' in rewrites table".into()))?;
+            let preserve_query = row
+                .try_get::<bool, _>("preserve_query")
+                .map_err(|_| QpacktError::DatabaseError("No column 'preserve_query' in rewrites table".into()))?;
+            let priority = row
+                .try_get::<i32, _>("priority")
+                .map_err(|_| QpacktError::DatabaseError("No column 'priority' in rewrites table".into()))?;
+            let rewrite = Rewrite::new(id, source, destination, status, preserve_query, priority)
+                .map_err(|e| QpacktError::DatabaseError(format!("Invalid rewrite {}: {}", id, e)))?;
+            rewrites.push(rewrite)
+        }
+        Ok(rewrites)
+    }
+
+    pub(crate) async fn create_rewrite(&self, rewrite: &Rewrite) -> Result<()> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        sqlx::query("INSERT INTO rewrites (source, destination, status, preserve_query, priority) VALUES ($1, $2, $3, $4, $5)")
+            .bind(&rewrite.source)
+            .bind(&rewrite.destination)
+            .bind(rewrite.status)
+            .bind(rewrite.preserve_query)
+            .bind(rewrite.priority)
+            .execute(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(format!("Unable to insert rewrite: {}", e)))?;
+        Ok(())
+    }
+
+    /// Replaces rule with [Rewrite::id]. Returns false if there is no such rule.
+    pub(crate) async fn update_rewrite(&self, rewrite: &Rewrite) -> Result<bool> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let result =
+            sqlx::query("UPDATE rewrites SET source = $1, destination = $2, status = $3, preserve_query = $4, priority = $5 WHERE id = $6")
+                .bind(&rewrite.source)
+                .bind(&rewrite.destination)
+                .bind(rewrite.status)
+                .bind(rewrite.preserve_query)
+                .bind(rewrite.priority)
+                .bind(rewrite.id)
+                .execute(&mut conn)
+                .await
+                .map_err(|e| QpacktError::DatabaseError(format!("Unable to update rewrite `{}`: {}", rewrite.id, e)))?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub(crate) async fn delete_rewrite(&self, id: i32) -> Result<()> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        sqlx::query("DELETE FROM rewrites WHERE id = $1")
+            .bind(id)
+            .execute(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(format!("Unable to delete rewrite `{}`: {}", id, e)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,61 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::dao::{get_sqlite_connection, Dao};
+use crate::error::{QpacktError, Result};
+use crate::manager::rollback::Rollback;
+use sqlx::Row;
+
+impl Dao {
+    /// Lists all automatic rollbacks, latest first.
+    pub(crate) async fn list_rollbacks(&self) -> Result<Vec<Rollback>> {
+        let url = self.inner.get_read_only_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let rows = sqlx::query("SELECT version, time, reason FROM rollbacks ORDER BY time DESC")
+            .fetch_all(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        let mut rollbacks = Vec::with_capacity(rows.len());
+        for row in rows {
+            let version = row
+                .try_get::<String, _>("version")
+                .map_err(|_| QpacktError::DatabaseError("No column 'version' in rollbacks table".into()))?;
+            let time =
+                row.try_get::<i64, _>("time").map_err(|_| QpacktError::DatabaseError("No column 'time' in rollbacks table".into()))?;
+            let reason = row
+                .try_get::<String, _>("reason")
+                .map_err(|_| QpacktError::DatabaseError("No column 'reason' in rollbacks table".into()))?;
+            rollbacks.push(Rollback { version: version.into(), time: time as u64, reason })
+        }
+        Ok(rollbacks)
+    }
+
+    pub(crate) async fn save_rollback(&self, rollback: &Rollback) -> Result<()> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        sqlx::query("INSERT INTO rollbacks (version, time, reason) VALUES ($1, $2, $3)")
+            .bind(rollback.version.to_string())
+            .bind(rollback.time as i64)
+            .bind(&rollback.reason)
+            .execute(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(format!("Unable to save rollback: {}", e)))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,169 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::dao::version::VersionName;
+use crate::dao::{get_sqlite_connection, Dao};
+use crate::error::{QpacktError, Result};
+use crate::manager::rollback::RollbackThresholds;
+use crate::manager::rollout::{Rollout, RolloutStep};
+use sqlx::{Connection, Row, SqliteConnection};
+
+impl Dao {
+    /// Lists all rollout plans, active and paused.
+    pub(crate) async fn list_rollouts(&self) -> Result<Vec<Rollout>> {
+        let url = self.inner.get_read_only_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        read_rollouts(&mut conn).await
+    }
+
+    /// Reads version's rollout plan, lets `update` change it and saves it if it returns true, all while holding the write
+    /// lock, so that the rollout task and panel don't overwrite each other's changes. Returns current plan, if there is one.
+    pub(crate) async fn update_rollout(&self, version: &VersionName, update: impl FnOnce(&mut Rollout) -> bool) -> Result<Option<Rollout>> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let mut transaction = conn.begin().await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        let Some(mut rollout) = read_rollouts(&mut transaction).await?.into_iter().find(|r| &r.version == version) else {
+            return Ok(None);
+        };
+        if !update(&mut rollout) {
+            return Ok(Some(rollout));
+        }
+        sqlx::query("UPDATE rollouts SET current_step = $1, next_step_time = $2, paused = $3 WHERE version = $4")
+            .bind(rollout.current_step)
+            .bind(rollout.next_step_time as i64)
+            .bind(rollout.paused)
+            .bind(rollout.version.to_string())
+            .execute(&mut *transaction)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(format!("Unable to update rollout: {}", e)))?;
+        transaction.commit().await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        Ok(Some(rollout))
+    }
+
+    /// Creates a rollout plan or replaces the existing one for the same version.
+    pub(crate) async fn save_rollout(&self, rollout: &Rollout) -> Result<()> {
+        let steps = serde_json::to_string(&rollout.steps).map_err(|_| QpacktError::SerializationError)?;
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        sqlx::query(
+            "INSERT OR REPLACE INTO rollouts (version, steps, current_step, next_step_time, paused, max_bounce_rate_increase, max_error_rate_increase) \
+                VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        )
+        .bind(rollout.version.to_string())
+        .bind(steps)
+        .bind(rollout.current_step)
+        .bind(rollout.next_step_time as i64)
+        .bind(rollout.paused)
+        .bind(rollout.thresholds.max_bounce_rate_increase)
+        .bind(rollout.thresholds.max_error_rate_increase)
+        .execute(&mut conn)
+        .await
+        .map_err(|e| QpacktError::DatabaseError(format!("Unable to save rollout: {}", e)))?;
+        Ok(())
+    }
+
+    /// Deletes rollout plan for the version. Returns false if there was none.
+    pub(crate) async fn delete_rollout(&self, version: &VersionName) -> Result<bool> {
+        let url = self.inner.get_read_write_url().await;
+        let mut conn = get_sqlite_connection(&url).await?;
+        let result = sqlx::query("DELETE FROM rollouts WHERE version = $1")
+            .bind(version.to_string())
+            .execute(&mut conn)
+            .await
+            .map_err(|e| QpacktError::DatabaseError(format!("Unable to delete rollout `{}`: {}", version, e)))?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+async fn read_rollouts(conn: &mut SqliteConnection) -> Result<Vec<Rollout>> {
+    let rows = sqlx::query(
+        "SELECT version, steps, current_step, next_step_time, paused, max_bounce_rate_increase, max_error_rate_increase FROM rollouts ORDER BY version",
+    )
+    .fetch_all(conn)
+    .await
+    .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+    let mut rollouts = Vec::with_capacity(rows.len());
+    for row in rows {
+        let version =
+            row.try_get::<String, _>("version").map_err(|_| QpacktError::DatabaseError("No column 'version' in rollouts table".into()))?;
+        let steps =
+            row.try_get::<String, _>("steps").map_err(|_| QpacktError::DatabaseError("No column 'steps' in rollouts table".into()))?;
+        let steps = serde_json::from_str::<Vec<RolloutStep>>(&steps)
+            .map_err(|_| QpacktError::DatabaseError(format!("Unable to deserialize rollout steps '{}' from json", steps)))?;
+        let current_step = row
+            .try_get::<u32, _>("current_step")
+            .map_err(|_| QpacktError::DatabaseError("No column 'current_step' in rollouts table".into()))?;
+        let next_step_time = row
+            .try_get::<i64, _>("next_step_time")
+            .map_err(|_| QpacktError::DatabaseError("No column 'next_step_time' in rollouts table".into()))?;
+        let paused =
+            row.try_get::<bool, _>("paused").map_err(|_| QpacktError::DatabaseError("No column 'paused' in rollouts table".into()))?;
+        let max_bounce_rate_increase = row
+            .try_get::<Option<f32>, _>("max_bounce_rate_increase")
+            .map_err(|_| QpacktError::DatabaseError("No column 'max_bounce_rate_increase' in rollouts table".into()))?;
+        let max_error_rate_increase = row
+            .try_get::<Option<f32>, _>("max_error_rate_increase")
+            .map_err(|_| QpacktError::DatabaseError("No column 'max_error_rate_increase' in rollouts table".into()))?;
+        rollouts.push(Rollout {
+            version: version.into(),
+            steps,
+            current_step,
+            next_step_time: next_step_time as u64,
+            paused,
+            thresholds: RollbackThresholds { max_bounce_rate_increase, max_error_rate_increase },
+        })
+    }
+    Ok(rollouts)
+}
+
+#[cfg(test)]
+mod test {
+    use tmpdir::TmpDir;
+
+    use crate::dao::Dao;
+    use crate::manager::rollback::RollbackThresholds;
+    use crate::manager::rollout::{Rollout, RolloutStep};
+
+    #[actix_web::test]
+    async fn keeps_concurrent_rollout_updates() {
+        let dir = TmpDir::new("dao").await.unwrap();
+        let dao = Dao::init(&dir.to_path_buf()).await.unwrap();
+        let steps = vec![RolloutStep { percent: 10, hold_seconds: 60 }, RolloutStep { percent: 100, hold_seconds: 0 }];
+        dao.save_rollout(&Rollout::new("v2".to_string().into(), steps, RollbackThresholds::default())).await.unwrap();
+        let version = "v2".to_string().into();
+        let pause = dao.update_rollout(&version, |rollout| {
+            rollout.paused = true;
+            true
+        });
+        let advance = dao.update_rollout(&version, |rollout| {
+            rollout.current_step += 1;
+            true
+        });
+        let (paused, advanced) = futures::join!(pause, advance);
+        paused.unwrap().unwrap();
+        advanced.unwrap().unwrap();
+        let rollout = &dao.list_rollouts().await.unwrap()[0];
+        assert!(rollout.paused);
+        assert_eq!(rollout.current_step, 1);
+
+        assert!(dao.delete_rollout(&version).await.unwrap());
+        assert!(dao.update_rollout(&version, |_| true).await.unwrap().is_none());
+        assert!(dao.list_rollouts().await.unwrap().is_empty());
+    }
+}
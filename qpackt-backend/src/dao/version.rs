@@ -20,8 +20,9 @@
 use crate::dao::{get_sqlite_connection, Dao};
 use crate::error::QpacktError;
 use crate::manager::strategy::Strategy;
+use crate::server::ServingProfile;
 use serde::{Deserialize, Serialize};
-use sqlx::{Connection, Row};
+use sqlx::{Connection, Row, SqliteConnection};
 use std::fmt::{Display, Formatter};
 use std::ops::Deref;
 use std::path::PathBuf;
@@ -46,6 +47,10 @@ impl VersionName {
     pub(crate) fn matches(&self, other: &str) -> bool {
         self.0.deref() == other
     }
+
+    pub(crate) fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -53,16 +58,24 @@ pub(crate) struct Version {
     pub(crate) name: VersionName,
     pub(crate) web_root: PathBuf,
     pub(crate) strategy: Strategy,
+    /// Drained version gets no new visitors and its current visitors are moved to other versions on their next request.
+    #[serde(default)]
+    pub(crate) drain: bool,
+    /// SPA fallback, error pages and URL handling.
+    #[serde(default)]
+    pub(crate) profile: ServingProfile,
 }
 
 impl Dao {
     /// Registers new version of the site in database.
     pub(crate) async fn register_version(&self, version: &Version) -> crate::error::Result<()> {
         let strategy = serde_json::to_string(&version.strategy).unwrap();
-        let q = sqlx::query("INSERT INTO versions (web_root, name, strategy) VALUES ($1, $2, $3)")
+        let profile = serde_json::to_string(&version.profile).map_err(|_| QpacktError::SerializationError)?;
+        let q = sqlx::query("INSERT INTO versions (web_root, name, strategy, profile) VALUES ($1, $2, $3, $4)")
             .bind(version.web_root.to_str().unwrap())
             .bind(version.name.to_string())
-            .bind(&strategy);
+            .bind(&strategy)
+            .bind(&profile);
         let url = self.inner.get_read_write_url().await;
         let mut connection = get_sqlite_connection(&url).await?;
         q.execute(&mut connection).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
@@ -89,46 +102,106 @@ impl Dao {
     pub(crate) async fn list_versions(&self) -> crate::error::Result<Vec<Version>> {
         let url = self.inner.get_read_only_url().await;
         let mut conn = get_sqlite_connection(&url).await?;
-        let rows = sqlx::query("SELECT name, web_root, strategy FROM versions ORDER BY name")
-            .fetch_all(&mut conn)
-            .await
-            .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
-        let mut versions = Vec::with_capacity(rows.len());
-        for row in rows {
-            let name =
-                row.try_get::<String, _>("name").map_err(|_| QpacktError::DatabaseError("No column 'name' in versions table".into()))?;
-            let web_root = row
-                .try_get::<String, _>("web_root")
-                .map_err(|_| QpacktError::DatabaseError("No column 'web_root' in versions table".into()))?;
-            let web_root = PathBuf::from(web_root);
-            let strategy = row
-                .try_get::<String, _>("strategy")
-                .map_err(|_| QpacktError::DatabaseError("No column 'strategy' in versions table".into()))?;
-
-            let strategy = serde_json::from_str::<Strategy>(&strategy)
-                .map_err(|_| QpacktError::DatabaseError(format!("Unable to deserialize strategy '{}' from json", strategy)))?;
-            versions.push(Version { name: name.into(), web_root, strategy })
-        }
-        Ok(versions)
+        read_versions(&mut conn).await
     }
 
-    /// Saves versions to the database
-    pub(crate) async fn save_versions(&self, versions: &[Version]) -> crate::error::Result<()> {
-        let q = sqlx::query("DELETE FROM versions");
+    /// Reads versions, lets `update` change them and saves them if it returns true, all while holding the write lock,
+    /// so that concurrent updates (panel, rollouts, rollbacks) don't overwrite each other. Returns current versions.
+    pub(crate) async fn update_versions(&self, update: impl FnOnce(&mut [Version]) -> bool) -> crate::error::Result<Vec<Version>> {
         let url = self.inner.get_read_write_url().await;
         let mut conn = get_sqlite_connection(&url).await?;
         let mut transaction = conn.begin().await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
-        q.execute(&mut *transaction).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
-        for version in versions {
+        let mut versions = read_versions(&mut transaction).await?;
+        if !update(&mut versions) {
+            return Ok(versions);
+        }
+        sqlx::query("DELETE FROM versions").execute(&mut *transaction).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+        for version in &versions {
             let web_root = version.web_root.to_str().unwrap();
             let strategy = serde_json::to_string(&version.strategy).unwrap();
-            let q = sqlx::query("INSERT INTO versions (web_root, name, strategy) VALUES ($1, $2, $3)")
+            let profile = serde_json::to_string(&version.profile).map_err(|_| QpacktError::SerializationError)?;
+            let q = sqlx::query("INSERT INTO versions (web_root, name, strategy, drain, profile) VALUES ($1, $2, $3, $4, $5)")
                 .bind(web_root)
                 .bind(version.name.to_string())
-                .bind(&strategy);
+                .bind(&strategy)
+                .bind(version.drain)
+                .bind(&profile);
             q.execute(&mut *transaction).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
         }
         transaction.commit().await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
-        Ok(())
+        Ok(versions)
+    }
+}
+
+async fn read_versions(conn: &mut SqliteConnection) -> crate::error::Result<Vec<Version>> {
+    let rows = sqlx::query("SELECT name, web_root, strategy, drain, profile FROM versions ORDER BY name")
+        .fetch_all(conn)
+        .await
+        .map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
+    let mut versions = Vec::with_capacity(rows.len());
+    for row in rows {
+        let name = row.try_get::<String, _>("name").map_err(|_| QpacktError::DatabaseError("No column 'name' in versions table".into()))?;
+        let web_root = row
+            .try_get::<String, _>("web_root")
+            .map_err(|_| QpacktError::DatabaseError("No column 'web_root' in versions table".into()))?;
+        let web_root = PathBuf::from(web_root);
+        let strategy = row
+            .try_get::<String, _>("strategy")
+            .map_err(|_| QpacktError::DatabaseError("No column 'strategy' in versions table".into()))?;
+
+        let strategy = serde_json::from_str::<Strategy>(&strategy)
+            .map_err(|_| QpacktError::DatabaseError(format!("Unable to deserialize strategy '{}' from json", strategy)))?;
+        let drain =
+            row.try_get::<bool, _>("drain").map_err(|_| QpacktError::DatabaseError("No column 'drain' in versions table".into()))?;
+        let profile =
+            row.try_get::<String, _>("profile").map_err(|_| QpacktError::DatabaseError("No column 'profile' in versions table".into()))?;
+        let profile = serde_json::from_str::<ServingProfile>(&profile)
+            .map_err(|_| QpacktError::DatabaseError(format!("Unable to deserialize serving profile '{}' from json", profile)))?;
+        versions.push(Version { name: name.into(), web_root, strategy, drain, profile })
+    }
+    Ok(versions)
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use tmpdir::TmpDir;
+
+    use crate::dao::version::Version;
+    use crate::dao::Dao;
+    use crate::manager::strategy::Strategy;
+
+    #[actix_web::test]
+    async fn keeps_concurrent_updates() {
+        let dir = TmpDir::new("dao").await.unwrap();
+        let dao = Dao::init(&dir.to_path_buf()).await.unwrap();
+        for name in ["a", "b"] {
+            let version = Version {
+                name: name.to_string().into(),
+                web_root: PathBuf::from(name),
+                strategy: Strategy::Weight(1),
+                drain: false,
+                profile: Default::default(),
+            };
+            dao.register_version(&version).await.unwrap();
+        }
+        let update = |index: usize, weight: u16| {
+            dao.update_versions(move |versions| {
+                versions[index].strategy = Strategy::Weight(weight);
+                true
+            })
+        };
+        let (a, b) = futures::join!(update(0, 10), update(1, 20));
+        a.unwrap();
+        b.unwrap();
+        let weights = dao.list_versions().await.unwrap().into_iter().map(|v| v.strategy).collect::<Vec<_>>();
+        assert!(matches!(weights[..], [Strategy::Weight(10), Strategy::Weight(20)]));
+        let unchanged = dao.update_versions(|versions| {
+            versions[0].drain = true;
+            false
+        });
+        unchanged.await.unwrap();
+        assert!(!dao.list_versions().await.unwrap()[0].drain);
     }
 }
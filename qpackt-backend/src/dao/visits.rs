@@ -21,6 +21,7 @@ use crate::analytics::hash::VisitorHash;
 use crate::dao::version::VersionName;
 use crate::dao::{get_sqlite_connection, Dao};
 use crate::error::{QpacktError, Result};
+use crate::experiment::ExperimentAssignments;
 use log::debug;
 use serde::Serialize;
 use sqlx::Row;
@@ -34,6 +35,7 @@ pub(crate) struct Visit {
     pub(crate) request_count: u32,
     pub(crate) visitor: VisitorHash,
     pub(crate) version: VersionName,
+    pub(crate) experiments: ExperimentAssignments,
 }
 
 impl Dao {
@@ -46,14 +48,15 @@ impl Dao {
         let mut conn = get_sqlite_connection(&url).await?;
         for visit in visits {
             let q = sqlx::query(
-                "INSERT INTO visits (first_request_time, last_request_time, request_count, visitor, version) values ($1, $2, $3, $4, $5) \
-                    ON CONFLICT(visitor) DO UPDATE SET request_count=request_count + $3, last_request_time=$2",
+                "INSERT INTO visits (first_request_time, last_request_time, request_count, visitor, version, experiments) values ($1, $2, $3, $4, $5, $6) \
+                    ON CONFLICT(visitor) DO UPDATE SET request_count=request_count + $3, last_request_time=$2, experiments=$6",
             )
             .bind(visit.first_request_time as i64)
             .bind(visit.last_request_time as i64)
             .bind(visit.request_count)
             .bind::<i64>(visit.visitor.into())
-            .bind(visit.version.to_string());
+            .bind(visit.version.to_string())
+            .bind(visit.experiments.to_string());
             q.execute(&mut conn).await.map_err(|e| QpacktError::DatabaseError(e.to_string()))?;
         }
         debug!("Updated visits: {}", visits.len());
@@ -68,7 +71,7 @@ impl Dao {
         let url = self.inner.get_read_only_url().await;
         let mut conn = get_sqlite_connection(&url).await?;
         let rows = sqlx::query(
-            "SELECT first_request_time, last_request_time, request_count, visitor, version FROM visits WHERE first_request_time >= $1 AND first_request_time <= $2",
+            "SELECT first_request_time, last_request_time, request_count, visitor, version, experiments FROM visits WHERE first_request_time >= $1 AND first_request_time <= $2",
         )
         .bind(from_ts as i64)
         .bind(to_ts as i64)
@@ -90,6 +93,9 @@ impl Dao {
             let version = row
                 .try_get::<&str, _>("version")
                 .map_err(|_| QpacktError::DatabaseError("No column 'version' in versions table".into()))?;
+            let experiments = row
+                .try_get::<&str, _>("experiments")
+                .map_err(|_| QpacktError::DatabaseError("No column 'experiments' in visits table".into()))?;
             // We don't want a new String for every visit. So let's find the right [VersionName] that's cheap to clone.
             // If not found then create one.
             let mut found_version: Option<VersionName> = None;
@@ -110,6 +116,7 @@ impl Dao {
                 request_count,
                 visitor: visitor.into(),
                 version,
+                experiments: ExperimentAssignments::parse(experiments),
             })
         }
         debug!("Returned {} visits", visits.len());
@@ -59,12 +59,16 @@ pub(crate) enum QpacktError {
 
     #[error("access forbidden")]
     Forbidden,
+
+    #[error("invalid request: {0}")]
+    InvalidRequest(String),
 }
 
 impl ResponseError for QpacktError {
     fn status_code(&self) -> StatusCode {
         match self {
             QpacktError::Forbidden => StatusCode::FORBIDDEN,
+            QpacktError::InvalidRequest(_) => StatusCode::BAD_REQUEST,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
@@ -0,0 +1,229 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Path-scoped experiments: traffic under a path prefix is split between versions independently of
+//! the whole-site [Strategy](crate::manager::strategy::Strategy). Other paths are served as usual.
+//! Many experiments can run at the same time. Every visitor is assigned in all of them at once and
+//! assignments of different experiments don't depend on each other.
+
+use arc_swap::ArcSwap;
+use log::debug;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+
+use crate::dao::version::VersionName;
+use crate::manager::assignment::rendezvous_score;
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Experiment {
+    pub(crate) id: i32,
+    pub(crate) name: String,
+    /// Path (e.g. `/pricing`) under which the experiment runs. Matches whole segments only, so `/pricing` matches
+    /// `/pricing` and `/pricing/plans` but not `/pricing-old`.
+    pub(crate) path_prefix: String,
+    pub(crate) variants: Vec<Variant>,
+}
+
+/// Version taking part in an [Experiment] with its share of experiment's traffic.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct Variant {
+    pub(crate) version: VersionName,
+    pub(crate) weight: u16,
+}
+
+impl Experiment {
+    pub(crate) fn matches_path(&self, path: &str) -> bool {
+        let prefix = self.path_prefix.trim_end_matches('/');
+        path.strip_prefix(prefix).is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Picks a variant proportionally to weights: by the assignment key if present, randomly otherwise.
+    /// The key is salted with experiment's id, so that bucketing in one experiment says nothing about another one.
+    /// Drained versions are never picked.
+    pub(crate) fn pick(&self, key: Option<u64>, drained: &[VersionName]) -> Option<&VersionName> {
+        let mut variants = self.variants.iter().filter(|v| v.weight > 0 && !drained.contains(&v.version));
+        let picked = match key {
+            Some(key) => {
+                let key = key ^ (self.id as u64).wrapping_mul(0x9e3779b97f4a7c15);
+                variants
+                    .map(|v| (rendezvous_score(key, &v.version, v.weight), v))
+                    .max_by(|(s1, _), (s2, _)| s1.total_cmp(s2))
+                    .map(|(_, v)| v)
+            }
+            None => {
+                let sum_weights = self.variants.iter().filter(|v| !drained.contains(&v.version)).map(|v| v.weight as u32).sum::<u32>();
+                if sum_weights == 0 {
+                    return None;
+                }
+                let mut cut = thread_rng().gen_range(0..sum_weights);
+                variants.find(|v| {
+                    if cut < v.weight as u32 {
+                        return true;
+                    }
+                    cut -= v.weight as u32;
+                    false
+                })
+            }
+        }?;
+        debug!("Picking version {} for experiment {}", picked.version, self.name);
+        Some(&picked.version)
+    }
+
+    pub(crate) fn has_version(&self, version: &VersionName) -> bool {
+        self.variants.iter().any(|v| &v.version == version)
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct Experiments {
+    list: ArcSwap<Vec<Experiment>>,
+}
+
+impl Experiments {
+    /// Replaces current experiments. Longer prefixes are checked first, so nested experiments take precedence.
+    pub(crate) fn set(&self, mut list: Vec<Experiment>) {
+        list.sort_by_key(|e| Reverse(e.path_prefix.trim_end_matches('/').len()));
+        self.list.store(Arc::new(list));
+    }
+
+    pub(crate) fn find_by_path(&self, path: &str) -> Option<Experiment> {
+        self.list.load().iter().find(|e| e.matches_path(path)).cloned()
+    }
+
+    /// Assigns a version in every running experiment. Previous assignments are kept as long as the experiment
+    /// still has that version and it isn't drained, assignments to finished experiments are dropped.
+    pub(crate) fn assign(&self, previous: &ExperimentAssignments, key: Option<u64>, drained: &[VersionName]) -> ExperimentAssignments {
+        let list = self.list.load();
+        let mut assignments = Vec::with_capacity(list.len());
+        for experiment in list.iter() {
+            let version = match previous.get(experiment.id) {
+                Some(version) if experiment.has_version(version) && !drained.contains(version) => Some(version),
+                _ => experiment.pick(key, drained),
+            };
+            if let Some(version) = version {
+                assignments.push((experiment.id, version.clone()));
+            }
+        }
+        assignments.sort_by_key(|(id, _)| *id);
+        ExperimentAssignments(assignments)
+    }
+}
+
+/// Versions assigned to a visitor in every running [Experiment].
+/// Kept in a cookie and in analytics tables as `id:version` pairs separated by `|`.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub(crate) struct ExperimentAssignments(Vec<(i32, VersionName)>);
+
+impl ExperimentAssignments {
+    /// Parses assignments. Malformed pairs are skipped.
+    pub(crate) fn parse(value: &str) -> Self {
+        let assignments = value
+            .split('|')
+            .filter_map(|pair| pair.split_once(':'))
+            .filter_map(|(id, version)| Some((id.parse().ok()?, version.to_string().into())))
+            .collect();
+        Self(assignments)
+    }
+
+    pub(crate) fn get(&self, experiment: i32) -> Option<&VersionName> {
+        self.0.iter().find(|(id, _)| *id == experiment).map(|(_, version)| version)
+    }
+}
+
+impl Display for ExperimentAssignments {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (i, (id, version)) in self.0.iter().enumerate() {
+            if i > 0 {
+                write!(f, "|")?;
+            }
+            write!(f, "{}:{}", id, version)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::experiment::{Experiment, ExperimentAssignments, Variant};
+
+    fn experiment(path_prefix: &str, variants: Vec<(&str, u16)>) -> Experiment {
+        experiment_with_id(1, path_prefix, variants)
+    }
+
+    fn experiment_with_id(id: i32, path_prefix: &str, variants: Vec<(&str, u16)>) -> Experiment {
+        let variants = variants.into_iter().map(|(version, weight)| Variant { version: version.to_string().into(), weight }).collect();
+        Experiment { id, name: "test".into(), path_prefix: path_prefix.into(), variants }
+    }
+
+    #[test]
+    fn matches_whole_segments() {
+        let e = experiment("/pricing", vec![]);
+        assert!(e.matches_path("/pricing"));
+        assert!(e.matches_path("/pricing/plans"));
+        assert!(!e.matches_path("/pricing-old"));
+        assert!(!e.matches_path("/"));
+        assert!(experiment("/pricing/", vec![]).matches_path("/pricing"));
+    }
+
+    #[test]
+    fn picks_only_weighted_variants() {
+        let e = experiment("/pricing", vec![("a", 0), ("b", 1)]);
+        for key in 0..100 {
+            assert!(e.pick(Some(key), &[]).unwrap().matches("b"));
+            assert!(e.pick(None, &[]).unwrap().matches("b"));
+        }
+        assert!(experiment("/pricing", vec![("a", 0)]).pick(None, &[]).is_none());
+        let e = experiment("/pricing", vec![("a", 1), ("b", 1)]);
+        let drained = vec!["a".to_string().into()];
+        for key in 0..100 {
+            assert!(e.pick(Some(key), &drained).unwrap().matches("b"));
+            assert!(e.pick(None, &drained).unwrap().matches("b"));
+        }
+    }
+
+    #[test]
+    fn splits_random_picks_by_weight() {
+        let e = experiment("/pricing", vec![("a", 1), ("b", 1), ("c", 2)]);
+        let picks = (0..10_000).map(|_| e.pick(None, &[]).unwrap().to_string()).collect::<Vec<_>>();
+        let count = |name: &str| picks.iter().filter(|p| *p == name).count();
+        assert!((2_250..2_750).contains(&count("a")), "{}", count("a"));
+        assert!((2_250..2_750).contains(&count("b")), "{}", count("b"));
+        assert!((4_500..5_500).contains(&count("c")), "{}", count("c"));
+    }
+
+    #[test]
+    fn buckets_experiments_independently() {
+        let e1 = experiment_with_id(1, "/a", vec![("a", 1), ("b", 1)]);
+        let e2 = experiment_with_id(2, "/b", vec![("a", 1), ("b", 1)]);
+        let same = (0..10_000).filter(|key| e1.pick(Some(*key), &[]).unwrap() == e2.pick(Some(*key), &[]).unwrap()).count();
+        // Independent 50/50 splits agree for about half of the keys.
+        assert!((4_500..5_500).contains(&same), "{}", same);
+    }
+
+    #[test]
+    fn parses_and_prints_assignments() {
+        let assignments = ExperimentAssignments::parse("1:2024_01_01__10_00_00|x:v|2|3:v3");
+        assert_eq!(assignments.to_string(), "1:2024_01_01__10_00_00|3:v3");
+        assert!(assignments.get(3).unwrap().matches("v3"));
+        assert_eq!(ExperimentAssignments::parse(""), ExperimentAssignments::default());
+    }
+}
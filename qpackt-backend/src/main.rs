@@ -32,6 +32,7 @@ unused_extern_crates,
 unused_qualifications,
 variant_size_differences
 )]
+#![allow(clippy::doc_lazy_continuation)]
 
 use std::{env, fs};
 use std::path::{Path, PathBuf};
@@ -53,27 +54,39 @@ use crate::dao::Dao;
 use crate::dao::version::Version;
 use crate::error::QpacktError;
 use crate::error::Result;
+use crate::experiment::Experiments;
+use crate::manager::bandit::spawn_bandit_loop;
+use crate::manager::rollout::spawn_rollout_loop;
 use crate::panel::start_panel_http;
 use crate::proxy::{start_proxy_http, start_proxy_https};
+use crate::proxy::cache::ResponseCache;
+use crate::proxy::rate_limit::RateLimiter;
+use crate::proxy::health::spawn_health_check_loop;
 use crate::reverse_proxy::ReverseProxies;
+use crate::rewrite::Rewrites;
 use crate::server::Versions;
 use crate::ssl::{FORCE_HTTPS_REDIRECT, get_certificate};
 use crate::ssl::challenge::AcmeChallenge;
 use crate::ssl::resolver::{read_intermediate_cert, try_build_resolver};
 
 mod analytics;
+mod cache_rules;
+mod compression;
 mod config;
 pub mod constants;
 pub mod dao;
 mod error;
+mod experiment;
 mod https_redirect;
 mod manager;
 mod panel;
 mod proxy;
+mod response_headers;
 mod server;
 mod ssl;
 
 mod reverse_proxy;
+mod rewrite;
 
 #[cfg(test)]
 mod tests;
@@ -128,7 +141,7 @@ async fn wait(handler: JoinHandle<()>) -> ! {
 /// Starts actix processes to serve admin's panel and http proxy.
 async fn start_http(qpackt_config: QpacktConfig, dao: Dao, versions: Vec<Version>, http_request_log_writer: HttpRequestLogWriter, event_writer: EventWriter) {
     let qpackt_config = Data::new(qpackt_config);
-    let servers = Versions::start(versions, qpackt_config.app_run_directory()).await;
+    let servers = Versions::new(versions, qpackt_config.app_run_directory(), qpackt_config.version_assignment().clone());
     let dao = Data::new(dao);
     let servers = Data::new(servers);
     let http_request_log_writer = Data::new(http_request_log_writer);
@@ -136,6 +149,17 @@ async fn start_http(qpackt_config: QpacktConfig, dao: Dao, versions: Vec<Version
     let reverse_proxies = ReverseProxies::default();
     reverse_proxies.set(dao.list_reverse_proxies().await.unwrap()).await;
     let reverse_proxies = Data::new(reverse_proxies);
+    let experiments = Experiments::default();
+    experiments.set(dao.list_experiments().await.unwrap());
+    let experiments = Data::new(experiments);
+    let cache = Data::new(ResponseCache::new(*qpackt_config.cache_limits(), qpackt_config.app_run_directory().join("cache")));
+    let limiter = Data::new(RateLimiter::new(qpackt_config.rate_limits().clone()));
+    let rewrites = Rewrites::default();
+    rewrites.set(dao.list_rewrites().await.unwrap()).await;
+    let rewrites = Data::new(rewrites);
+    spawn_rollout_loop(dao.clone(), servers.clone());
+    spawn_bandit_loop(dao.clone(), servers.clone());
+    spawn_health_check_loop(reverse_proxies.clone(), qpackt_config.upstream_policy().clone());
     let ssl_challenge = AcmeChallenge::new().await;
     start_proxy_http(
         qpackt_config.http_proxy_addr(),
@@ -144,9 +168,14 @@ async fn start_http(qpackt_config: QpacktConfig, dao: Dao, versions: Vec<Version
         http_request_log_writer.clone(),
         Data::new(ssl_challenge.clone()),
         reverse_proxies.clone(),
+        experiments.clone(),
         event_writer.clone(),
+        qpackt_config.clone(),
+        cache.clone(),
+        limiter.clone(),
+        rewrites.clone(),
     );
-    start_panel_http(qpackt_config.clone(), dao.clone(), servers.clone(), None, reverse_proxies.clone());
+    start_panel_http(qpackt_config.clone(), dao.clone(), servers.clone(), None, reverse_proxies.clone(), experiments.clone(), cache.clone(), rewrites.clone());
 
     if let Some(https_proxy_addr) = qpackt_config.https_proxy_addr() {
         let certificate = get_certificate(qpackt_config.domain(), qpackt_config.app_run_directory(), ssl_challenge.clone()).await;
@@ -155,8 +184,8 @@ async fn start_http(qpackt_config: QpacktConfig, dao: Dao, versions: Vec<Version
         let resolver = try_build_resolver(certificate, intermediate_cert);
         let tls_config = ServerConfig::builder().with_safe_defaults().with_no_client_auth().with_cert_resolver(Arc::new(resolver));
         FORCE_HTTPS_REDIRECT.store(true, Ordering::Relaxed);
-        start_proxy_https(https_proxy_addr, dao.clone(), servers.clone(), http_request_log_writer.clone(), tls_config.clone(), reverse_proxies.clone(), event_writer.clone());
-        start_panel_http(qpackt_config, dao, servers.clone(), Some(tls_config), reverse_proxies);
+        start_proxy_https(https_proxy_addr, dao.clone(), servers.clone(), http_request_log_writer.clone(), tls_config.clone(), reverse_proxies.clone(), experiments.clone(), event_writer.clone(), qpackt_config.clone(), cache.clone(), limiter, rewrites.clone());
+        start_panel_http(qpackt_config, dao, servers.clone(), Some(tls_config), reverse_proxies, experiments, cache, rewrites);
     }
 }
 
@@ -0,0 +1,186 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2024 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Decides how new visitors are bucketed into [crate::manager::strategy::Strategy::Weight] versions.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+use crate::dao::version::VersionName;
+use crate::error::QpacktError;
+
+/// Source of the key used to assign a visitor to a version.
+/// With anything other than [Assignment::Random] the same key always lands in the same version
+/// (as long as weights don't change), even if the visitor doesn't keep the version cookie.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) enum Assignment {
+    /// Pick a random version for every new visitor.
+    #[default]
+    Random,
+    /// Use client's address and User-Agent. Unlike [crate::analytics::hash::VisitorHash] the key isn't salted with
+    /// the daily seed, so visitors keep their version across days.
+    Visitor,
+    /// Use the value of the given request header, e.g. a user id set by a load balancer.
+    Header(String),
+    /// Use the value of the given cookie, e.g. a first-party id set by the site itself.
+    Cookie(String),
+}
+
+impl FromStr for Assignment {
+    type Err = QpacktError;
+
+    /// Parses values like `random`, `visitor`, `header:X-User-Id` or `cookie:uid`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "random" => Ok(Self::Random),
+            None if s == "visitor" => Ok(Self::Visitor),
+            Some(("header", name)) if !name.is_empty() => Ok(Self::Header(name.to_string())),
+            Some(("cookie", name)) if !name.is_empty() => Ok(Self::Cookie(name.to_string())),
+            _ => Err(QpacktError::InvalidConfig(format!("Invalid version assignment `{}`", s))),
+        }
+    }
+}
+
+impl std::fmt::Display for Assignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Assignment::Random => write!(f, "random"),
+            Assignment::Visitor => write!(f, "visitor"),
+            Assignment::Header(name) => write!(f, "header:{}", name),
+            Assignment::Cookie(name) => write!(f, "cookie:{}", name),
+        }
+    }
+}
+
+impl Assignment {
+    /// Calculates the key for the request. Returns `None` if the assignment is random or
+    /// the request doesn't carry the configured header/cookie.
+    pub(crate) fn key(&self, request: &HttpRequest, client_ip: IpAddr) -> Option<u64> {
+        match self {
+            Assignment::Random => None,
+            Assignment::Visitor => {
+                let user_agent = request.headers().get(header::USER_AGENT).map(|v| v.as_bytes()).unwrap_or_default();
+                Some(fnv1a(&[client_ip.to_string().as_bytes(), b"|", user_agent].concat()))
+            }
+            Assignment::Header(name) => request.headers().get(name).map(|v| fnv1a(v.as_bytes())),
+            Assignment::Cookie(name) => request.cookie(name).map(|c| fnv1a(c.value().as_bytes())),
+        }
+    }
+}
+
+/// Weighted rendezvous (highest random weight) score for the key and the version.
+/// The version with the highest score wins. Changing one version's weight only moves visitors
+/// from or to that version, every other visitor keeps its assignment.
+pub(crate) fn rendezvous_score(key: u64, version: &VersionName, weight: u16) -> f64 {
+    let hash = salted_hash(key, version.to_string().as_bytes());
+    // Map to (0, 1], never 0 so that ln() is finite.
+    let unit = ((hash >> 11) as f64 + 1.0) / (1u64 << 53) as f64;
+    weight as f64 / -unit.ln()
+}
+
+/// Hash of the key combined with `salt`, evenly spread for similar keys.
+pub(crate) fn salted_hash(key: u64, salt: &[u8]) -> u64 {
+    mix(key ^ fnv1a(salt))
+}
+
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Finalizer from splitmix64 to spread bits of similar keys.
+fn mix(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::path::Path;
+
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+
+    use crate::dao::version::{Version, VersionName};
+    use crate::manager::assignment::Assignment;
+    use crate::manager::strategy::Strategy;
+    use crate::server::{build_version_root, pick_by_key, VersionRoot};
+
+    fn roots(versions: &[(&VersionName, u16)]) -> (Vec<VersionRoot>, Vec<u16>) {
+        versions
+            .iter()
+            .map(|(name, weight)| {
+                let strategy = Strategy::Weight(*weight);
+                let version = Version {
+                    name: (*name).clone(),
+                    web_root: name.to_string().into(),
+                    strategy,
+                    drain: false,
+                    profile: Default::default(),
+                };
+                (build_version_root(version, Path::new("/nonexistent")), *weight)
+            })
+            .unzip()
+    }
+
+    fn pick(key: u64, (roots, weights): &(Vec<VersionRoot>, Vec<u16>)) -> VersionName {
+        pick_by_key(roots, weights, key).unwrap().1
+    }
+
+    #[test]
+    fn splits_proportionally_and_moves_minimum() {
+        const COUNT: u64 = 100_000;
+        let v1: VersionName = "v1".to_string().into();
+        let v2: VersionName = "v2".to_string().into();
+        let before = roots(&[(&v1, 90), (&v2, 10)]);
+        let after = roots(&[(&v1, 80), (&v2, 20)]);
+        let mut in_v2 = 0;
+        let mut moved = 0;
+        for key in 0..COUNT {
+            let old = pick(key, &before);
+            let new = pick(key, &after);
+            if old == v2 {
+                in_v2 += 1;
+                // Visitors may only move towards the version that gained weight.
+                assert_eq!(new, v2);
+            }
+            if old != new {
+                moved += 1;
+            }
+        }
+        assert!((9_000..11_000).contains(&in_v2), "v2 got {} visitors", in_v2);
+        assert!((9_000..11_000).contains(&moved), "moved {} visitors", moved);
+    }
+
+    #[test]
+    fn keys_visitors_by_address_and_user_agent() {
+        let request = |user_agent: &str| TestRequest::default().insert_header((header::USER_AGENT, user_agent)).to_http_request();
+        let ip = |last: u8| IpAddr::V4(Ipv4Addr::new(10, 0, 0, last));
+        let key = Assignment::Visitor.key(&request("firefox"), ip(1));
+        assert!(key.is_some());
+        assert_eq!(Assignment::Visitor.key(&request("firefox"), ip(1)), key);
+        assert_ne!(Assignment::Visitor.key(&request("chrome"), ip(1)), key);
+        assert_ne!(Assignment::Visitor.key(&request("firefox"), ip(2)), key);
+        assert_eq!(Assignment::Random.key(&request("firefox"), ip(1)), None);
+    }
+}
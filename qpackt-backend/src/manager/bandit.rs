@@ -0,0 +1,179 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Multi-armed bandit traffic allocation for [Strategy::Bandit](crate::manager::strategy::Strategy::Bandit) versions.
+//! Every version's share of traffic is the probability that it converts best (Thompson sampling), estimated from
+//! visits and goal events of the last [BANDIT_WINDOW_SECONDS].
+
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::time::Duration;
+
+use actix_web::web::Data;
+use log::{debug, error};
+use rand::{thread_rng, Rng};
+use tokio::time::sleep;
+
+use crate::dao::events::Conversions;
+use crate::dao::version::VersionName;
+use crate::dao::Dao;
+use crate::error::Result;
+use crate::manager::rollout::now;
+use crate::server::Versions;
+
+/// Weights of bandit versions used by [Versions] when picking a version for a new visitor.
+pub(crate) type Allocations = HashMap<VersionName, u16>;
+
+/// Sum of weights of all bandit versions.
+pub(crate) const BANDIT_TOTAL_WEIGHT: u16 = 10_000;
+
+/// How often allocations are recomputed.
+const BANDIT_RECOMPUTE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Only visits and events from this many last seconds are taken into account.
+const BANDIT_WINDOW_SECONDS: u64 = 7 * 24 * 60 * 60;
+
+/// Number of simulated draws when estimating the probability of being the best version.
+const THOMPSON_DRAWS: usize = 10_000;
+
+/// Minimum share of traffic every bandit version keeps, so that a version with an unlucky start can still recover.
+const MIN_SHARE: f64 = 0.01;
+
+/// Starts a background task that recomputes bandit allocations every [BANDIT_RECOMPUTE_INTERVAL].
+pub(crate) fn spawn_bandit_loop(dao: Data<Dao>, versions: Data<Versions>) {
+    tokio::spawn(async move {
+        debug!("Started bandit task");
+        loop {
+            if let Err(e) = recompute_allocations(&dao, &versions).await {
+                error!("Unable to recompute bandit allocations: {}", e);
+            }
+            sleep(BANDIT_RECOMPUTE_INTERVAL).await;
+        }
+    });
+}
+
+async fn recompute_allocations(dao: &Dao, versions: &Versions) -> Result<()> {
+    let goals = versions.bandit_goals().await;
+    if goals.is_empty() {
+        versions.set_allocations(Allocations::default());
+        return Ok(());
+    }
+    let to = now();
+    let from = to.saturating_sub(BANDIT_WINDOW_SECONDS);
+    // Versions usually share the goal, so query once per goal event.
+    let mut conversions: HashMap<&str, Vec<Conversions>> = HashMap::with_capacity(goals.len());
+    for (_, goal_event) in &goals {
+        if !conversions.contains_key(goal_event.as_str()) {
+            conversions.insert(goal_event, dao.get_conversions(from, to, goal_event).await?);
+        }
+    }
+    let arms = goals
+        .iter()
+        .map(|(name, goal_event)| {
+            conversions[goal_event.as_str()].iter().find(|c| &c.version == name).map(|c| (c.converted, c.visitors)).unwrap_or_default()
+        })
+        .collect::<Vec<_>>();
+    let shares = thompson_shares(&arms, &mut thread_rng());
+    let allocations = goals
+        .into_iter()
+        .zip(shares)
+        .map(|((name, _), share)| (name, (share * BANDIT_TOTAL_WEIGHT as f64) as u16))
+        .collect::<Allocations>();
+    debug!("New bandit allocations: {:?}", allocations);
+    versions.set_allocations(allocations);
+    Ok(())
+}
+
+/// Estimates for every `(converted, visitors)` arm the probability that it has the highest conversion rate.
+/// Every arm gets at least [MIN_SHARE] (or less if there are too many arms), shares add up to 1.
+fn thompson_shares(arms: &[(u64, u64)], rng: &mut impl Rng) -> Vec<f64> {
+    let mut wins = vec![0usize; arms.len()];
+    for _ in 0..THOMPSON_DRAWS {
+        let mut best: Option<(usize, f64)> = None;
+        for (i, (converted, visitors)) in arms.iter().enumerate() {
+            let sample = sample_beta(rng, (*converted + 1) as f64, (visitors.saturating_sub(*converted) + 1) as f64);
+            if best.is_none_or(|(_, best_sample)| sample > best_sample) {
+                best = Some((i, sample));
+            }
+        }
+        if let Some((i, _)) = best {
+            wins[i] += 1;
+        }
+    }
+    let min_share = MIN_SHARE.min(1.0 / arms.len() as f64);
+    let explore = 1.0 - min_share * arms.len() as f64;
+    wins.into_iter().map(|w| min_share + explore * w as f64 / THOMPSON_DRAWS as f64).collect()
+}
+
+/// Samples Beta(a, b) as X / (X + Y) where X ~ Gamma(a), Y ~ Gamma(b).
+fn sample_beta(rng: &mut impl Rng, a: f64, b: f64) -> f64 {
+    let x = sample_gamma(rng, a);
+    let y = sample_gamma(rng, b);
+    x / (x + y)
+}
+
+/// Marsaglia and Tsang's method, valid for `shape >= 1`.
+fn sample_gamma(rng: &mut impl Rng, shape: f64) -> f64 {
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        let x = sample_normal(rng);
+        let v = (1.0 + c * x).powi(3);
+        if v <= 0.0 {
+            continue;
+        }
+        let u = 1.0 - rng.gen::<f64>();
+        if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+            return d * v;
+        }
+    }
+}
+
+/// Box-Muller transform.
+fn sample_normal(rng: &mut impl Rng) -> f64 {
+    let u1 = 1.0 - rng.gen::<f64>();
+    let u2 = rng.gen::<f64>();
+    (-2.0 * u1.ln()).sqrt() * (2.0 * PI * u2).cos()
+}
+
+#[cfg(test)]
+mod test {
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use crate::manager::bandit::{sample_beta, thompson_shares, MIN_SHARE};
+
+    #[test]
+    fn beta_has_expected_mean() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mean = (0..10_000).map(|_| sample_beta(&mut rng, 3.0, 7.0)).sum::<f64>() / 10_000.0;
+        assert!((mean - 0.3).abs() < 0.01);
+    }
+
+    #[test]
+    fn favours_better_converting_version() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let shares = thompson_shares(&[(50, 1_000), (100, 1_000)], &mut rng);
+        assert!((shares.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+        assert!(shares[0] < MIN_SHARE + 0.001);
+        assert!(shares[1] > 0.98);
+        let shares = thompson_shares(&[(0, 0), (0, 0)], &mut rng);
+        assert!((shares[0] - 0.5).abs() < 0.05);
+    }
+}
@@ -17,4 +17,8 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+pub(crate) mod assignment;
+pub(crate) mod bandit;
+pub(crate) mod rollback;
+pub(crate) mod rollout;
 pub(crate) mod strategy;
@@ -0,0 +1,168 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Automatic rollback of a version that is being rolled out when its metrics get worse than baseline's.
+//! Baseline is the other version that had the most traffic in the evaluation window.
+//! 5xx rate is the upstream one: reverse proxy responses to visitors of the version, see [Dao::get_request_errors].
+//! Versions' files don't count, neither do responses served from cache.
+
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::dao::version::VersionName;
+use crate::dao::Dao;
+use crate::error::Result;
+use crate::manager::rollout::{now, set_percent, PercentChange, Rollout};
+use crate::panel::convert_to_response;
+use crate::server::Versions;
+
+/// Only visits and requests from this many last seconds are compared.
+const EVALUATION_WINDOW_SECONDS: u64 = 15 * 60;
+
+/// Minimum number of visits (for bounce rate) or reverse proxy responses (for 5xx rate) a version needs in the evaluation window
+/// before its rate is trusted. Prevents rollbacks caused by a few unlucky visitors.
+const MIN_SAMPLES: usize = 100;
+
+/// Thresholds in percentage points. `None` means the metric isn't checked.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct RollbackThresholds {
+    pub(crate) max_bounce_rate_increase: Option<f32>,
+    pub(crate) max_error_rate_increase: Option<f32>,
+}
+
+/// Record of an automatic rollback, so that it's visible in the panel why a version lost its traffic.
+#[derive(Debug, Serialize)]
+pub(crate) struct Rollback {
+    pub(crate) version: VersionName,
+    /// Unix time (seconds) of the rollback.
+    pub(crate) time: u64,
+    pub(crate) reason: String,
+}
+
+/// Rate (in percent) of a metric for a single version, together with number of samples it was computed from.
+struct VersionRate<'a> {
+    name: &'a VersionName,
+    samples: usize,
+    rate: f32,
+}
+
+/// Compares every running rollout (that already got some traffic) with its baseline.
+/// Versions that crossed a threshold get weight 0, their rollouts are removed and a [Rollback] is saved.
+pub(crate) async fn check_rollbacks(dao: &Dao, versions: &Versions) -> Result<()> {
+    let rollouts =
+        dao.list_rollouts().await?.into_iter().filter(|r| r.current_step > 0 && has_thresholds(&r.thresholds)).collect::<Vec<_>>();
+    if rollouts.is_empty() {
+        return Ok(());
+    }
+    let to = now();
+    let from = to - EVALUATION_WINDOW_SECONDS;
+    let stats = convert_to_response(dao.get_visits(from, to).await?).versions_stats;
+    let bounce_rates = stats.iter().map(|s| VersionRate { name: &s.name, samples: s.visit_count, rate: s.bounce_rate }).collect::<Vec<_>>();
+    let errors = dao.get_request_errors(from, to).await?;
+    let error_rates = errors
+        .iter()
+        .map(|e| VersionRate {
+            name: &e.version,
+            samples: e.request_count as usize,
+            rate: 100.0 * e.error_count as f32 / e.request_count as f32,
+        })
+        .collect::<Vec<_>>();
+    for rollout in rollouts {
+        let thresholds = &rollout.thresholds;
+        let reason = thresholds
+            .max_bounce_rate_increase
+            .and_then(|max| find_degradation("bounce rate", &bounce_rates, &rollout.version, max))
+            .or_else(|| {
+                thresholds.max_error_rate_increase.and_then(|max| find_degradation("5xx rate", &error_rates, &rollout.version, max))
+            });
+        match reason {
+            Some(reason) => roll_back(dao, versions, rollout, reason).await?,
+            None => debug!("Rollout of {} looks healthy", rollout.version),
+        }
+    }
+    Ok(())
+}
+
+fn has_thresholds(thresholds: &RollbackThresholds) -> bool {
+    thresholds.max_bounce_rate_increase.is_some() || thresholds.max_error_rate_increase.is_some()
+}
+
+/// Returns human readable reason if version's rate exceeds baseline's by more than `max_increase` percentage points.
+fn find_degradation(metric: &str, rates: &[VersionRate<'_>], version: &VersionName, max_increase: f32) -> Option<String> {
+    let canary = rates.iter().find(|r| r.name == version && r.samples >= MIN_SAMPLES)?;
+    let baseline = rates.iter().filter(|r| r.name != version && r.samples >= MIN_SAMPLES).max_by_key(|r| r.samples)?;
+    if canary.rate - baseline.rate <= max_increase {
+        return None;
+    }
+    Some(format!(
+        "{} {:.1}% vs {:.1}% of baseline `{}`, allowed increase is {:.1} percentage points",
+        metric, canary.rate, baseline.rate, baseline.name, max_increase
+    ))
+}
+
+async fn roll_back(dao: &Dao, versions: &Versions, rollout: Rollout, reason: String) -> Result<()> {
+    let mut change = PercentChange::NoVersion;
+    let current = dao
+        .update_versions(|current| {
+            change = set_percent(current, &rollout.version, 0);
+            change == PercentChange::Applied
+        })
+        .await?;
+    match change {
+        PercentChange::Applied => {}
+        // Deleted version, its rollout will be removed when advancing rollouts.
+        PercentChange::NoVersion => return Ok(()),
+        PercentChange::NoBaseline => {
+            warn!("Unable to roll back {} ({}), no other weighted version can take its traffic", rollout.version, reason);
+            return Ok(());
+        }
+    }
+    versions.update_strategies(&current).await;
+    dao.delete_rollout(&rollout.version).await?;
+    warn!("Rolled back {}: {}", rollout.version, reason);
+    dao.save_rollback(&Rollback { version: rollout.version, time: now(), reason }).await
+}
+
+#[cfg(test)]
+mod test {
+    use crate::dao::version::VersionName;
+    use crate::manager::rollback::{find_degradation, VersionRate};
+
+    #[test]
+    fn compares_with_busiest_baseline() {
+        let (canary, small, big): (VersionName, VersionName, VersionName) =
+            ("canary".to_string().into(), "small".to_string().into(), "big".to_string().into());
+        let rates = vec![
+            VersionRate { name: &canary, samples: 200, rate: 30.0 },
+            VersionRate { name: &small, samples: 150, rate: 5.0 },
+            VersionRate { name: &big, samples: 5_000, rate: 25.0 },
+        ];
+        assert!(find_degradation("bounce rate", &rates, &canary, 10.0).is_none());
+        let reason = find_degradation("bounce rate", &rates, &canary, 4.0).unwrap();
+        assert!(reason.contains("baseline `big`"));
+    }
+
+    #[test]
+    fn ignores_small_samples() {
+        let (canary, baseline): (VersionName, VersionName) = ("canary".to_string().into(), "baseline".to_string().into());
+        let rates =
+            vec![VersionRate { name: &canary, samples: 10, rate: 90.0 }, VersionRate { name: &baseline, samples: 5_000, rate: 1.0 }];
+        assert!(find_degradation("5xx rate", &rates, &canary, 1.0).is_none());
+    }
+}
@@ -0,0 +1,247 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Scheduled canary rollouts: a version gets more and more traffic in steps (e.g. 1% → 5% → 25% → 100%)
+//! without anyone updating weights by hand.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::web::Data;
+use log::{debug, error, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::time::sleep;
+
+use crate::dao::version::{Version, VersionName};
+use crate::dao::Dao;
+use crate::error::Result;
+use crate::manager::rollback::{check_rollbacks, RollbackThresholds};
+use crate::manager::strategy::Strategy;
+use crate::server::Versions;
+
+/// How often rollout plans are checked for due steps.
+const ROLLOUT_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Sum of all [Strategy::Weight]s after a rollout step, so that 1% of traffic equals weight 100.
+const TOTAL_WEIGHT: u32 = 10_000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct RolloutStep {
+    /// Percent of weighted traffic (0-100) the version gets in this step.
+    pub(crate) percent: u8,
+    /// Seconds to wait before applying the next step.
+    pub(crate) hold_seconds: u64,
+}
+
+/// Rollout plan for a single version.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Rollout {
+    pub(crate) version: VersionName,
+    pub(crate) steps: Vec<RolloutStep>,
+    /// Index of the next step to apply.
+    pub(crate) current_step: u32,
+    /// Unix time (seconds) when the next step is due.
+    pub(crate) next_step_time: u64,
+    pub(crate) paused: bool,
+    /// When to roll the version back instead of continuing.
+    pub(crate) thresholds: RollbackThresholds,
+}
+
+impl Rollout {
+    /// Creates a plan with the first step due immediately.
+    pub(crate) fn new(version: VersionName, steps: Vec<RolloutStep>, thresholds: RollbackThresholds) -> Self {
+        Self { version, steps, current_step: 0, next_step_time: now(), paused: false, thresholds }
+    }
+
+    /// Unpauses the plan. The hold time of the last applied step starts over.
+    pub(crate) fn resume(&mut self) {
+        let hold_seconds = self.current_step.checked_sub(1).map(|i| self.steps[i as usize].hold_seconds).unwrap_or_default();
+        self.next_step_time = now() + hold_seconds;
+        self.paused = false;
+    }
+
+    fn is_due(&self, now: u64) -> bool {
+        !self.paused && self.next_step_time <= now
+    }
+}
+
+/// Starts a background task that every [ROLLOUT_CHECK_INTERVAL] rolls back degraded versions and applies due rollout steps.
+pub(crate) fn spawn_rollout_loop(dao: Data<Dao>, versions: Data<Versions>) {
+    tokio::spawn(async move {
+        debug!("Started rollout task");
+        loop {
+            if let Err(e) = check_rollbacks(&dao, &versions).await {
+                error!("Unable to check rollbacks: {}", e);
+            }
+            if let Err(e) = advance_rollouts(&dao, &versions).await {
+                error!("Unable to advance rollouts: {}", e);
+            }
+            sleep(ROLLOUT_CHECK_INTERVAL).await;
+        }
+    });
+}
+
+/// Applies the next step of every due rollout: saves new weights and updates [Versions].
+/// Finished rollouts and rollouts of deleted versions are removed. The step is taken from the plan with
+/// [Dao::update_rollout] before it's applied, so that a rollout paused or cancelled meanwhile isn't overwritten.
+async fn advance_rollouts(dao: &Dao, versions: &Versions) -> Result<()> {
+    let now = now();
+    for rollout in dao.list_rollouts().await? {
+        if !rollout.is_due(now) {
+            continue;
+        }
+        let mut step = None;
+        let taken = dao
+            .update_rollout(&rollout.version, |rollout| {
+                if !rollout.is_due(now) {
+                    return false;
+                }
+                let RolloutStep { percent, hold_seconds } = rollout.steps[rollout.current_step as usize];
+                step = Some((rollout.current_step, percent));
+                rollout.current_step += 1;
+                rollout.next_step_time = now + hold_seconds;
+                true
+            })
+            .await?;
+        let (Some(rollout), Some((index, percent))) = (taken, step) else {
+            continue;
+        };
+        let mut change = PercentChange::NoVersion;
+        let current = dao
+            .update_versions(|current| {
+                change = set_percent(current, &rollout.version, percent);
+                change == PercentChange::Applied
+            })
+            .await?;
+        match change {
+            PercentChange::Applied => {}
+            PercentChange::NoVersion => {
+                warn!("Version {} no longer exists, removing its rollout", rollout.version);
+                dao.delete_rollout(&rollout.version).await?;
+                continue;
+            }
+            PercentChange::NoBaseline => {
+                warn!("No other weighted version can take traffic from {}, pausing its rollout", rollout.version);
+                dao.update_rollout(&rollout.version, |rollout| {
+                    rollout.current_step = index;
+                    rollout.paused = true;
+                    true
+                })
+                .await?;
+                continue;
+            }
+        }
+        versions.update_strategies(&current).await;
+        info!("Rollout of {} is now at {}%", rollout.version, percent);
+        if rollout.current_step as usize >= rollout.steps.len() {
+            info!("Finished rollout of {}", rollout.version);
+            dao.delete_rollout(&rollout.version).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Outcome of [set_percent].
+#[derive(Debug, PartialEq)]
+pub(super) enum PercentChange {
+    Applied,
+    /// There is no such version.
+    NoVersion,
+    /// Other versions have no weight and none of them can take the rest of the traffic.
+    NoBaseline,
+}
+
+/// Gives `percent` of weighted traffic to the version and scales other [Strategy::Weight] versions
+/// so that they keep their ratios. If they all have weight 0, the rest goes to the first of them that isn't drained,
+/// so that a small step doesn't send all weighted traffic to the version.
+pub(super) fn set_percent(versions: &mut [Version], name: &VersionName, percent: u8) -> PercentChange {
+    if !versions.iter().any(|v| &v.name == name) {
+        return PercentChange::NoVersion;
+    }
+    let others =
+        versions.iter().filter(|v| &v.name != name).map(|v| if let Strategy::Weight(w) = v.strategy { w as u32 } else { 0 }).sum::<u32>();
+    let remaining = TOTAL_WEIGHT * (100 - percent as u32) / 100;
+    let baseline = if others == 0 && remaining > 0 {
+        match versions.iter().position(|v| &v.name != name && !v.drain && matches!(v.strategy, Strategy::Weight(_))) {
+            Some(baseline) => Some(baseline),
+            None => return PercentChange::NoBaseline,
+        }
+    } else {
+        None
+    };
+    for (i, v) in versions.iter_mut().enumerate() {
+        if &v.name == name {
+            v.strategy = Strategy::Weight((TOTAL_WEIGHT * percent as u32 / 100) as u16);
+        } else if baseline == Some(i) {
+            v.strategy = Strategy::Weight(remaining as u16);
+        } else if let Strategy::Weight(w) = v.strategy {
+            if let Some(scaled) = (w as u32 * remaining).checked_div(others) {
+                v.strategy = Strategy::Weight(scaled as u16);
+            }
+        }
+    }
+    PercentChange::Applied
+}
+
+pub(super) fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use crate::dao::version::Version;
+    use crate::manager::rollout::{set_percent, PercentChange};
+    use crate::manager::strategy::Strategy;
+
+    fn version(name: &str, strategy: Strategy) -> Version {
+        Version { name: name.to_string().into(), web_root: PathBuf::from(name), strategy, drain: false, profile: Default::default() }
+    }
+
+    fn weight(version: &Version) -> u16 {
+        match version.strategy {
+            Strategy::Weight(w) => w,
+            _ => panic!("Not a weight"),
+        }
+    }
+
+    #[test]
+    fn scales_other_weights() {
+        let mut versions =
+            vec![version("a", Strategy::Weight(3)), version("b", Strategy::Weight(1)), version("c", Strategy::UrlParam("c".into()))];
+        assert_eq!(set_percent(&mut versions, &"c".to_string().into(), 20), PercentChange::Applied);
+        assert_eq!(weight(&versions[0]), 6_000);
+        assert_eq!(weight(&versions[1]), 2_000);
+        assert_eq!(weight(&versions[2]), 2_000);
+        assert_eq!(set_percent(&mut versions, &"c".to_string().into(), 100), PercentChange::Applied);
+        assert_eq!(versions.iter().map(weight).collect::<Vec<_>>(), vec![0, 0, 10_000]);
+        assert_eq!(set_percent(&mut versions, &"d".to_string().into(), 100), PercentChange::NoVersion);
+    }
+
+    #[test]
+    fn gives_rest_to_baseline_when_others_have_no_weight() {
+        let mut versions = vec![version("a", Strategy::Weight(0)), version("b", Strategy::Weight(0)), version("c", Strategy::Weight(0))];
+        versions[0].drain = true;
+        assert_eq!(set_percent(&mut versions, &"c".to_string().into(), 1), PercentChange::Applied);
+        assert_eq!(versions.iter().map(weight).collect::<Vec<_>>(), vec![0, 9_900, 100]);
+        let mut versions = vec![version("a", Strategy::UrlParam("a".into())), version("c", Strategy::Weight(0))];
+        assert_eq!(set_percent(&mut versions, &"c".to_string().into(), 1), PercentChange::NoBaseline);
+        assert_eq!(weight(&versions[1]), 0);
+    }
+}
@@ -17,11 +17,18 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use actix_web::http::header;
+use actix_web::HttpRequest;
 use serde::{Deserialize, Serialize};
 /*
 Deserialize to:
 {"Weight":20.0}
 {"UrlParam":"param"}
+{"Header":{"name":"X-Beta","value":"1"}}
+{"Language":"de"}
+{"Device":"Mobile"}
+{"Cookie":{"name":"plan","value":"pro"}}
+{"Bandit":{"goal_event":"signup"}}
  */
 /// Traffic split strategy.
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -42,4 +49,99 @@ pub(crate) enum Strategy {
     Weight(u16),
     /// Matches new sessions that have url query containing the string.
     UrlParam(String),
+    /// Matches new sessions with request header of exactly this value.
+    Header { name: String, value: String },
+    /// Matches new sessions whose most preferred `Accept-Language` is this language (e.g. `de` or `pt-BR`).
+    /// A rule without region (`pt`) matches all regions (`pt-BR`, `pt-PT`).
+    Language(String),
+    /// Matches new sessions from this class of devices (based on `User-Agent`).
+    Device(Device),
+    /// Matches new sessions with a cookie of exactly this value (e.g. set by the site itself).
+    Cookie { name: String, value: String },
+    /// Competes with other `Bandit` versions for weighted traffic. Share of traffic is periodically recomputed
+    /// (see [crate::manager::bandit]) from how often version's visitors fire `goal_event`, so that better
+    /// converting versions get more visitors. All `Bandit` versions together weigh as much as [Strategy::Weight]s summing to 10000.
+    Bandit { goal_event: String },
+}
+
+/// Device class derived from `User-Agent`.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) enum Device {
+    Mobile,
+    Desktop,
+}
+
+impl Strategy {
+    /// Checks if the request matches a targeting rule. [Strategy::Weight] and [Strategy::Bandit] never match.
+    pub(crate) fn matches(&self, request: &HttpRequest) -> bool {
+        match self {
+            Strategy::Weight(_) | Strategy::Bandit { .. } => false,
+            Strategy::UrlParam(needle) => request.query_string().contains(needle),
+            Strategy::Header { name, value } => request.headers().get(name).is_some_and(|v| v.as_bytes() == value.as_bytes()),
+            Strategy::Language(language) => {
+                let accept = request.headers().get(header::ACCEPT_LANGUAGE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+                preferred_language(accept).is_some_and(|preferred| language_matches(language, preferred))
+            }
+            Strategy::Device(device) => {
+                let user_agent = request.headers().get(header::USER_AGENT).and_then(|v| v.to_str().ok()).unwrap_or_default();
+                device_from_user_agent(user_agent) == *device
+            }
+            Strategy::Cookie { name, value } => request.cookie(name).is_some_and(|c| c.value() == value),
+        }
+    }
+}
+
+/// Returns the language with the highest quality from `Accept-Language` header (first one wins on ties).
+fn preferred_language(accept_language: &str) -> Option<&str> {
+    let mut best: Option<(&str, f32)> = None;
+    for item in accept_language.split(',') {
+        let mut parts = item.split(';');
+        let language = parts.next().unwrap_or_default().trim();
+        let quality = parts.find_map(|p| p.trim().strip_prefix("q=")).and_then(|q| q.parse().ok()).unwrap_or(1.0);
+        if language.is_empty() || language == "*" || quality <= 0.0 {
+            continue;
+        }
+        if best.is_none_or(|(_, best_quality)| quality > best_quality) {
+            best = Some((language, quality));
+        }
+    }
+    best.map(|(language, _)| language)
+}
+
+fn language_matches(rule: &str, language: &str) -> bool {
+    if rule.eq_ignore_ascii_case(language) {
+        return true;
+    }
+    !rule.contains('-') && language.split('-').next().is_some_and(|primary| primary.eq_ignore_ascii_case(rule))
+}
+
+fn device_from_user_agent(user_agent: &str) -> Device {
+    if ["Mobi", "Android", "iPhone", "iPad"].iter().any(|needle| user_agent.contains(needle)) {
+        Device::Mobile
+    } else {
+        Device::Desktop
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::manager::strategy::{device_from_user_agent, language_matches, preferred_language, Device};
+
+    #[test]
+    fn picks_preferred_language() {
+        assert_eq!(preferred_language("de-DE,de;q=0.9,en;q=0.8"), Some("de-DE"));
+        assert_eq!(preferred_language("en;q=0.5, fr"), Some("fr"));
+        assert_eq!(preferred_language("*"), None);
+        assert!(language_matches("de", "de-DE"));
+        assert!(language_matches("pt-BR", "pt-br"));
+        assert!(!language_matches("pt-BR", "pt-PT"));
+    }
+
+    #[test]
+    fn detects_device() {
+        let iphone = "Mozilla/5.0 (iPhone; CPU iPhone OS 17_0 like Mac OS X) AppleWebKit/605.1.15 Mobile/15E148";
+        let linux = "Mozilla/5.0 (X11; Linux x86_64; rv:120.0) Gecko/20100101 Firefox/120.0";
+        assert_eq!(device_from_user_agent(iphone), Device::Mobile);
+        assert_eq!(device_from_user_agent(linux), Device::Desktop);
+    }
 }
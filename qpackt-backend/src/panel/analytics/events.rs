@@ -109,13 +109,13 @@ async fn get_events_db(dao: Data<Dao>, filter: GetEventsFilter, dao_sender: Send
 type ResponseItem = std::result::Result<Bytes, QpacktError>;
 
 async fn map_to_csv(mut dao_receiver: Receiver<SavedEventData>, response_sender: Sender<ResponseItem>) {
-    let line = "id,time,event,version,visitor,params,path,payload\r\n";
+    let line = "id,time,event,version,visitor,params,path,payload,experiments\r\n";
     response_sender.send(Ok(Bytes::from(line))).await.unwrap();
     while let Some(event) = dao_receiver.recv().await {
         let time = DateTime::from_timestamp(event.event.time as i64, 0).unwrap();
         let time = time.format("%Y-%m-%d %H:%M");
-        let line = format!("{},{},{},{},{},{},{},{}\r\n",
-                           event.id, time, event.event.name, event.event.version, event.event.visitor, event.event.params, event.event.path, event.event.payload
+        let line = format!("{},{},{},{},{},{},{},{},{}\r\n",
+                           event.id, time, event.event.name, event.event.version, event.event.visitor, event.event.params, event.event.path, event.event.payload, event.event.experiments
         );
         let mut m_bytes = Some(Ok(Bytes::from(line)));
         while let Some(bytes) = m_bytes.take() {
@@ -39,34 +39,51 @@ const BOUNCE_VISIT_MAX_LENGTH: u64 = 5;
 pub(super) struct DateRange {
     pub(super) from_time: DateTime<Utc>,
     pub(super) to_time: DateTime<Utc>,
+    /// If set, stats are grouped by versions assigned in this [crate::experiment::Experiment] instead of site's versions.
+    #[serde(default)]
+    pub(super) experiment: Option<i32>,
 }
 
 #[derive(Serialize)]
-struct AnalyticsResponse {
-    total_visit_count: usize,
-    versions_stats: Vec<VersionStats>,
+pub(crate) struct AnalyticsResponse {
+    pub(crate) total_visit_count: usize,
+    pub(crate) versions_stats: Vec<VersionStats>,
 }
 
 /// Stats for single [VersionName].
 #[derive(Serialize)]
-struct VersionStats {
-    name: VersionName,
-    average_requests: f32,
-    average_duration: u32,
-    bounce_rate: f32,
-    visit_count: usize,
+pub(crate) struct VersionStats {
+    pub(crate) name: VersionName,
+    pub(crate) average_requests: f32,
+    pub(crate) average_duration: u32,
+    pub(crate) bounce_rate: f32,
+    pub(crate) visit_count: usize,
 }
 
 pub(crate) async fn get_analytics(http_request: HttpRequest, request: Json<DateRange>, dao: Data<Dao>) -> Result<impl Responder> {
     validate_permission(&http_request)?;
     let from = request.from_time.timestamp() as u64;
     let to = request.to_time.timestamp() as u64;
-    let visits = dao.get_visits(from, to).await.unwrap();
+    let mut visits = dao.get_visits(from, to).await.unwrap();
+    if let Some(experiment) = request.experiment {
+        visits = slice_by_experiment(visits, experiment);
+    }
     let response = convert_to_response(visits);
     Ok(Json(response))
 }
 
-fn convert_to_response(visits: Vec<Visit>) -> AnalyticsResponse {
+/// Replaces every visit's version with the one assigned in the experiment. Visits without assignment are dropped.
+fn slice_by_experiment(visits: Vec<Visit>, experiment: i32) -> Vec<Visit> {
+    visits
+        .into_iter()
+        .filter_map(|mut visit| {
+            visit.version = visit.experiments.get(experiment)?.clone();
+            Some(visit)
+        })
+        .collect()
+}
+
+pub(crate) fn convert_to_response(visits: Vec<Visit>) -> AnalyticsResponse {
     let total_visit_count = visits.len();
     let mut versions_stats = HashMap::with_capacity(16);
     // In the first pass add all the numbers
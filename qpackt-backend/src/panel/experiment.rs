@@ -0,0 +1,85 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::dao::Dao;
+use crate::error::{QpacktError, Result};
+use crate::experiment::{Experiments, Variant};
+use crate::panel::validate_permission;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{HttpRequest, Responder};
+use log::{debug, info};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateExperimentRequest {
+    name: String,
+    path_prefix: String,
+    variants: Vec<Variant>,
+}
+
+pub(crate) async fn list_experiments(request: HttpRequest, dao: Data<Dao>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    debug!("Listing experiments");
+    Ok(Json(dao.list_experiments().await?))
+}
+
+pub(crate) async fn create_experiment(
+    request: HttpRequest,
+    dao: Data<Dao>,
+    experiments: Data<Experiments>,
+    Json(create_experiment_request): Json<CreateExperimentRequest>,
+) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    debug!("Creating experiment: {:?}", create_experiment_request);
+    let CreateExperimentRequest { name, path_prefix, variants } = create_experiment_request;
+    if !path_prefix.starts_with('/') {
+        return Err(QpacktError::InvalidRequest("path prefix must start with `/`".into()));
+    }
+    // `/shop/` and `/shop` cover the same paths, only one experiment may run there.
+    let path_prefix = match path_prefix.trim_end_matches('/') {
+        "" => "/".to_string(),
+        prefix => prefix.to_string(),
+    };
+    if variants.iter().all(|v| v.weight == 0) {
+        return Err(QpacktError::InvalidRequest("at least one variant needs weight above 0".into()));
+    }
+    let versions = dao.list_versions().await?;
+    if let Some(missing) = variants.iter().find(|variant| !versions.iter().any(|v| v.name == variant.version)) {
+        return Err(QpacktError::InvalidRequest(format!("no such version `{}`", missing.version)));
+    }
+    dao.create_experiment(&name, &path_prefix, &variants).await?;
+    experiments.set(dao.list_experiments().await?);
+    info!("Created experiment {} for {}", name, path_prefix);
+    Ok("OK".to_string())
+}
+
+pub(crate) async fn delete_experiment(
+    request: HttpRequest,
+    dao: Data<Dao>,
+    experiments: Data<Experiments>,
+    id: Path<i32>,
+) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    let id = id.into_inner();
+    debug!("Deleting experiment {}", id);
+    dao.delete_experiment(id).await?;
+    experiments.set(dao.list_experiments().await?);
+    info!("Deleted experiment {}", id);
+    Ok("OK".to_string())
+}
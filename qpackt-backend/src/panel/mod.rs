@@ -32,33 +32,48 @@ use auth::token::get_token;
 use crate::config::QpacktConfig;
 use crate::dao::Dao;
 use crate::error::{QpacktError, Result};
+use crate::experiment::Experiments;
 use crate::https_redirect::CheckHttpsRedirect;
 use crate::panel::analytics::events::{get_events_csv, get_events_stats};
 use crate::panel::analytics::get_analytics;
 use crate::panel::auth::token::{invalidate_token, is_token_valid};
-use crate::panel::reverse_proxy::{create_proxy, delete_proxy, list_proxies};
+use crate::panel::experiment::{create_experiment, delete_experiment, list_experiments};
+use crate::panel::reverse_proxy::{add_target, cache_stats, create_proxy, delete_proxy, delete_target, list_proxies, purge_cache};
+use crate::panel::rewrite::{create_rewrite, delete_rewrite, list_rewrites, update_rewrite};
+use crate::panel::rollout::{cancel_rollout, create_rollout, list_rollbacks, list_rollouts, pause_rollout, resume_rollout};
 use crate::panel::versions::delete::delete_version;
 use crate::panel::versions::list::list_versions;
 use crate::panel::versions::update::update_versions;
 use crate::panel::versions::upload::upload_version;
+use crate::proxy::cache::ResponseCache;
 use crate::reverse_proxy::ReverseProxies;
+use crate::rewrite::Rewrites;
 use crate::server::Versions;
 
 mod analytics;
 pub(crate) mod auth;
+mod experiment;
 pub(crate) mod reverse_proxy;
+mod rewrite;
+mod rollout;
 mod versions;
 
+pub(crate) use analytics::convert_to_response;
+
 const PANEL_HTTP: &str = "0.0.0.0:9080";
 // TODO turn port into constant (for tests)
 const PANEL_HTTPS: &str = "0.0.0.0:9443";
 
+#[allow(clippy::too_many_arguments)]
 pub(super) fn start_panel_http(
     config: Data<QpacktConfig>,
     dao: Data<Dao>,
     versions: Data<Versions>,
     tls_config: Option<ServerConfig>,
     reverse_proxies: Data<ReverseProxies>,
+    experiments: Data<Experiments>,
+    cache: Data<ResponseCache>,
+    rewrites: Data<Rewrites>,
 ) {
     tokio::spawn({
         let app_config = config.clone();
@@ -70,9 +85,24 @@ pub(super) fn start_panel_http(
                 .app_data(versions.clone())
                 .app_data(dao.clone())
                 .app_data(reverse_proxies.clone())
+                .app_data(experiments.clone())
+                .app_data(cache.clone())
+                .app_data(rewrites.clone())
                 .service(web::resource("/analytics").route(web::post().to(get_analytics)))
+                .service(web::resource("/experiment").get(list_experiments).post(create_experiment))
+                .service(web::resource("/experiment/{id}").delete(delete_experiment))
                 .service(web::resource("/proxy").get(list_proxies).post(create_proxy))
+                .service(web::resource("/proxy/cache").get(cache_stats).delete(purge_cache))
                 .service(web::resource("/proxy/{id}").delete(delete_proxy))
+                .service(web::resource("/proxy/{id}/target").post(add_target))
+                .service(web::resource("/proxy/{id}/target/{target_id}").delete(delete_target))
+                .service(web::resource("/rewrite").get(list_rewrites).post(create_rewrite))
+                .service(web::resource("/rewrite/{id}").put(update_rewrite).delete(delete_rewrite))
+                .service(web::resource("/rollback").get(list_rollbacks))
+                .service(web::resource("/rollout").get(list_rollouts).post(create_rollout))
+                .service(web::resource("/rollout/{version}").delete(cancel_rollout))
+                .service(web::resource("/rollout/{version}/pause").put(pause_rollout))
+                .service(web::resource("/rollout/{version}/resume").put(resume_rollout))
                 .service(web::resource("/token").delete(invalidate_token).post(get_token))
                 .service(web::resource("/version").post(upload_version))
                 .service(web::resource("/version/{name}").route(web::delete().to(delete_version)))
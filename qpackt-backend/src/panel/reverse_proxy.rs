@@ -17,36 +17,158 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::dao::version::VersionName;
 use crate::dao::Dao;
 use crate::error::{QpacktError, Result};
 use crate::panel::validate_permission;
-use crate::reverse_proxy::ReverseProxies;
-use actix_web::web::{Data, Json, Path};
+use crate::proxy::cache::ResponseCache;
+use crate::reverse_proxy::{Balancing, HeaderRule, Matcher, ProxyCache, ProxyHeaders, ReverseProxies, Target};
+use actix_web::web::{Data, Json, Path, Query};
 use actix_web::{HttpRequest, Responder};
 use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::str::FromStr;
+use std::time::Duration;
 use url::Url;
 
+/// Default number of failed requests in a row after which a target is ejected.
+const DEFAULT_MAX_FAILURES: u32 = 3;
+
 #[derive(Serialize)]
 pub(crate) struct ReverseProxyDTO {
     id: i32,
     prefix: String,
+    host: Option<String>,
+    methods: Vec<String>,
+    path_regex: Option<String>,
+    rewrite: Option<String>,
+    priority: i32,
+    preserve_host: bool,
+    header_rules: Vec<HeaderRule>,
+    cache: bool,
+    cache_ttl_seconds: Option<u64>,
+    balancing: Balancing,
+    health_check_path: Option<String>,
+    max_failures: u32,
+    targets: Vec<TargetDTO>,
+}
+
+/// Pool member with its current health.
+#[derive(Serialize)]
+pub(crate) struct TargetDTO {
+    id: i32,
     target: String,
+    /// Version whose visitors the target gets, fallback target if missing.
+    version: Option<VersionName>,
+    /// Result of the last active health check.
+    healthy: bool,
+    /// Whether the target gets no traffic after failing too many requests in a row.
+    ejected: bool,
+    failures: u32,
+    connections: usize,
+}
+
+impl From<&Target> for TargetDTO {
+    fn from(target: &Target) -> Self {
+        Self {
+            id: target.id,
+            target: target.url.clone(),
+            version: target.version.clone(),
+            healthy: target.state.is_healthy(),
+            ejected: target.state.is_ejected(),
+            failures: target.state.failures(),
+            connections: target.state.connections(),
+        }
+    }
 }
 
+/// Single `target` is kept for compatibility, it's added to `targets` - fallback targets for visitors of versions
+/// without targets in `version_targets` and visitors without a version. Rule needs a `prefix` or a `path_regex`,
+/// see [Matcher] for the rest of its fields.
 #[derive(Deserialize)]
 pub(crate) struct CreateReverseProxyRequest {
+    #[serde(default)]
     prefix: String,
+    #[serde(default)]
+    host: Option<String>,
+    #[serde(default)]
+    methods: Vec<String>,
+    #[serde(default)]
+    path_regex: Option<String>,
+    #[serde(default)]
+    rewrite: Option<String>,
+    #[serde(default)]
+    priority: i32,
+    /// Client's `Host` is sent to targets by default.
+    #[serde(default)]
+    preserve_host: Option<bool>,
+    #[serde(default)]
+    header_rules: Vec<HeaderRule>,
+    #[serde(default)]
+    cache: bool,
+    /// Overrides lifetime of cached responses.
+    #[serde(default)]
+    cache_ttl_seconds: Option<u64>,
+    #[serde(default)]
+    target: Option<String>,
+    #[serde(default)]
+    targets: Vec<String>,
+    #[serde(default)]
+    version_targets: BTreeMap<VersionName, Vec<String>>,
+    #[serde(default)]
+    balancing: Balancing,
+    #[serde(default)]
+    health_check_path: Option<String>,
+    #[serde(default)]
+    max_failures: Option<u32>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct AddTargetRequest {
     target: String,
+    /// Fallback target if missing.
+    #[serde(default)]
+    version: Option<VersionName>,
+}
+
+/// Cached responses under the prefix are removed, all of them if it's missing.
+#[derive(Deserialize)]
+pub(crate) struct PurgeCacheRequest {
+    #[serde(default)]
+    prefix: String,
 }
 
-pub(crate) async fn list_proxies(request: HttpRequest, dao: Data<Dao>) -> Result<impl Responder> {
+/// Number of purged entries.
+#[derive(Serialize)]
+pub(crate) struct PurgeCacheResponse {
+    purged: usize,
+}
+
+pub(crate) async fn list_proxies(request: HttpRequest, reverse_proxies: Data<ReverseProxies>) -> Result<impl Responder> {
     validate_permission(&request)?;
     debug!("Listing proxies");
-    let proxies = dao.list_reverse_proxies().await?;
-    let proxies =
-        proxies.into_iter().map(|p| ReverseProxyDTO { id: p.id, prefix: p.prefix, target: p.target.to_string() }).collect::<Vec<_>>();
+    let proxies = reverse_proxies
+        .list()
+        .iter()
+        .map(|p| ReverseProxyDTO {
+            id: p.id,
+            prefix: p.prefix.clone(),
+            host: p.matcher.host.clone(),
+            methods: p.matcher.methods.iter().map(|m| m.to_string()).collect(),
+            path_regex: p.matcher.path_regex.as_ref().map(|r| r.to_string()),
+            rewrite: p.matcher.rewrite.clone(),
+            priority: p.matcher.priority,
+            preserve_host: p.headers.preserve_host,
+            header_rules: p.headers.rules.clone(),
+            cache: p.cache.enabled,
+            cache_ttl_seconds: p.cache.ttl.map(|ttl| ttl.as_secs()),
+            balancing: p.balancing,
+            health_check_path: p.health_check_path.clone(),
+            max_failures: p.max_failures,
+            targets: p.targets.iter().map(TargetDTO::from).collect(),
+        })
+        .collect::<Vec<_>>();
     debug!("Got {} proxies", proxies.len());
     Ok(Json(proxies))
 }
@@ -73,15 +195,124 @@ pub(crate) async fn create_proxy(
     reverse_proxies: Data<ReverseProxies>,
     Json(create_reverse_proxy_request): Json<CreateReverseProxyRequest>,
 ) -> Result<impl Responder> {
-    debug!("Creating reverse proxy: {} -> {}", create_reverse_proxy_request.prefix, create_reverse_proxy_request.target);
+    let CreateReverseProxyRequest {
+        prefix,
+        host,
+        methods,
+        path_regex,
+        rewrite,
+        priority,
+        preserve_host,
+        header_rules,
+        cache,
+        cache_ttl_seconds,
+        target,
+        mut targets,
+        version_targets,
+        balancing,
+        health_check_path,
+        max_failures,
+    } = create_reverse_proxy_request;
+    targets.extend(target);
+    debug!("Creating reverse proxy: {} -> {:?}", path_regex.as_deref().unwrap_or(&prefix), targets);
     validate_permission(&request)?;
-    if let Err(e) = Url::from_str(&create_reverse_proxy_request.target) {
-        warn!("Invalid URL when attempting to create proxy `{}`: {}", create_reverse_proxy_request.target, e);
-        return Err(QpacktError::ProxyError);
+    if prefix.is_empty() && path_regex.is_none() && host.is_none() {
+        return Err(QpacktError::InvalidRequest("Reverse proxy needs a prefix, a path regex or a host".into()));
+    }
+    let matcher = Matcher::new(host, &methods.join(","), path_regex.as_deref(), rewrite, priority)?;
+    for rule in &header_rules {
+        rule.validate()?;
+    }
+    let headers = ProxyHeaders { preserve_host: preserve_host.unwrap_or(true), rules: header_rules };
+    let cache = ProxyCache { enabled: cache, ttl: cache_ttl_seconds.map(Duration::from_secs) };
+    if targets.is_empty() {
+        return Err(QpacktError::InvalidRequest("Reverse proxy needs at least one fallback target".into()));
+    }
+    if let Some(path) = health_check_path.as_deref().filter(|path| !path.starts_with('/')) {
+        return Err(QpacktError::InvalidRequest(format!("Health check path `{}` must start with `/`", path)));
+    }
+    let targets = targets
+        .into_iter()
+        .map(|target| (target, None))
+        .chain(version_targets.into_iter().flat_map(|(version, targets)| targets.into_iter().map(move |target| (target, Some(version.clone())))))
+        .collect::<Vec<_>>();
+    for (target, _) in &targets {
+        validate_target(target)?;
     }
-    dao.create_reverse_proxy(&create_reverse_proxy_request.prefix, &create_reverse_proxy_request.target).await?;
+    validate_target_versions(&dao, targets.iter().filter_map(|(_, version)| version.as_ref())).await?;
+    let max_failures = max_failures.unwrap_or(DEFAULT_MAX_FAILURES);
+    dao.create_reverse_proxy(&prefix, &matcher, &headers, cache, &targets, balancing, health_check_path.as_deref(), max_failures).await?;
     let current = dao.list_reverse_proxies().await?;
     reverse_proxies.set(current).await;
-    debug!("Created reverse proxy: {} -> {}", create_reverse_proxy_request.prefix, create_reverse_proxy_request.target);
+    debug!("Created reverse proxy: {} -> {:?}", path_regex.as_deref().unwrap_or(&prefix), targets);
     Ok("OK".to_string())
 }
+
+pub(crate) async fn add_target(
+    request: HttpRequest,
+    dao: Data<Dao>,
+    reverse_proxies: Data<ReverseProxies>,
+    id: Path<i32>,
+    Json(add_target_request): Json<AddTargetRequest>,
+) -> Result<impl Responder> {
+    let id = id.into_inner();
+    debug!("Adding target {} ({:?}) to proxy {}", add_target_request.target, add_target_request.version, id);
+    validate_permission(&request)?;
+    validate_target(&add_target_request.target)?;
+    validate_target_versions(&dao, add_target_request.version.iter()).await?;
+    if !reverse_proxies.list().iter().any(|p| p.id == id) {
+        return Err(QpacktError::InvalidRequest(format!("No reverse proxy {}", id)));
+    }
+    dao.add_reverse_proxy_target(id, &add_target_request.target, add_target_request.version.as_ref()).await?;
+    let current = dao.list_reverse_proxies().await?;
+    reverse_proxies.set(current).await;
+    info!("Added target {} to proxy {}", add_target_request.target, id);
+    Ok("OK".to_string())
+}
+
+pub(crate) async fn delete_target(
+    request: HttpRequest,
+    dao: Data<Dao>,
+    reverse_proxies: Data<ReverseProxies>,
+    path: Path<(i32, i32)>,
+) -> Result<impl Responder> {
+    let (id, target_id) = path.into_inner();
+    debug!("Deleting target {} of proxy {}", target_id, id);
+    validate_permission(&request)?;
+    dao.delete_reverse_proxy_target(id, target_id).await?;
+    let current = dao.list_reverse_proxies().await?;
+    reverse_proxies.set(current).await;
+    info!("Deleted target {} of proxy {}", target_id, id);
+    Ok("OK".to_string())
+}
+
+fn validate_target(target: &str) -> Result<()> {
+    if let Err(e) = Url::from_str(target) {
+        warn!("Invalid URL when attempting to create proxy `{}`: {}", target, e);
+        return Err(QpacktError::ProxyError);
+    }
+    Ok(())
+}
+
+/// Targets can only be added for existing versions, otherwise nobody would ever be routed to them.
+async fn validate_target_versions(dao: &Dao, versions: impl Iterator<Item = &VersionName>) -> Result<()> {
+    let existing = dao.list_versions().await?;
+    for version in versions {
+        if !existing.iter().any(|v| v.name == *version) {
+            return Err(QpacktError::InvalidRequest(format!("No version {}", version)));
+        }
+    }
+    Ok(())
+}
+
+pub(crate) async fn cache_stats(request: HttpRequest, cache: Data<ResponseCache>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    Ok(Json(cache.stats()))
+}
+
+pub(crate) async fn purge_cache(request: HttpRequest, cache: Data<ResponseCache>, purge: Query<PurgeCacheRequest>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    let purged = cache.purge(&purge.prefix).await;
+    info!("Purged {} cached responses under `{}`", purged, purge.prefix);
+    Ok(Json(PurgeCacheResponse { purged }))
+}
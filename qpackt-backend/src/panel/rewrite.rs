@@ -0,0 +1,109 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::dao::Dao;
+use crate::error::{QpacktError, Result};
+use crate::panel::validate_permission;
+use crate::rewrite::{Rewrite, Rewrites};
+use actix_web::web::{Data, Json, Path};
+use actix_web::{HttpRequest, Responder};
+use log::{debug, info};
+use serde::Deserialize;
+
+/// See [Rewrite] for the fields.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RewriteRequest {
+    source: String,
+    destination: String,
+    #[serde(default = "default_status")]
+    status: u16,
+    #[serde(default = "default_preserve_query")]
+    preserve_query: bool,
+    #[serde(default)]
+    priority: i32,
+}
+
+fn default_status() -> u16 {
+    301
+}
+
+fn default_preserve_query() -> bool {
+    true
+}
+
+impl RewriteRequest {
+    fn into_rewrite(self, id: i32) -> Result<Rewrite> {
+        Rewrite::new(id, self.source, self.destination, self.status, self.preserve_query, self.priority)
+    }
+}
+
+pub(crate) async fn list_rewrites(request: HttpRequest, dao: Data<Dao>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    debug!("Listing rewrites");
+    Ok(Json(dao.list_rewrites().await?))
+}
+
+pub(crate) async fn create_rewrite(
+    request: HttpRequest,
+    dao: Data<Dao>,
+    rewrites: Data<Rewrites>,
+    Json(rewrite_request): Json<RewriteRequest>,
+) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    debug!("Creating rewrite: {:?}", rewrite_request);
+    let rewrite = rewrite_request.into_rewrite(0)?;
+    dao.create_rewrite(&rewrite).await?;
+    rewrites.set(dao.list_rewrites().await?).await;
+    info!("Created rewrite {} -> {}", rewrite.source, rewrite.destination);
+    Ok("OK".to_string())
+}
+
+pub(crate) async fn update_rewrite(
+    request: HttpRequest,
+    dao: Data<Dao>,
+    rewrites: Data<Rewrites>,
+    id: Path<i32>,
+    Json(rewrite_request): Json<RewriteRequest>,
+) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    let id = id.into_inner();
+    debug!("Updating rewrite {}: {:?}", id, rewrite_request);
+    let rewrite = rewrite_request.into_rewrite(id)?;
+    if !dao.update_rewrite(&rewrite).await? {
+        return Err(QpacktError::InvalidRequest(format!("No rewrite {}", id)));
+    }
+    rewrites.set(dao.list_rewrites().await?).await;
+    info!("Updated rewrite {}", id);
+    Ok("OK".to_string())
+}
+
+pub(crate) async fn delete_rewrite(
+    request: HttpRequest,
+    dao: Data<Dao>,
+    rewrites: Data<Rewrites>,
+    id: Path<i32>,
+) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    let id = id.into_inner();
+    debug!("Deleting rewrite {}", id);
+    dao.delete_rewrite(id).await?;
+    rewrites.set(dao.list_rewrites().await?).await;
+    info!("Deleted rewrite {}", id);
+    Ok("OK".to_string())
+}
@@ -0,0 +1,116 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+use crate::dao::version::VersionName;
+use crate::dao::Dao;
+use crate::error::{QpacktError, Result};
+use crate::manager::rollback::RollbackThresholds;
+use crate::manager::rollout::{Rollout, RolloutStep};
+use crate::panel::validate_permission;
+use actix_web::web::{Data, Json, Path};
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use awc::http::StatusCode;
+use log::{debug, info, warn};
+use serde::Deserialize;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct CreateRolloutRequest {
+    version: VersionName,
+    steps: Vec<RolloutStep>,
+    #[serde(flatten)]
+    thresholds: RollbackThresholds,
+}
+
+pub(crate) async fn list_rollouts(request: HttpRequest, dao: Data<Dao>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    debug!("Listing rollouts");
+    Ok(Json(dao.list_rollouts().await?))
+}
+
+/// Lists automatic rollbacks together with reasons why they happened.
+pub(crate) async fn list_rollbacks(request: HttpRequest, dao: Data<Dao>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    debug!("Listing rollbacks");
+    Ok(Json(dao.list_rollbacks().await?))
+}
+
+/// Creates a rollout plan. The first step is applied by the rollout task shortly after.
+/// Only one rollout can run at a time, otherwise plans would fight over the same weights.
+pub(crate) async fn create_rollout(
+    request: HttpRequest,
+    dao: Data<Dao>,
+    Json(create_rollout_request): Json<CreateRolloutRequest>,
+) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    debug!("Creating rollout: {:?}", create_rollout_request);
+    let CreateRolloutRequest { version, steps, thresholds } = create_rollout_request;
+    if steps.is_empty() || steps.iter().any(|s| s.percent > 100) {
+        warn!("Invalid rollout steps for {}: {:?}", version, steps);
+        return Err(QpacktError::InvalidRequest("steps must be non-empty with percent between 0 and 100".into()));
+    }
+    if [thresholds.max_bounce_rate_increase, thresholds.max_error_rate_increase].iter().flatten().any(|t| *t < 0.0) {
+        return Err(QpacktError::InvalidRequest("rollback thresholds can't be negative".into()));
+    }
+    if !dao.list_versions().await?.iter().any(|v| v.name == version) {
+        return Err(QpacktError::InvalidRequest(format!("no such version `{}`", version)));
+    }
+    if let Some(running) = dao.list_rollouts().await?.first() {
+        return Err(QpacktError::InvalidRequest(format!("rollout of `{}` is already running", running.version)));
+    }
+    dao.save_rollout(&Rollout::new(version.clone(), steps, thresholds)).await?;
+    info!("Created rollout of {}", version);
+    Ok(HttpResponse::new(StatusCode::CREATED))
+}
+
+pub(crate) async fn pause_rollout(request: HttpRequest, dao: Data<Dao>, version: Path<String>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    let version = version.into_inner().into();
+    update_rollout(&dao, &version, |rollout| rollout.paused = true).await?;
+    info!("Paused rollout of {}", version);
+    Ok("OK".to_string())
+}
+
+pub(crate) async fn resume_rollout(request: HttpRequest, dao: Data<Dao>, version: Path<String>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    let version = version.into_inner().into();
+    update_rollout(&dao, &version, Rollout::resume).await?;
+    info!("Resumed rollout of {}", version);
+    Ok("OK".to_string())
+}
+
+/// Cancels rollout plan. Weights stay as they were after the last applied step.
+pub(crate) async fn cancel_rollout(request: HttpRequest, dao: Data<Dao>, version: Path<String>) -> Result<impl Responder> {
+    validate_permission(&request)?;
+    let version: VersionName = version.into_inner().into();
+    if !dao.delete_rollout(&version).await? {
+        return Err(QpacktError::InvalidRequest(format!("no rollout for version `{}`", version)));
+    }
+    info!("Cancelled rollout of {}", version);
+    Ok("OK".to_string())
+}
+
+async fn update_rollout(dao: &Dao, version: &VersionName, update: impl FnOnce(&mut Rollout)) -> Result<()> {
+    dao.update_rollout(version, |rollout| {
+        update(rollout);
+        true
+    })
+    .await?
+    .ok_or_else(|| QpacktError::InvalidRequest(format!("no rollout for version `{}`", version)))?;
+    Ok(())
+}
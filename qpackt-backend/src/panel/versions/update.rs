@@ -16,12 +16,12 @@
    You should have received a copy of the GNU Affero General Public License
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
-use crate::dao::version::VersionName;
+use crate::dao::version::{Version, VersionName};
 use crate::dao::Dao;
 use crate::error::Result;
 use crate::manager::strategy::Strategy;
 use crate::panel::validate_permission;
-use crate::server::Versions;
+use crate::server::{ServingProfile, Versions};
 use actix_web::web::Data;
 use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use awc::http::StatusCode;
@@ -32,11 +32,16 @@ use serde::Deserialize;
 pub(crate) struct VersionRequest {
     name: VersionName,
     strategy: Strategy,
+    /// Leaves drain flag unchanged when missing.
+    #[serde(default)]
+    drain: Option<bool>,
+    /// Leaves serving profile unchanged when missing.
+    #[serde(default)]
+    profile: Option<ServingProfile>,
 }
 
 /// Updates configuration for traffic split.
-/// * Retrieve versions from database
-/// * Update according to request and save to database
+/// * Retrieve versions from database, update according to request and save them, see [Dao::update_versions]
 /// * Update handlers to be used for actual traffic split
 pub(crate) async fn update_versions(
     request: HttpRequest,
@@ -46,26 +51,33 @@ pub(crate) async fn update_versions(
 ) -> Result<impl Responder> {
     validate_permission(&request)?;
     debug!("Received versions update: {:?}", version_requests);
-    let mut current = match dao.list_versions().await {
+    for profile in version_requests.iter().filter_map(|v| v.profile.as_ref()) {
+        profile.validate()?;
+    }
+    let update = |current: &mut [Version]| {
+        for current_version in current {
+            for new_version in &version_requests {
+                if current_version.name == new_version.name {
+                    current_version.strategy = new_version.strategy.clone();
+                    if let Some(drain) = new_version.drain {
+                        current_version.drain = drain;
+                    }
+                    if let Some(profile) = &new_version.profile {
+                        current_version.profile = profile.clone();
+                    }
+                    break;
+                }
+            }
+        }
+        true
+    };
+    let current = match dao.update_versions(update).await {
         Ok(current) => current,
         Err(e) => {
-            error!("Unable to list site's versions: {}", e);
+            error!("Unable to save new site's versions: {}", e);
             return Err(e);
         }
     };
-
-    for current_version in &mut current {
-        for new_version in &version_requests {
-            if current_version.name == new_version.name {
-                current_version.strategy = new_version.strategy.clone();
-                break;
-            }
-        }
-    }
-    if let Err(e) = dao.save_versions(&current).await {
-        error!("Unable to save new site's versions: {}", e);
-        return Err(e);
-    }
     versions.update_strategies(&current).await;
     info!("Saved new site's versions: {:?}", current);
     Ok(HttpResponse::new(StatusCode::CREATED))
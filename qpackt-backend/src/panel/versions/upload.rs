@@ -17,6 +17,7 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
+use crate::compression::precompress_dir;
 use crate::config::QpacktConfig;
 use crate::constants::VERSIONS_SUBDIRECTORY;
 use crate::dao::version::{Version, VersionName};
@@ -25,7 +26,7 @@ use crate::error::QpacktError;
 use crate::error::Result;
 use crate::manager::strategy::Strategy;
 use crate::panel::validate_permission;
-use crate::server::Versions;
+use crate::server::{ServingProfile, Versions};
 use actix_multipart::{Field, Multipart};
 use actix_web::web::Data;
 use actix_web::{HttpRequest, HttpResponse, Responder};
@@ -82,7 +83,7 @@ async fn save_version(field: Field, config: &QpacktConfig, dao: &Dao) -> Result<
     let name = create_name();
     let target = create_path(config, &name)?;
     let zip_path = wait_for_content(field, &target).await?;
-    unzip_and_register(&zip_path, &target, name, config.app_run_directory(), dao).await
+    unzip_and_register(&zip_path, &target, name, config.app_run_directory(), config.precompress_uploads(), dao).await
 }
 
 fn create_path(config: &QpacktConfig, name: &VersionName) -> Result<PathBuf> {
@@ -108,12 +109,22 @@ async fn wait_for_content(mut field: Field, target: &Path) -> Result<PathBuf> {
     Ok(zip_path)
 }
 
-async fn unzip_and_register(zip_path: &Path, target: &Path, name: VersionName, app_run_dir: &Path, dao: &Dao) -> Result<Version> {
+async fn unzip_and_register(zip_path: &Path, target: &Path, name: VersionName, app_run_dir: &Path, precompress: bool, dao: &Dao) -> Result<Version> {
     let web_root = unzip_site(zip_path, target)?;
+    if precompress {
+        // Brotli at the best quality takes a while, it must not block the worker.
+        let dir = web_root.clone();
+        tokio::task::spawn_blocking(move || precompress_dir(&dir))
+            .await
+            .map_err(std::io::Error::from)
+            .and_then(|result| result)
+            .map_err(|e| QpacktError::UnableToProcessSite(format!("unable to precompress: {}", e)))?;
+    }
+    let profile = ServingProfile::detect(&web_root);
     let web_root = web_root
         .strip_prefix(app_run_dir.join(VERSIONS_SUBDIRECTORY))
         .map_err(|e| QpacktError::UnableToProcessSite(format!("unable to strip site prefix: {}", e)))?;
-    let version = Version { name, web_root: web_root.to_path_buf(), strategy: Strategy::Weight(0) };
+    let version = Version { name, web_root: web_root.to_path_buf(), strategy: Strategy::Weight(0), drain: false, profile };
     dao.register_version(&version).await?;
     Ok(version)
 }
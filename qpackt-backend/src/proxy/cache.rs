@@ -0,0 +1,667 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Cache of reverse proxy GET responses, shared by all workers. Entries are kept in memory, the oldest ones are moved
+//! to disk when memory limit is reached and dropped when disk limit is reached too.
+//! Freshness comes from `Cache-Control` (`max-age`, `s-maxage`, `stale-while-revalidate`) or `Expires`, unless the
+//! proxy overrides it (see [crate::reverse_proxy::ProxyCache]). Responses without freshness information, with
+//! `no-store`, `private`, `Vary: *` or cookies are not cached, neither are requests with credentials (`Authorization`
+//! or cookies other than qpackt's). Expired entries with `ETag` are revalidated.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, HttpDate};
+use actix_web::http::{header, Method, StatusCode, Uri};
+use actix_web::HttpResponse;
+use bytes::Bytes;
+use log::{debug, warn};
+use serde::Serialize;
+use tokio::fs;
+
+/// Tells the client how the response was served: `HIT`, `STALE`, `REVALIDATED` or `MISS`.
+pub(crate) const CACHE_STATUS_HEADER: &str = "x-qpackt-cache";
+
+/// Bigger responses (or ones without `Content-Length`) are not cached.
+pub(crate) const MAX_ENTRY_SIZE: usize = 4 * 1024 * 1024;
+
+/// Headers that belong to a single connection or are set when serving an entry.
+const SKIPPED_HEADERS: [HeaderName; 5] =
+    [header::CONNECTION, header::TRANSFER_ENCODING, header::CONTENT_LENGTH, header::AGE, header::UPGRADE];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct CacheLimits {
+    /// Bytes of responses kept in memory.
+    pub(crate) memory: usize,
+    /// Bytes of responses moved to disk, 0 turns overflowing to disk off.
+    pub(crate) disk: u64,
+}
+
+impl Default for CacheLimits {
+    fn default() -> Self {
+        Self { memory: 64 * 1024 * 1024, disk: 256 * 1024 * 1024 }
+    }
+}
+
+/// Prefix of qpackt's own cookies, they don't make a request private.
+const QPACKT_COOKIE_PREFIX: &str = "QPACKT_";
+
+/// Responses differ between proxies, hosts (rules can match many hosts, see
+/// [crate::reverse_proxy::ProxyHeaders::preserve_host]) and versions of the site, see
+/// [crate::reverse_proxy::Target::version].
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub(crate) struct CacheKey {
+    proxy_id: i32,
+    host: Option<String>,
+    version: Option<String>,
+    path: String,
+    query: Option<String>,
+}
+
+impl CacheKey {
+    pub(crate) fn new(proxy_id: i32, host: Option<&str>, version: Option<&str>, uri: &Uri) -> Self {
+        Self {
+            proxy_id,
+            host: host.map(str::to_ascii_lowercase),
+            version: version.map(str::to_string),
+            path: uri.path().to_string(),
+            query: uri.query().map(str::to_string),
+        }
+    }
+}
+
+/// How long an entry can be served.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Freshness {
+    pub(crate) fresh_for: Duration,
+    /// Time after `fresh_for` when the entry is still served, while it's revalidated in the background.
+    pub(crate) stale_for: Duration,
+}
+
+impl Freshness {
+    /// Reads freshness of a response, `None` if it can't be stored. `ttl` overrides response's own lifetime.
+    pub(crate) fn of(status: StatusCode, headers: &HeaderMap, ttl: Option<Duration>) -> Option<Self> {
+        if status != StatusCode::OK || headers.contains_key(header::SET_COOKIE) {
+            return None;
+        }
+        if headers.get_all(header::VARY).flat_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')).any(|v| v.trim() == "*") {
+            return None;
+        }
+        let directives = cache_control(headers);
+        let directive = |name: &str| directives.iter().find(|(n, _)| n == name);
+        let seconds = |name: &str| directive(name).and_then(|(_, v)| v.as_deref()?.parse::<u64>().ok()).map(Duration::from_secs);
+        if directive("no-store").is_some() || directive("private").is_some() {
+            return None;
+        }
+        let fresh_for = match ttl {
+            Some(ttl) => ttl,
+            None if directive("no-cache").is_some() => Duration::ZERO,
+            None => seconds("s-maxage").or_else(|| seconds("max-age")).or_else(|| expires(headers))?,
+        };
+        let stale_for = seconds("stale-while-revalidate").unwrap_or_default();
+        if fresh_for.is_zero() && stale_for.is_zero() && !headers.contains_key(header::ETAG) {
+            return None;
+        }
+        Some(Self { fresh_for, stale_for })
+    }
+}
+
+/// Lowercase `Cache-Control` directives with their values.
+fn cache_control(headers: &HeaderMap) -> Vec<(String, Option<String>)> {
+    headers
+        .get_all(header::CACHE_CONTROL)
+        .flat_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .map(|directive| match directive.split_once('=') {
+            Some((name, value)) => (name.trim().to_ascii_lowercase(), Some(value.trim().trim_matches('"').to_string())),
+            None => (directive.trim().to_ascii_lowercase(), None),
+        })
+        .collect()
+}
+
+/// Lifetime from `Expires`, relative to `Date` if present.
+fn expires(headers: &HeaderMap) -> Option<Duration> {
+    let date = |name| headers.get(name)?.to_str().ok().and_then(|v| HttpDate::from_str(v).ok()).map(SystemTime::from);
+    let expires = date(header::EXPIRES).unwrap_or(SystemTime::UNIX_EPOCH);
+    let now = date(header::DATE).unwrap_or_else(SystemTime::now);
+    Some(expires.duration_since(now).unwrap_or_default())
+}
+
+/// Whether request's response can be taken from the cache or stored in it. Requests with credentials can get pages
+/// private to the user, so they always go to the target.
+pub(crate) fn is_cacheable_request(method: &Method, headers: &HeaderMap) -> bool {
+    *method == Method::GET
+        && !headers.contains_key(header::AUTHORIZATION)
+        && !has_private_cookies(headers)
+        && !cache_control(headers).iter().any(|(name, _)| name == "no-store")
+}
+
+/// Whether the request has cookies other than qpackt's.
+fn has_private_cookies(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::COOKIE)
+        .flat_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(';'))
+        .map(|cookie| cookie.split_once('=').map_or(cookie, |(name, _)| name).trim())
+        .any(|name| !name.is_empty() && !name.starts_with(QPACKT_COOKIE_PREFIX))
+}
+
+/// Client asks for a response from the target with `no-cache`, it can still be stored.
+pub(crate) fn bypasses_cache(headers: &HeaderMap) -> bool {
+    cache_control(headers).iter().any(|(name, _)| name == "no-cache")
+        || headers.get(header::PRAGMA).is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"no-cache"))
+}
+
+#[derive(Debug)]
+enum Body {
+    Memory(Bytes),
+    Disk(PathBuf),
+}
+
+#[derive(Debug)]
+pub(crate) struct CachedResponse {
+    /// Unique, growing with insertion time.
+    id: u64,
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+    body: Body,
+    size: usize,
+    stored: Instant,
+    freshness: Freshness,
+    /// Headers named in response's `Vary` with their values in the request.
+    vary: Vec<(HeaderName, Option<HeaderValue>)>,
+    revalidating: AtomicBool,
+}
+
+impl CachedResponse {
+    pub(crate) fn etag(&self) -> Option<&HeaderValue> {
+        self.headers.iter().find(|(name, _)| name == header::ETAG).map(|(_, value)| value)
+    }
+
+    /// Marks the entry as being revalidated. Returns false if it already is.
+    pub(crate) fn start_revalidation(&self) -> bool {
+        !self.revalidating.swap(true, Ordering::Relaxed)
+    }
+
+    fn age(&self) -> Duration {
+        self.stored.elapsed()
+    }
+
+    fn matches(&self, request: &HeaderMap) -> bool {
+        self.vary.iter().all(|(name, value)| request.get(name) == value.as_ref())
+    }
+
+    /// Same response, stored again with new freshness.
+    fn refreshed(&self, id: u64, freshness: Freshness) -> Self {
+        let body = match &self.body {
+            Body::Memory(bytes) => Body::Memory(bytes.clone()),
+            Body::Disk(path) => Body::Disk(path.clone()),
+        };
+        Self {
+            id,
+            status: self.status,
+            headers: self.headers.clone(),
+            body,
+            size: self.size,
+            stored: Instant::now(),
+            freshness,
+            vary: self.vary.clone(),
+            revalidating: AtomicBool::new(false),
+        }
+    }
+
+    fn moved_to(&self, path: PathBuf) -> Self {
+        Self {
+            id: self.id,
+            status: self.status,
+            headers: self.headers.clone(),
+            body: Body::Disk(path),
+            size: self.size,
+            stored: self.stored,
+            freshness: self.freshness,
+            vary: self.vary.clone(),
+            revalidating: AtomicBool::new(self.revalidating.load(Ordering::Relaxed)),
+        }
+    }
+}
+
+/// Result of a cache lookup.
+pub(crate) enum Cached {
+    Fresh(Arc<CachedResponse>),
+    /// Can be served while it's revalidated.
+    Stale(Arc<CachedResponse>),
+    /// Can be served only if target confirms it's still valid (see [CachedResponse::etag]).
+    Expired(Arc<CachedResponse>),
+}
+
+#[derive(Debug, Default, Serialize)]
+pub(crate) struct CacheStats {
+    pub(crate) hits: u64,
+    pub(crate) misses: u64,
+    /// Hits served stale while revalidating.
+    pub(crate) stale: u64,
+    /// Expired entries confirmed by targets.
+    pub(crate) revalidated: u64,
+    pub(crate) entries: usize,
+    pub(crate) memory_bytes: usize,
+    pub(crate) disk_bytes: u64,
+}
+
+#[derive(Debug, Default)]
+struct CacheState {
+    entries: HashMap<CacheKey, Vec<Arc<CachedResponse>>>,
+    memory: usize,
+    disk: u64,
+}
+
+impl CacheState {
+    fn remove(&mut self, key: &CacheKey, id: u64) -> Option<Arc<CachedResponse>> {
+        let variants = self.entries.get_mut(key)?;
+        let index = variants.iter().position(|e| e.id == id)?;
+        let entry = variants.remove(index);
+        if variants.is_empty() {
+            self.entries.remove(key);
+        }
+        match entry.body {
+            Body::Memory(_) => self.memory -= entry.size,
+            Body::Disk(_) => self.disk -= entry.size as u64,
+        }
+        Some(entry)
+    }
+
+    /// The oldest entry kept in memory (or on disk).
+    fn oldest(&self, on_disk: bool) -> Option<(CacheKey, Arc<CachedResponse>)> {
+        self.entries
+            .iter()
+            .flat_map(|(key, variants)| variants.iter().map(move |entry| (key, entry)))
+            .filter(|(_, entry)| matches!(entry.body, Body::Disk(_)) == on_disk)
+            .min_by_key(|(_, entry)| entry.id)
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct ResponseCache {
+    limits: CacheLimits,
+    directory: PathBuf,
+    state: Mutex<CacheState>,
+    next_id: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+    stale: AtomicU64,
+    revalidated: AtomicU64,
+}
+
+impl ResponseCache {
+    /// Entries moved to disk are kept in `directory`, files left from previous runs are removed.
+    pub(crate) fn new(limits: CacheLimits, directory: PathBuf) -> Self {
+        if directory.exists() {
+            if let Err(e) = std::fs::remove_dir_all(&directory) {
+                warn!("Unable to clear cache directory {:?}: {}", directory, e);
+            }
+        }
+        Self {
+            limits,
+            directory,
+            state: Mutex::default(),
+            next_id: AtomicU64::default(),
+            hits: AtomicU64::default(),
+            misses: AtomicU64::default(),
+            stale: AtomicU64::default(),
+            revalidated: AtomicU64::default(),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &CacheKey, request: &HeaderMap) -> Option<Cached> {
+        let entry = self.state.lock().unwrap().entries.get(key).and_then(|variants| variants.iter().find(|e| e.matches(request)).cloned());
+        let cached = entry.map(|entry| {
+            let age = entry.age();
+            if age < entry.freshness.fresh_for {
+                Cached::Fresh(entry)
+            } else if age < entry.freshness.fresh_for + entry.freshness.stale_for {
+                Cached::Stale(entry)
+            } else {
+                Cached::Expired(entry)
+            }
+        });
+        match &cached {
+            Some(Cached::Fresh(_)) => self.hits.fetch_add(1, Ordering::Relaxed),
+            Some(Cached::Stale(_)) => {
+                self.stale.fetch_add(1, Ordering::Relaxed);
+                self.hits.fetch_add(1, Ordering::Relaxed)
+            }
+            _ => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        cached
+    }
+
+    /// Stores a response, replacing the one with the same `Vary` headers.
+    pub(crate) async fn insert(
+        &self,
+        key: CacheKey,
+        status: StatusCode,
+        headers: &HeaderMap,
+        body: Bytes,
+        freshness: Freshness,
+        request: &HeaderMap,
+    ) {
+        let vary = headers
+            .get_all(header::VARY)
+            .flat_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(','))
+            .flat_map(|name| HeaderName::from_str(name.trim()).ok())
+            .map(|name| {
+                let value = request.get(&name).cloned();
+                (name, value)
+            })
+            .collect::<Vec<_>>();
+        let entry = CachedResponse {
+            id: self.next_id.fetch_add(1, Ordering::Relaxed),
+            status,
+            headers: headers.iter().filter(|(name, _)| !SKIPPED_HEADERS.contains(name)).map(|(n, v)| (n.clone(), v.clone())).collect(),
+            size: body.len(),
+            body: Body::Memory(body),
+            stored: Instant::now(),
+            freshness,
+            vary,
+            revalidating: AtomicBool::new(false),
+        };
+        debug!("Caching {:?} for {:?}", key, freshness);
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            let same = state.entries.get(&key).and_then(|variants| variants.iter().find(|e| e.vary == entry.vary).map(|e| e.id));
+            let removed = same.and_then(|id| state.remove(&key, id));
+            state.memory += entry.size;
+            state.entries.entry(key).or_default().push(Arc::new(entry));
+            removed
+        };
+        self.remove_files(removed.into_iter().collect()).await;
+        self.overflow().await;
+    }
+
+    /// Stores an entry again after the target confirmed it's still valid, with new freshness if the target sent it.
+    pub(crate) fn refresh(&self, key: &CacheKey, entry: &CachedResponse, freshness: Option<Freshness>) -> Arc<CachedResponse> {
+        self.revalidated.fetch_add(1, Ordering::Relaxed);
+        let refreshed = Arc::new(entry.refreshed(self.next_id.fetch_add(1, Ordering::Relaxed), freshness.unwrap_or(entry.freshness)));
+        let mut state = self.state.lock().unwrap();
+        if let Some(current) = state.entries.get_mut(key).and_then(|variants| variants.iter_mut().find(|e| e.id == entry.id)) {
+            *current = refreshed.clone();
+        }
+        refreshed
+    }
+
+    /// Lets the entry be revalidated again.
+    pub(crate) fn revalidation_failed(&self, entry: &CachedResponse) {
+        entry.revalidating.store(false, Ordering::Relaxed);
+    }
+
+    /// Removes entries whose path starts with `prefix`. Returns how many were removed.
+    pub(crate) async fn purge(&self, prefix: &str) -> usize {
+        let removed = {
+            let mut state = self.state.lock().unwrap();
+            let keys = state.entries.keys().filter(|key| key.path.starts_with(prefix)).cloned().collect::<Vec<_>>();
+            let mut removed = vec![];
+            for key in keys {
+                let ids = state.entries.get(&key).map(|variants| variants.iter().map(|e| e.id).collect::<Vec<_>>()).unwrap_or_default();
+                removed.extend(ids.into_iter().flat_map(|id| state.remove(&key, id)));
+            }
+            removed
+        };
+        let count = removed.len();
+        self.remove_files(removed).await;
+        count
+    }
+
+    pub(crate) fn stats(&self) -> CacheStats {
+        let state = self.state.lock().unwrap();
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            stale: self.stale.load(Ordering::Relaxed),
+            revalidated: self.revalidated.load(Ordering::Relaxed),
+            entries: state.entries.values().map(Vec::len).sum(),
+            memory_bytes: state.memory,
+            disk_bytes: state.disk,
+        }
+    }
+
+    /// Builds response from the entry, `None` if its body can't be read. Clients that already have the entry
+    /// (`If-None-Match` with its `ETag`) get 304.
+    pub(crate) async fn respond(&self, entry: &CachedResponse, request: &HeaderMap, cache_status: &'static str) -> Option<HttpResponse> {
+        if let (Some(etag), Some(if_none_match)) = (entry.etag(), request.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok())) {
+            if if_none_match.split(',').any(|tag| tag.trim() == "*" || tag.trim().as_bytes() == etag.as_bytes()) {
+                return Some(
+                    HttpResponse::NotModified()
+                        .insert_header((header::ETAG, etag.clone()))
+                        .insert_header((CACHE_STATUS_HEADER, cache_status))
+                        .finish(),
+                );
+            }
+        }
+        let body = match &entry.body {
+            Body::Memory(bytes) => bytes.clone(),
+            Body::Disk(path) => match fs::read(path).await {
+                Ok(body) => Bytes::from(body),
+                Err(e) => {
+                    warn!("Unable to read cached response {:?}: {}", path, e);
+                    return None;
+                }
+            },
+        };
+        let mut response = HttpResponse::build(entry.status);
+        for (name, value) in &entry.headers {
+            response.append_header((name.clone(), value.clone()));
+        }
+        response.insert_header((header::AGE, entry.age().as_secs())).insert_header((CACHE_STATUS_HEADER, cache_status));
+        Some(response.body(body))
+    }
+
+    /// Moves the oldest entries to disk while memory limit is exceeded, drops the oldest ones from disk while disk
+    /// limit is exceeded.
+    async fn overflow(&self) {
+        loop {
+            let oldest = {
+                let state = self.state.lock().unwrap();
+                if state.memory <= self.limits.memory {
+                    break;
+                }
+                state.oldest(false)
+            };
+            let Some((key, entry)) = oldest else {
+                break;
+            };
+            let moved = match &entry.body {
+                Body::Memory(bytes) if self.limits.disk > 0 => self.write_file(entry.id, bytes).await.map(|path| entry.moved_to(path)),
+                _ => None,
+            };
+            let moved = moved.map(Arc::new);
+            let stale = {
+                let mut state = self.state.lock().unwrap();
+                match (state.remove(&key, entry.id), moved) {
+                    (Some(_), Some(moved)) => {
+                        state.disk += moved.size as u64;
+                        state.entries.entry(key).or_default().push(moved);
+                        None
+                    }
+                    // Replaced or purged while the file was written.
+                    (None, moved) => moved,
+                    (Some(_), None) => None,
+                }
+            };
+            self.remove_files(stale.into_iter().collect()).await;
+        }
+        let mut removed = vec![];
+        {
+            let mut state = self.state.lock().unwrap();
+            while state.disk > self.limits.disk {
+                let Some((key, entry)) = state.oldest(true) else {
+                    break;
+                };
+                removed.extend(state.remove(&key, entry.id));
+            }
+        }
+        self.remove_files(removed).await;
+    }
+
+    async fn write_file(&self, id: u64, body: &Bytes) -> Option<PathBuf> {
+        let path = self.directory.join(id.to_string());
+        let written = async {
+            fs::create_dir_all(&self.directory).await?;
+            fs::write(&path, body).await
+        };
+        match written.await {
+            Ok(()) => Some(path),
+            Err(e) => {
+                warn!("Unable to move cached response to {:?}: {}", path, e);
+                None
+            }
+        }
+    }
+
+    async fn remove_files(&self, entries: Vec<Arc<CachedResponse>>) {
+        for entry in entries {
+            if let Body::Disk(path) = &entry.body {
+                if let Err(e) = fs::remove_file(path).await {
+                    warn!("Unable to remove cached response {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use actix_web::body::MessageBody;
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use actix_web::http::{header, Method, StatusCode, Uri};
+    use bytes::Bytes;
+    use tmpdir::TmpDir;
+
+    use crate::proxy::cache::{is_cacheable_request, CacheKey, CacheLimits, Cached, Freshness, ResponseCache};
+
+    fn headers(values: &[(HeaderName, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in values {
+            headers.append(name.clone(), HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    fn key(path: &'static str) -> CacheKey {
+        CacheKey::new(1, None, None, &Uri::from_static(path))
+    }
+
+    #[test]
+    fn skips_requests_with_credentials() {
+        let get = |values: &[(HeaderName, &'static str)]| is_cacheable_request(&Method::GET, &headers(values));
+        assert!(get(&[]));
+        assert!(get(&[(header::COOKIE, "QPACKT_VERSION=blue; QPACKT_EXPERIMENTS=1:red")]));
+        assert!(!get(&[(header::COOKIE, "QPACKT_VERSION=blue; session=secret")]));
+        assert!(!get(&[(header::AUTHORIZATION, "Bearer secret")]));
+        assert!(!get(&[(header::CACHE_CONTROL, "no-store")]));
+        assert!(!is_cacheable_request(&Method::POST, &HeaderMap::new()));
+    }
+
+    #[actix_web::test]
+    async fn separates_entries_by_host() {
+        let dir = TmpDir::new("cache").await.unwrap();
+        let cache = ResponseCache::new(CacheLimits::default(), dir.to_path_buf().join("cache"));
+        let fresh = Freshness { fresh_for: Duration::from_secs(60), stale_for: Duration::ZERO };
+        let uri = Uri::from_static("/api/a");
+        let (a, b) = (CacheKey::new(1, Some("a.qpackt.com"), None, &uri), CacheKey::new(1, Some("b.qpackt.com"), None, &uri));
+        cache.insert(a.clone(), StatusCode::OK, &HeaderMap::new(), Bytes::from_static(b"a"), fresh, &HeaderMap::new()).await;
+        assert!(matches!(cache.get(&a, &HeaderMap::new()), Some(Cached::Fresh(_))));
+        assert!(cache.get(&CacheKey::new(1, Some("A.qpackt.com"), None, &uri), &HeaderMap::new()).is_some());
+        assert!(cache.get(&b, &HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn reads_freshness_from_headers() {
+        let fresh = |values: &[(HeaderName, &'static str)]| Freshness::of(StatusCode::OK, &headers(values), None);
+        let max_age = fresh(&[(header::CACHE_CONTROL, "public, max-age=60, stale-while-revalidate=30")]).unwrap();
+        assert_eq!(max_age, Freshness { fresh_for: Duration::from_secs(60), stale_for: Duration::from_secs(30) });
+        assert_eq!(fresh(&[(header::CACHE_CONTROL, "max-age=60, s-maxage=120")]).unwrap().fresh_for, Duration::from_secs(120));
+        let expires = fresh(&[(header::DATE, "Sun, 06 Nov 1994 08:49:37 GMT"), (header::EXPIRES, "Sun, 06 Nov 1994 08:59:37 GMT")]);
+        assert_eq!(expires.unwrap().fresh_for, Duration::from_secs(600));
+        assert_eq!(fresh(&[(header::CACHE_CONTROL, "no-cache"), (header::ETAG, "\"1\"")]).unwrap().fresh_for, Duration::ZERO);
+        assert!(fresh(&[(header::CACHE_CONTROL, "no-cache")]).is_none());
+        assert!(fresh(&[(header::CACHE_CONTROL, "private, max-age=60")]).is_none());
+        assert!(fresh(&[(header::CACHE_CONTROL, "max-age=60"), (header::VARY, "*")]).is_none());
+        assert!(fresh(&[(header::CACHE_CONTROL, "max-age=60"), (header::SET_COOKIE, "a=1")]).is_none());
+        assert!(fresh(&[]).is_none());
+        assert_eq!(
+            Freshness::of(StatusCode::OK, &HeaderMap::new(), Some(Duration::from_secs(5))).unwrap().fresh_for,
+            Duration::from_secs(5)
+        );
+        assert!(Freshness::of(StatusCode::NOT_FOUND, &headers(&[(header::CACHE_CONTROL, "max-age=60")]), None).is_none());
+    }
+
+    #[actix_web::test]
+    async fn serves_entries_by_vary_and_age() {
+        let dir = TmpDir::new("cache").await.unwrap();
+        let cache = ResponseCache::new(CacheLimits::default(), dir.to_path_buf().join("cache"));
+        let response = headers(&[(header::VARY, "accept-language"), (header::ETAG, "\"1\"")]);
+        let english = headers(&[(header::ACCEPT_LANGUAGE, "en")]);
+        let fresh = Freshness { fresh_for: Duration::from_secs(60), stale_for: Duration::ZERO };
+        cache.insert(key("/api/a"), StatusCode::OK, &response, Bytes::from_static(b"a"), fresh, &english).await;
+        let expired = Freshness { fresh_for: Duration::ZERO, stale_for: Duration::ZERO };
+        cache.insert(key("/api/b"), StatusCode::OK, &response, Bytes::from_static(b"b"), expired, &english).await;
+        assert!(matches!(cache.get(&key("/api/a"), &english), Some(Cached::Fresh(_))));
+        assert!(cache.get(&key("/api/a"), &headers(&[(header::ACCEPT_LANGUAGE, "pl")])).is_none());
+        let Some(Cached::Expired(entry)) = cache.get(&key("/api/b"), &english) else {
+            panic!("Expected expired entry");
+        };
+        assert_eq!(entry.etag().unwrap(), "\"1\"");
+        cache.refresh(&key("/api/b"), &entry, Some(fresh));
+        let if_none_match = headers(&[(header::IF_NONE_MATCH, "\"0\", \"1\"")]);
+        assert_eq!(cache.respond(&entry, &if_none_match, "HIT").await.unwrap().status(), StatusCode::NOT_MODIFIED);
+        assert!(matches!(cache.get(&key("/api/b"), &english), Some(Cached::Fresh(_))));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses, stats.revalidated, stats.entries), (2, 2, 1, 2));
+        assert_eq!(cache.purge("/api/a").await, 1);
+        assert!(cache.get(&key("/api/a"), &english).is_none());
+    }
+
+    #[actix_web::test]
+    async fn moves_oldest_entries_to_disk() {
+        let dir = TmpDir::new("cache").await.unwrap();
+        let cache = ResponseCache::new(CacheLimits { memory: 10, disk: 10 }, dir.to_path_buf().join("cache"));
+        let fresh = Freshness { fresh_for: Duration::from_secs(60), stale_for: Duration::ZERO };
+        for path in ["/a", "/b", "/c"] {
+            cache.insert(key(path), StatusCode::OK, &HeaderMap::new(), Bytes::from_static(b"123456"), fresh, &HeaderMap::new()).await;
+        }
+        let stats = cache.stats();
+        assert_eq!((stats.entries, stats.memory_bytes, stats.disk_bytes), (2, 6, 6));
+        assert!(cache.get(&key("/a"), &HeaderMap::new()).is_none());
+        let Some(Cached::Fresh(entry)) = cache.get(&key("/b"), &HeaderMap::new()) else {
+            panic!("Expected entry on disk");
+        };
+        let response = cache.respond(&entry, &HeaderMap::new(), "HIT").await.unwrap();
+        assert_eq!(response.headers().get("x-qpackt-cache").unwrap(), "HIT");
+        assert_eq!(response.into_body().try_into_bytes().unwrap(), Bytes::from_static(b"123456"));
+        assert_eq!(cache.purge("/").await, 2);
+        assert_eq!(std::fs::read_dir(dir.to_path_buf().join("cache")).unwrap().count(), 0);
+    }
+}
@@ -0,0 +1,98 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Attributes of cookies set by qpackt to remember visitors' versions.
+
+use std::ops::Add;
+
+use actix_web::cookie::{Cookie, SameSite};
+use awc::cookie::time::{Duration, OffsetDateTime};
+
+use crate::error::QpacktError;
+
+/// How long visitors keep their versions when nothing else is configured.
+const DEFAULT_LIFETIME_DAYS: i64 = 7;
+
+/// Attributes of version cookies, configured in qpackt.yaml.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CookiePolicy {
+    pub(crate) lifetime_days: i64,
+    pub(crate) secure: bool,
+    pub(crate) http_only: bool,
+    pub(crate) same_site: Option<SameSite>,
+    pub(crate) path: String,
+    pub(crate) domain: Option<String>,
+}
+
+impl Default for CookiePolicy {
+    fn default() -> Self {
+        Self { lifetime_days: DEFAULT_LIFETIME_DAYS, secure: false, http_only: false, same_site: None, path: "/".into(), domain: None }
+    }
+}
+
+impl CookiePolicy {
+    /// Builds a cookie with all configured attributes.
+    pub(crate) fn build(&self, name: &'static str, value: String) -> Cookie<'static> {
+        let mut cookie = Cookie::new(name, value);
+        cookie.set_expires(OffsetDateTime::now_utc().add(Duration::days(self.lifetime_days)));
+        cookie.set_secure(self.secure);
+        cookie.set_http_only(self.http_only);
+        cookie.set_same_site(self.same_site);
+        cookie.set_path(self.path.clone());
+        if let Some(domain) = &self.domain {
+            cookie.set_domain(domain.clone());
+        }
+        cookie
+    }
+}
+
+/// Parses `SameSite` values: `strict`, `lax` or `none`.
+pub(crate) fn parse_same_site(value: &str) -> Result<SameSite, QpacktError> {
+    match value.to_ascii_lowercase().as_str() {
+        "strict" => Ok(SameSite::Strict),
+        "lax" => Ok(SameSite::Lax),
+        "none" => Ok(SameSite::None),
+        _ => Err(QpacktError::InvalidConfig(format!("Invalid cookie SameSite `{}`", value))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::cookie::SameSite;
+
+    use crate::proxy::cookie::{parse_same_site, CookiePolicy};
+
+    #[test]
+    fn builds_cookie_with_policy_attributes() {
+        let policy = CookiePolicy {
+            secure: true,
+            http_only: true,
+            same_site: Some(parse_same_site("Lax").unwrap()),
+            domain: Some("example.com".into()),
+            ..Default::default()
+        };
+        let cookie = policy.build("QPACKT_VERSION", "v1".into());
+        assert_eq!(cookie.secure(), Some(true));
+        assert_eq!(cookie.http_only(), Some(true));
+        assert_eq!(cookie.same_site(), Some(SameSite::Lax));
+        assert_eq!(cookie.path(), Some("/"));
+        assert_eq!(cookie.domain(), Some("example.com"));
+        assert!(parse_same_site("sometimes").is_err());
+    }
+}
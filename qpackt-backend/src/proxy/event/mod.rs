@@ -17,7 +17,6 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::time::{SystemTime, UNIX_EPOCH};
 
 use actix_web::{HttpRequest, HttpResponse, web};
@@ -32,9 +31,11 @@ use web::Json;
 use crate::analytics;
 use crate::analytics::event_writer::EventWriter;
 use crate::analytics::hash::VisitorHash;
+use crate::config::QpacktConfig;
 use crate::dao::events::EventData;
+use crate::proxy::handler::experiment_assignments;
 
-pub(super) const QPACKT_EVENT_URI: &str = "/qpackt/event";
+pub(crate) const QPACKT_EVENT_URI: &str = "/qpackt/event";
 
 #[derive(Debug, Deserialize)]
 pub(crate) struct CreateEventRequest {
@@ -49,12 +50,17 @@ pub(crate) struct CreateEventRequest {
 
 
 /// Saves event sent from the browser.
-pub(super) async fn collect_event(http: HttpRequest, Json(event): Json<CreateEventRequest>, event_writer: Data<EventWriter>) -> HttpResponse {
+pub(super) async fn collect_event(
+    http: HttpRequest,
+    Json(event): Json<CreateEventRequest>,
+    event_writer: Data<EventWriter>,
+    config: Data<QpacktConfig>,
+) -> HttpResponse {
     debug!("Received event {:?}", event);
     let event_writer = event_writer.into_inner();
     info!("Saving event {}", event.name);
     let hash = if event.visitor.is_empty() {
-        let peer = http.peer_addr().unwrap_or_else(|| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1))).ip();
+        let peer = config.trusted_proxies().client_ip(&http);
         analytics::hash::create(peer, event.user_agent.into_bytes())
     } else {
         event.visitor
@@ -69,6 +75,7 @@ pub(super) async fn collect_event(http: HttpRequest, Json(event): Json<CreateEve
         params: event.params,
         path: event.path,
         payload,
+        experiments: experiment_assignments(&http),
     };
     event_writer.save(event).await;
     HttpResponse::new(StatusCode::OK)
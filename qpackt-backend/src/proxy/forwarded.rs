@@ -0,0 +1,217 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Real client addresses behind load balancers and CDNs. `Forwarded` and `X-Forwarded-*` headers are believed
+//! only when they come from configured trusted proxies, otherwise anyone could pick their own address.
+
+use std::net::{IpAddr, Ipv4Addr};
+use std::str::FromStr;
+
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue, FORWARDED, HOST};
+use actix_web::HttpRequest;
+use ipnet::IpNet;
+
+use crate::error::{QpacktError, Result};
+use crate::proxy::proxy_protocol::TlsConnection;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_FORWARDED_HOST: HeaderName = HeaderName::from_static("x-forwarded-host");
+
+/// Networks of proxies allowed to tell the client's address, configured in qpackt.yaml.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct TrustedProxies(Vec<IpNet>);
+
+impl TrustedProxies {
+    /// Parses CIDRs (e.g. `10.0.0.0/8`). Single addresses are accepted as well.
+    pub(crate) fn parse(values: &[String]) -> Result<Self> {
+        let networks = values
+            .iter()
+            .map(|value| {
+                IpNet::from_str(value)
+                    .or_else(|_| IpAddr::from_str(value).map(IpNet::from))
+                    .map_err(|_| QpacktError::InvalidConfig(format!("Invalid trusted proxy `{}`", value)))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self(networks))
+    }
+
+    pub(crate) fn networks(&self) -> &[IpNet] {
+        &self.0
+    }
+
+    fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Address of the client. Forwarding headers are followed from the nearest hop as long as hops are trusted,
+    /// the first untrusted address is the client. Without trusted proxies this is the peer address.
+    pub(crate) fn client_ip(&self, request: &HttpRequest) -> IpAddr {
+        let mut ip = peer_ip(request);
+        if !self.contains(ip) {
+            return ip;
+        }
+        for hop in forwarded_for(request.headers()).into_iter().rev() {
+            match hop {
+                Some(hop) => {
+                    ip = hop;
+                    if !self.contains(ip) {
+                        break;
+                    }
+                }
+                // Obfuscated or unknown address, the last known hop is as close to the client as it gets.
+                None => break,
+            }
+        }
+        ip
+    }
+
+    /// Sets `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host` for a request sent upstream.
+    /// Values received from trusted proxies are extended, values received from anyone else are replaced.
+    pub(crate) fn set_forwarded_headers(&self, request: &HttpRequest, headers: &mut HeaderMap) {
+        let peer = peer_ip(request);
+        let trusted = self.contains(peer);
+        let received = |name: &HeaderName| request.headers().get(name).filter(|_| trusted).cloned();
+
+        let received_for = request.headers().get_all(X_FORWARDED_FOR).filter_map(|v| v.to_str().ok()).collect::<Vec<_>>();
+        let forwarded_for =
+            if trusted && !received_for.is_empty() { format!("{}, {}", received_for.join(", "), peer) } else { peer.to_string() };
+        let secure = request.app_config().secure() || request.conn_data::<TlsConnection>().is_some();
+        let scheme = if secure { "https" } else { "http" };
+        let host =
+            request.headers().get(HOST).cloned().or_else(|| request.uri().authority().and_then(|a| HeaderValue::from_str(a.as_str()).ok()));
+
+        headers.remove(X_FORWARDED_FOR);
+        if let Ok(value) = HeaderValue::from_str(&forwarded_for) {
+            headers.insert(X_FORWARDED_FOR, value);
+        }
+        headers.insert(X_FORWARDED_PROTO, received(&X_FORWARDED_PROTO).unwrap_or(HeaderValue::from_static(scheme)));
+        headers.remove(X_FORWARDED_HOST);
+        if let Some(host) = received(&X_FORWARDED_HOST).or(host) {
+            headers.insert(X_FORWARDED_HOST, host);
+        }
+        if !trusted {
+            headers.remove(FORWARDED);
+        }
+    }
+}
+
+fn peer_ip(request: &HttpRequest) -> IpAddr {
+    request.peer_addr().map(|a| a.ip()).unwrap_or(IpAddr::V4(Ipv4Addr::LOCALHOST))
+}
+
+/// Client chain from `Forwarded` (preferred) or `X-Forwarded-For`, the nearest hop last.
+/// Entries that are not addresses (`unknown`, obfuscated identifiers) are `None`.
+fn forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded = headers.get_all(FORWARDED).filter_map(|v| v.to_str().ok()).collect::<Vec<_>>();
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|v| v.split(','))
+            .filter_map(|element| element.split(';').find_map(|pair| pair.trim().strip_prefix("for=").or(pair.trim().strip_prefix("For="))))
+            .map(parse_node)
+            .collect();
+    }
+    headers.get_all(X_FORWARDED_FOR).filter_map(|v| v.to_str().ok()).flat_map(|v| v.split(',')).map(parse_node).collect()
+}
+
+/// Parses `1.2.3.4`, `1.2.3.4:80`, `"[2001:db8::1]:80"` or `2001:db8::1`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split(']').next()?.parse().ok();
+    }
+    node.parse().ok().or_else(|| node.rsplit_once(':')?.0.parse::<Ipv4Addr>().ok().map(IpAddr::V4))
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::{IpAddr, SocketAddr};
+
+    use actix_web::http::header::{HeaderMap, HeaderValue, FORWARDED};
+    use actix_web::test::TestRequest;
+
+    use crate::proxy::forwarded::{parse_node, TrustedProxies, X_FORWARDED_FOR, X_FORWARDED_HOST, X_FORWARDED_PROTO};
+
+    fn trusted() -> TrustedProxies {
+        TrustedProxies::parse(&["10.0.0.0/8".into(), "192.168.1.1".into()]).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    fn peer(ip: &str) -> SocketAddr {
+        SocketAddr::new(ip.parse().unwrap(), 1234)
+    }
+
+    #[test]
+    fn parses_nodes() {
+        assert_eq!(parse_node(" 1.2.3.4"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_node("1.2.3.4:80"), Some(ip("1.2.3.4")));
+        assert_eq!(parse_node("\"[2001:db8::1]:4711\""), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("2001:db8::1"), Some(ip("2001:db8::1")));
+        assert_eq!(parse_node("unknown"), None);
+        assert!(TrustedProxies::parse(&["10.0.0.0/33".into()]).is_err());
+    }
+
+    #[test]
+    fn trusts_forwarding_headers_only_from_trusted_proxies() {
+        let request = TestRequest::default().peer_addr(peer("1.1.1.1")).insert_header((X_FORWARDED_FOR, "2.2.2.2")).to_http_request();
+        assert_eq!(trusted().client_ip(&request), ip("1.1.1.1"));
+
+        let request = TestRequest::default()
+            .peer_addr(peer("10.0.0.1"))
+            .insert_header((X_FORWARDED_FOR, "6.6.6.6, 2.2.2.2, 192.168.1.1"))
+            .to_http_request();
+        assert_eq!(trusted().client_ip(&request), ip("2.2.2.2"));
+        assert_eq!(TrustedProxies::default().client_ip(&request), ip("10.0.0.1"));
+
+        let request = TestRequest::default()
+            .peer_addr(peer("10.0.0.1"))
+            .insert_header((FORWARDED, "for=\"[2001:db8::1]:4711\";proto=https, for=10.1.1.1"))
+            .insert_header((X_FORWARDED_FOR, "3.3.3.3"))
+            .to_http_request();
+        assert_eq!(trusted().client_ip(&request), ip("2001:db8::1"));
+
+        let request = TestRequest::default().peer_addr(peer("10.0.0.1")).insert_header((X_FORWARDED_FOR, "unknown")).to_http_request();
+        assert_eq!(trusted().client_ip(&request), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn sets_forwarded_headers() {
+        let request = TestRequest::default()
+            .peer_addr(peer("10.0.0.1"))
+            .insert_header(("host", "qpackt.com"))
+            .insert_header((X_FORWARDED_FOR, "2.2.2.2"))
+            .insert_header((X_FORWARDED_PROTO, "https"))
+            .to_http_request();
+        let mut headers = HeaderMap::new();
+        trusted().set_forwarded_headers(&request, &mut headers);
+        assert_eq!(headers.get(X_FORWARDED_FOR).unwrap(), "2.2.2.2, 10.0.0.1");
+        assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(), "https");
+        assert_eq!(headers.get(X_FORWARDED_HOST).unwrap(), "qpackt.com");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(X_FORWARDED_FOR, HeaderValue::from_static("2.2.2.2"));
+        TrustedProxies::default().set_forwarded_headers(&request, &mut headers);
+        assert_eq!(headers.get(X_FORWARDED_FOR).unwrap(), "10.0.0.1");
+        assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(), "http");
+    }
+}
@@ -17,20 +17,18 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
-use std::ops::{Add, Deref};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 
-use actix_web::{HttpRequest, HttpResponse};
-use actix_web::cookie::Cookie;
+use actix_web::{HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
 use actix_web::dev::RequestHead;
-use actix_web::http::Uri;
+use actix_web::http::header::{HeaderMap, HeaderValue};
+use actix_web::http::{header, Uri};
 use actix_web::web::{Data, Payload};
-use awc::{Client, ClientRequest};
-use awc::cookie::time::{Duration, OffsetDateTime};
 use awc::http::StatusCode;
-use log::debug;
+use awc::ClientResponse;
+use log::{debug, warn};
 use url::Url;
 
 use crate::analytics;
@@ -38,127 +36,522 @@ use crate::analytics::hash::VisitorHash;
 use crate::analytics::http_request_log_writer::HttpRequestLogWriter;
 use crate::dao::requests::CreateHttpRequestLog;
 use crate::dao::version::VersionName;
-use crate::reverse_proxy::{ReverseProxies, ReverseProxy};
-use crate::server::Versions;
+use crate::experiment::{Experiment, ExperimentAssignments, Experiments};
+use crate::config::QpacktConfig;
+use crate::proxy::cache::{bypasses_cache, is_cacheable_request, CacheKey, Cached, CachedResponse, Freshness, ResponseCache, CACHE_STATUS_HEADER, MAX_ENTRY_SIZE};
+use crate::proxy::cookie::CookiePolicy;
+use crate::proxy::forwarded::TrustedProxies;
+use crate::proxy::tunnel::is_websocket_upgrade;
+use crate::proxy::upstream::UpstreamClient;
+use crate::reverse_proxy::{HeaderDirection, ReverseProxies, ReverseProxy, Target};
+use crate::server::{serve_file, Versions, WebRoot};
 
 /// A cookie that is used to recognize which version was served to the client in previous requests.
+/// Its attributes come from [crate::proxy::cookie::CookiePolicy]. Cookies of drained versions are ignored.
 /// If no cookie is set then assume it's the first request and use [Strategy] to decide which version will be served
 /// from now on.
 const QPACKT_COOKIE_NAME: &str = "QPACKT_VERSION";
 
+/// Header with visitor's version, sent to reverse proxy targets instead of qpackt's cookies.
+const QPACKT_VERSION_HEADER: &str = "x-qpackt-version";
+
+/// A cookie that holds versions assigned to the client in all running [crate::experiment::Experiment]s (see [ExperimentAssignments]).
+const QPACKT_EXPERIMENTS_COOKIE_NAME: &str = "QPACKT_EXPERIMENTS";
+
 /// Basic proxy handler (method agnostic).
-/// Checks for [ReverseProxy] prefix, if found - sends the request there.
+/// Checks for [ReverseProxy] rule matching the request, if found - sends the request there.
+/// Then checks for [crate::experiment::Experiment] running under request's path, if found - serves experiment's version.
 /// Otherwise, finds cookie in client's request and previous version.
-/// If not found, then creates a new cookie and picks url from [Versions]
+/// If not found, then creates a new cookie and picks web root from [Versions]
+#[allow(clippy::too_many_arguments)]
 pub(crate) async fn proxy_handler(
     payload: Payload,
     client_request: HttpRequest,
     versions: Data<Versions>,
     reverse_proxies: Data<ReverseProxies>,
+    experiments: Data<Experiments>,
     writer: Data<HttpRequestLogWriter>,
+    config: Data<QpacktConfig>,
+    upstream: Data<UpstreamClient>,
+    cache: Data<ResponseCache>,
 ) -> HttpResponse {
-    if let Some(rev) = reverse_proxies.find_by_uri(client_request.uri()) {
-        serve_reverse_proxy(payload, &client_request, rev).await
+    if let Some(rev) = take_reverse_proxy(&client_request, &reverse_proxies) {
+        serve_reverse_proxy(payload, &client_request, rev, &versions, &experiments, &upstream, config.trusted_proxies(), &writer, &cache).await
     } else {
-        serve_static(payload, client_request, versions, writer).await
+        serve_static(client_request, versions, experiments, writer, &config).await
+    }
+}
+
+/// Sends request to one of reverse proxy's targets (of visitor's version, if it has any), WebSocket upgrades are
+/// tunnelled. Headers are changed according to [crate::reverse_proxy::ProxyHeaders]. Failures are answered with 502/504
+/// (503 if no target is available). Every response that didn't come from the cache is saved in request log (with
+/// visitor's current version), so that the upstream 5xx rate and outages show up in analytics. Responses to visitors
+/// without a known version are not logged, as they can't be attributed to any version.
+/// Failures and 5xx responses count towards target's ejection.
+/// GET responses of proxies with [crate::reverse_proxy::ProxyCache] are served from [ResponseCache] when possible.
+#[allow(clippy::too_many_arguments)]
+async fn serve_reverse_proxy(
+    payload: Payload,
+    client_request: &HttpRequest,
+    rev: ReverseProxy,
+    versions: &Versions,
+    experiments: &Experiments,
+    upstream: &Data<UpstreamClient>,
+    trusted_proxies: &TrustedProxies,
+    writer: &HttpRequestLogWriter,
+    cache: &Data<ResponseCache>,
+) -> HttpResponse {
+    let hash = calculate_visitor_hash(client_request, trusted_proxies);
+    let visitor_version = visitor_version(client_request, versions, experiments).await;
+    let key = (rev.cache.enabled && !is_websocket_upgrade(client_request) && is_cacheable_request(client_request.method(), client_request.headers()))
+        .then(|| CacheKey::new(rev.id, request_host(client_request), visitor_version.as_ref().map(|v| v.as_str()), client_request.uri()));
+    let cached = key.as_ref().filter(|_| !bypasses_cache(client_request.headers())).and_then(|key| cache.get(key, client_request.headers()));
+    let mut expired = None;
+    let response = match cached {
+        Some(Cached::Fresh(entry)) => cache.respond(&entry, client_request.headers(), "HIT").await,
+        Some(Cached::Stale(entry)) => {
+            if entry.start_revalidation() {
+                revalidate(client_request, &rev, hash, visitor_version.as_ref(), key.clone().unwrap(), entry.clone(), upstream, trusted_proxies, cache);
+            }
+            cache.respond(&entry, client_request.headers(), "STALE").await
+        }
+        Some(Cached::Expired(entry)) => {
+            expired = Some(entry);
+            None
+        }
+        None => None,
+    };
+    let response = match response {
+        Some(response) => Ok(response),
+        None => {
+            let response = match rev.pick(i64::from(hash) as u64, visitor_version.as_ref()) {
+                Some(target) => {
+                    let (head, url) = upstream_request(client_request, &rev, target, visitor_version.as_ref(), trusted_proxies);
+                    let connection = target.state.connect();
+                    let response = if is_websocket_upgrade(client_request) {
+                        upstream.tunnel(payload, &head, &url).await
+                    } else if let Some(key) = key {
+                        let request = CacheableRequest { head, url, key, expired, ttl: rev.cache.ttl };
+                        fetch_cacheable(payload, request, client_request, upstream, cache).await
+                    } else {
+                        build_response(payload, &head, url, upstream).await
+                    };
+                    let success = response.as_ref().is_ok_and(|r| !matches!(r.status().as_u16(), 502..=504));
+                    if target.state.record(success, rev.max_failures) {
+                        warn!("Ejecting target {} of {} after {} failures", target.url, rev.prefix, rev.max_failures);
+                    }
+                    response.map(|response| connection.hold(response))
+                }
+                None => {
+                    warn!("No available target for {}", rev.prefix);
+                    Err(StatusCode::SERVICE_UNAVAILABLE)
+                }
+            };
+            if let Some(version) = visitor_version {
+                let status = response.as_ref().map_or_else(|status| *status, |response| response.status());
+                let log = CreateHttpRequestLog::new(hash, version, client_request.uri().clone(), status.as_u16(), experiment_assignments(client_request));
+                writer.save(log.proxied()).await;
+            }
+            response
+        }
+    };
+    match response {
+        Ok(mut response) => {
+            rev.headers.apply(HeaderDirection::Response, response.headers_mut());
+            response
+        }
+        Err(status) => HttpResponse::new(status),
+    }
+}
+
+/// Visitor's current version: the one assigned in the experiment running under request's path, as in
+/// [serve_experiment], otherwise the one from [QPACKT_COOKIE_NAME]. Cookies are client's input, so only existing,
+/// not drained versions are returned.
+async fn visitor_version(request: &HttpRequest, versions: &Versions, experiments: &Experiments) -> Option<VersionName> {
+    let assigned = experiments
+        .find_by_path(request.path())
+        .and_then(|e| experiment_assignments(request).get(e.id).filter(|v| e.has_version(v)).map(VersionName::to_string));
+    let cookie = request.cookie(QPACKT_COOKIE_NAME).map(|c| c.value().to_string());
+    for name in assigned.into_iter().chain(cookie) {
+        if let Some((_, version)) = versions.get_root_for_cookie(&name).await {
+            return Some(version);
+        }
+    }
+    None
+}
+
+/// Request head and URL to send to the target.
+fn upstream_request(
+    client_request: &HttpRequest,
+    rev: &ReverseProxy,
+    target: &Target,
+    version: Option<&VersionName>,
+    trusted_proxies: &TrustedProxies,
+) -> (RequestHead, Url) {
+    let url = build_reverse_proxy_url(rev, target, client_request.uri());
+    let mut head = client_request.head().clone();
+    trusted_proxies.set_forwarded_headers(client_request, &mut head.headers);
+    set_qpackt_headers(version, &mut head.headers);
+    if !rev.headers.preserve_host {
+        set_target_host(&url, &mut head.headers);
+    }
+    rev.headers.apply(HeaderDirection::Request, &mut head.headers);
+    (head, url)
+}
+
+/// GET request whose response can be stored in [ResponseCache].
+struct CacheableRequest {
+    head: RequestHead,
+    url: Url,
+    key: CacheKey,
+    /// Entry to revalidate with its `ETag`.
+    expired: Option<Arc<CachedResponse>>,
+    ttl: Option<Duration>,
+}
+
+/// Sends request to the target and stores the response if it allows. Expired entry is served again if the target
+/// confirms it's still valid.
+async fn fetch_cacheable(
+    payload: Payload,
+    request: CacheableRequest,
+    client_request: &HttpRequest,
+    upstream: &UpstreamClient,
+    cache: &ResponseCache,
+) -> Result<HttpResponse, StatusCode> {
+    let CacheableRequest { mut head, url, key, expired, ttl } = request;
+    let revalidated = expired.filter(|e| e.etag().is_some() && !head.headers.contains_key(header::IF_NONE_MATCH));
+    if let Some(etag) = revalidated.as_ref().and_then(|e| e.etag()) {
+        head.headers.insert(header::IF_NONE_MATCH, etag.clone());
+    }
+    let mut upstream_response = upstream.send(payload, &head, &url).await?;
+    if let Some(entry) = revalidated.filter(|_| upstream_response.status() == StatusCode::NOT_MODIFIED) {
+        let entry = cache.refresh(&key, &entry, Freshness::of(StatusCode::OK, upstream_response.headers(), ttl));
+        return cache.respond(&entry, client_request.headers(), "REVALIDATED").await.ok_or(StatusCode::BAD_GATEWAY);
+    }
+    let Some(freshness) = Freshness::of(upstream_response.status(), upstream_response.headers(), ttl).filter(|_| fits_in_cache(upstream_response.headers())) else {
+        let mut response = stream_response(&upstream_response);
+        response.insert_header((CACHE_STATUS_HEADER, "MISS"));
+        return Ok(response.streaming(upstream_response));
+    };
+    let body = upstream_response.body().limit(MAX_ENTRY_SIZE).await.map_err(|e| {
+        warn!("Unable to read response from {}: {}", url, e);
+        StatusCode::BAD_GATEWAY
+    })?;
+    cache.insert(key, upstream_response.status(), upstream_response.headers(), body.clone(), freshness, client_request.headers()).await;
+    let mut response = stream_response(&upstream_response);
+    response.insert_header((CACHE_STATUS_HEADER, "MISS"));
+    Ok(response.body(body))
+}
+
+/// Refreshes a stale entry in the background, the client gets the stale one.
+#[allow(clippy::too_many_arguments)]
+fn revalidate(
+    client_request: &HttpRequest,
+    rev: &ReverseProxy,
+    hash: VisitorHash,
+    visitor_version: Option<&VersionName>,
+    key: CacheKey,
+    entry: Arc<CachedResponse>,
+    upstream: &Data<UpstreamClient>,
+    trusted_proxies: &TrustedProxies,
+    cache: &Data<ResponseCache>,
+) {
+    let Some(target) = rev.pick(i64::from(hash) as u64, visitor_version) else {
+        cache.revalidation_failed(&entry);
+        return;
+    };
+    let (mut head, url) = upstream_request(client_request, rev, target, visitor_version, trusted_proxies);
+    head.headers.remove(header::IF_MODIFIED_SINCE);
+    head.headers.remove(header::IF_NONE_MATCH);
+    if let Some(etag) = entry.etag() {
+        head.headers.insert(header::IF_NONE_MATCH, etag.clone());
     }
+    let ttl = rev.cache.ttl;
+    // Vary values are compared with clients' headers, not with the ones rewritten for the target.
+    let request_headers = client_request.headers().clone();
+    let (upstream, cache) = (upstream.clone(), cache.clone());
+    actix_web::rt::spawn(async move {
+        debug!("Revalidating {}", url);
+        let mut response = match upstream.fetch(&head, &url).await {
+            Ok(response) => response,
+            Err(_) => return cache.revalidation_failed(&entry),
+        };
+        if response.status() == StatusCode::NOT_MODIFIED {
+            cache.refresh(&key, &entry, Freshness::of(StatusCode::OK, response.headers(), ttl));
+            return;
+        }
+        let freshness = Freshness::of(response.status(), response.headers(), ttl).filter(|_| fits_in_cache(response.headers()));
+        match (freshness, response.body().limit(MAX_ENTRY_SIZE).await) {
+            (Some(freshness), Ok(body)) => cache.insert(key, response.status(), response.headers(), body, freshness, &request_headers).await,
+            _ => cache.revalidation_failed(&entry),
+        }
+    });
+}
+
+/// Only responses with known, small enough length are cached.
+fn fits_in_cache(headers: &HeaderMap) -> bool {
+    headers.get(header::CONTENT_LENGTH).and_then(|v| v.to_str().ok()?.parse::<usize>().ok()).is_some_and(|length| length <= MAX_ENTRY_SIZE)
 }
 
-async fn serve_reverse_proxy(payload: Payload, client_request: &HttpRequest, rev: ReverseProxy) -> HttpResponse {
-    let url = build_reverse_proxy_url(rev, client_request.uri());
-    build_response(payload, client_request.head(), url, None).await
+/// Replaces qpackt's cookies with [QPACKT_VERSION_HEADER] holding visitor's version, if it's known (see
+/// [visitor_version]). The header is removed from client's request, so targets can trust it.
+fn set_qpackt_headers(version: Option<&VersionName>, headers: &mut HeaderMap) {
+    let is_qpackt_cookie = |cookie: &str| [QPACKT_COOKIE_NAME, QPACKT_EXPERIMENTS_COOKIE_NAME].iter().any(|name| cookie.split_once('=').is_some_and(|(n, _)| n == *name));
+    let cookies = headers.get_all(header::COOKIE).flat_map(|v| v.to_str().ok()).flat_map(|v| v.split(';')).map(str::trim).collect::<Vec<_>>();
+    if cookies.iter().any(|c| is_qpackt_cookie(c)) {
+        let cookies = cookies.into_iter().filter(|c| !c.is_empty() && !is_qpackt_cookie(c)).collect::<Vec<_>>().join("; ");
+        headers.remove(header::COOKIE);
+        if let Ok(cookies) = HeaderValue::from_str(&cookies).map_err(|e| warn!("Unable to rebuild cookies: {}", e)) {
+            if !cookies.is_empty() {
+                headers.insert(header::COOKIE, cookies);
+            }
+        }
+    }
+    headers.remove(QPACKT_VERSION_HEADER);
+    if let Some(version) = version.and_then(|v| HeaderValue::from_str(v.as_str()).ok()) {
+        headers.insert(header::HeaderName::from_static(QPACKT_VERSION_HEADER), version);
+    }
 }
 
-fn build_reverse_proxy_url(reverse_proxy: ReverseProxy, uri: &Uri) -> Url {
-    let mut url = Url::from_str(&reverse_proxy.target).unwrap();
-    let new_path = format!("{}{}", url.path(), &uri.path()[reverse_proxy.prefix.len()..]);
+/// Sets `Host` to target's host (with port, if not default).
+fn set_target_host(url: &Url, headers: &mut HeaderMap) {
+    let Some(host) = url.host_str() else {
+        return;
+    };
+    let host = match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    };
+    if let Ok(host) = HeaderValue::from_str(&host) {
+        headers.insert(header::HOST, host);
+    }
+}
+
+/// [ReverseProxy] rule matching a request, kept in request's extensions, so that rate limiting and [proxy_handler] don't
+/// both look it up. `path` tells whether the request was rewritten in between.
+struct ProxyMatch {
+    path: String,
+    proxy: Option<ReverseProxy>,
+}
+
+/// Checks if a [ReverseProxy] rule matches the request. The rule is kept for [proxy_handler].
+pub(crate) fn match_reverse_proxy(request: &HttpRequest, reverse_proxies: &ReverseProxies) -> bool {
+    let proxy = reverse_proxies.find(request_host(request), request.method(), request.path());
+    let found = proxy.is_some();
+    request.extensions_mut().insert(ProxyMatch { path: request.path().to_string(), proxy });
+    found
+}
+
+/// Takes the rule found by [match_reverse_proxy], or looks it up if there's none or the request was rewritten since.
+fn take_reverse_proxy(request: &HttpRequest, reverse_proxies: &ReverseProxies) -> Option<ReverseProxy> {
+    match request.extensions_mut().remove::<ProxyMatch>() {
+        Some(found) if found.path == request.path() => found.proxy,
+        _ => reverse_proxies.find(request_host(request), request.method(), request.path()),
+    }
+}
+
+/// Host from `Host` header (or URI for HTTP/2), without port.
+pub(crate) fn request_host(request: &HttpRequest) -> Option<&str> {
+    let host = request.uri().host().or_else(|| request.headers().get(header::HOST)?.to_str().ok())?;
+    Some(host.rsplit_once(':').filter(|(_, port)| port.parse::<u16>().is_ok()).map_or(host, |(host, _)| host))
+}
+
+fn build_reverse_proxy_url(rev: &ReverseProxy, target: &Target, uri: &Uri) -> Url {
+    let mut url = Url::from_str(&target.url).unwrap();
+    let new_path = format!("{}{}", url.path().trim_end_matches('/'), rev.upstream_path(uri.path()));
     url.set_query(uri.query());
     url.set_path(&new_path);
-    debug!("Hitting reverse proxy {} for for uri {} => {}", reverse_proxy.target, uri, url);
+    debug!("Hitting reverse proxy {} for for uri {} => {}", target.url, uri, url);
     url
 }
 
 async fn serve_static(
-    payload: Payload,
     client_request: HttpRequest,
     versions: Data<Versions>,
+    experiments: Data<Experiments>,
     writer: Data<HttpRequestLogWriter>,
+    config: &QpacktConfig,
 ) -> HttpResponse {
-    match previous_url(&client_request, &versions).await {
-        None => proxy_to_new(payload, client_request, versions, writer).await,
-        Some((url, version)) => proxy_to_previous(payload, client_request, url.deref().clone(), writer, version).await,
+    let cookie_policy = config.cookie_policy();
+    let hash = calculate_visitor_hash(&client_request, config.trusted_proxies());
+    let previous = experiment_assignments(&client_request);
+    let experiment = experiments.find_by_path(client_request.path());
+    // Drained versions of experiments elsewhere on the site are replaced when the visitor gets there.
+    let drained = if experiment.is_some() { versions.drained().await } else { vec![] };
+    let key = versions.assignment_key(&client_request, config.trusted_proxies());
+    let assignments = experiments.assign(&previous, key, &drained);
+    let served = match &experiment {
+        Some(experiment) => serve_experiment(&client_request, &versions, experiment, &writer, hash, &assignments).await,
+        None => None,
+    };
+    let mut response = match served {
+        Some(response) => response,
+        None => match previous_root(&client_request, &versions).await {
+            None => serve_new(client_request, versions, writer, hash, key, &assignments, cookie_policy).await,
+            Some((web_root, version)) => serve_previous(client_request, &web_root, writer, version, hash, &assignments).await,
+        },
+    };
+    if assignments != previous {
+        if let Err(e) = response.add_cookie(&cookie_policy.build(QPACKT_EXPERIMENTS_COOKIE_NAME, assignments.to_string())) {
+            warn!("Unable to set experiments cookie: {}", e);
+        }
     }
+    response
 }
 
-async fn proxy_to_new(
-    payload: Payload,
+async fn serve_new(
     client_request: HttpRequest,
     versions: Data<Versions>,
     writer: Data<HttpRequestLogWriter>,
+    hash: VisitorHash,
+    key: Option<u64>,
+    assignments: &ExperimentAssignments,
+    cookie_policy: &CookiePolicy,
 ) -> HttpResponse {
-    let Ok((url, version)) = versions.pick_upstream(client_request.query_string()).await else {
+    let Ok((web_root, version)) = versions.pick_upstream(&client_request, key).await else {
         return HttpResponse::new(StatusCode::INTERNAL_SERVER_ERROR);
     };
-    let cookie = create_new_cookie(version.clone());
-    let hash = calculate_visitor_hash(&client_request);
-    debug!("Proxying request to {} with visitor hash {:?}", url, hash);
-    writer.save(CreateHttpRequestLog::new(hash, version, client_request.uri().clone())).await;
-    let destination = build_static_url(&client_request, url.deref().clone()).await;
-    build_response(payload, client_request.head(), destination, Some(cookie)).await
+    let cookie = cookie_policy.build(QPACKT_COOKIE_NAME, version.to_string());
+    debug!("Serving request from {:?} with visitor hash {:?}", web_root.path, hash);
+    let mut response = serve_file(&client_request, &web_root).await;
+    let status = response.status().as_u16();
+    writer.save(CreateHttpRequestLog::new(hash, version, client_request.uri().clone(), status, assignments.clone())).await;
+    if let Err(e) = response.add_cookie(&cookie) {
+        warn!("Unable to set version cookie: {}", e);
+    }
+    response
+}
+
+/// Serves the version assigned to the client in the experiment running under request's path.
+/// Returns `None` if the visitor has no version in the experiment or it can't be served (e.g. it was deleted).
+async fn serve_experiment(
+    client_request: &HttpRequest,
+    versions: &Versions,
+    experiment: &Experiment,
+    writer: &HttpRequestLogWriter,
+    hash: VisitorHash,
+    assignments: &ExperimentAssignments,
+) -> Option<HttpResponse> {
+    let Some((web_root, version)) = versions.get_root_for_cookie(&assignments.get(experiment.id)?.to_string()).await else {
+        warn!("No version of experiment {} is available, serving site's version", experiment.name);
+        return None;
+    };
+    debug!("Serving experiment {} from {:?} with visitor hash {:?}", experiment.name, web_root.path, hash);
+    let response = serve_file(client_request, &web_root).await;
+    let status = response.status().as_u16();
+    writer.save(CreateHttpRequestLog::new(hash, version, client_request.uri().clone(), status, assignments.clone())).await;
+    Some(response)
 }
 
-fn calculate_visitor_hash(client_request: &HttpRequest) -> VisitorHash {
-    let peer = client_request.peer_addr().unwrap_or_else(|| SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1))).ip();
+/// Reads experiment assignments from the cookie.
+pub(super) fn experiment_assignments(request: &HttpRequest) -> ExperimentAssignments {
+    request.cookie(QPACKT_EXPERIMENTS_COOKIE_NAME).map(|c| ExperimentAssignments::parse(c.value())).unwrap_or_default()
+}
+
+pub(crate) fn calculate_visitor_hash(client_request: &HttpRequest, trusted_proxies: &TrustedProxies) -> VisitorHash {
+    let peer = trusted_proxies.client_ip(client_request);
     let user_agent = client_request.headers().get("User-Agent").map(|v| v.as_bytes().to_vec()).unwrap_or_default();
     analytics::hash::create(peer, user_agent)
 }
 
-async fn proxy_to_previous(
-    payload: Payload,
+async fn serve_previous(
     client_request: HttpRequest,
-    url: Url,
+    web_root: &WebRoot,
     writer: Data<HttpRequestLogWriter>,
     version: VersionName,
+    hash: VisitorHash,
+    assignments: &ExperimentAssignments,
 ) -> HttpResponse {
-    let hash = calculate_visitor_hash(&client_request);
-    debug!("Proxying request to {} with visitor hash {:?}", url, hash);
-    let destination = build_static_url(&client_request, url).await;
-    writer.save(CreateHttpRequestLog::new(hash, version, client_request.uri().clone())).await;
-    build_response(payload, client_request.head(), destination, None).await
+    debug!("Serving request from {:?} with visitor hash {:?}", web_root.path, hash);
+    let response = serve_file(&client_request, web_root).await;
+    let status = response.status().as_u16();
+    writer.save(CreateHttpRequestLog::new(hash, version, client_request.uri().clone(), status, assignments.clone())).await;
+    response
 }
 
-async fn previous_url(request: &HttpRequest, versions: &Data<Versions>) -> Option<(Arc<Url>, VersionName)> {
+async fn previous_root(request: &HttpRequest, versions: &Data<Versions>) -> Option<(Arc<WebRoot>, VersionName)> {
     let version = request.cookie(QPACKT_COOKIE_NAME)?;
-    versions.get_url_for_cookie(version.value()).await
+    versions.get_root_for_cookie(version.value()).await
 }
 
-async fn build_response(payload: Payload, head: &RequestHead, destination: Url, cookie: Option<Cookie<'_>>) -> HttpResponse {
-    let proxy_request = build_request(head, destination);
-    let upstream_response = proxy_request.send_stream(payload).await.unwrap();
+/// Sends request upstream and streams the response back. Returns status to respond with if upstream failed.
+async fn build_response(payload: Payload, head: &RequestHead, destination: Url, upstream: &UpstreamClient) -> Result<HttpResponse, StatusCode> {
+    let upstream_response = upstream.send(payload, head, &destination).await?;
+    Ok(stream_response(&upstream_response).streaming(upstream_response))
+}
+
+/// Response with upstream's status and headers.
+fn stream_response<S>(upstream_response: &ClientResponse<S>) -> HttpResponseBuilder {
     let mut proxy_response = HttpResponse::build(upstream_response.status());
     for (header_name, header_value) in upstream_response.headers().iter().filter(|(h, _)| *h != "connection") {
         proxy_response.insert_header((header_name.clone(), header_value.clone()));
     }
-    if let Some(cookie) = cookie {
-        proxy_response.cookie(cookie);
-    }
-    // TODO add "no cache"
-    proxy_response.streaming(upstream_response)
+    proxy_response
 }
 
-fn create_new_cookie(version: VersionName) -> Cookie<'static> {
-    let mut cookie = Cookie::new(QPACKT_COOKIE_NAME, version.to_string());
-    cookie.set_expires(OffsetDateTime::now_utc().add(Duration::days(7)));
-    cookie
-}
+#[cfg(test)]
+mod test {
+    use std::path::Path;
 
-fn build_request(head: &RequestHead, destination: Url) -> ClientRequest {
-    let client = Client::default();
-    client.request_from(destination.as_str(), head).no_decompress()
-}
+    use actix_web::http::header;
+    use actix_web::test::TestRequest;
+    use url::Url;
 
-async fn build_static_url(client_request: &HttpRequest, mut url: Url) -> Url {
-    url.set_path(client_request.uri().path());
-    url.set_query(client_request.uri().query());
-    url
+    use crate::dao::version::Version;
+    use crate::experiment::{Experiment, Experiments, Variant};
+    use crate::manager::assignment::Assignment;
+    use crate::manager::strategy::Strategy;
+    use crate::proxy::handler::{set_qpackt_headers, set_target_host, visitor_version};
+    use crate::server::{ServingProfile, Versions};
+
+    fn versions() -> Versions {
+        let version = |name: &str, drain: bool| Version {
+            name: name.to_string().into(),
+            web_root: name.into(),
+            strategy: Strategy::Weight(50),
+            drain,
+            profile: ServingProfile::default(),
+        };
+        Versions::new(vec![version("blue", false), version("red", false), version("old", true)], Path::new("/tmp"), Assignment::Random)
+    }
+
+    #[actix_web::test]
+    async fn trusts_only_cookies_of_known_versions() {
+        let versions = versions();
+        let experiments = Experiments::default();
+        let variants = vec![Variant { version: "blue".to_string().into(), weight: 1 }, Variant { version: "red".to_string().into(), weight: 1 }];
+        experiments.set(vec![Experiment { id: 1, name: "shop".into(), path_prefix: "/shop".into(), variants }]);
+        let request = |path: &str, cookies: &str| TestRequest::with_uri(path).insert_header((header::COOKIE, cookies.to_string())).to_http_request();
+        let (versions, experiments) = (&versions, &experiments);
+        let version = |request| async move { visitor_version(&request, versions, experiments).await.map(|v| v.to_string()) };
+        assert_eq!(version(request("/api", "QPACKT_VERSION=blue")).await.as_deref(), Some("blue"));
+        assert_eq!(version(request("/api", "QPACKT_VERSION=old")).await, None);
+        assert_eq!(version(request("/api", "QPACKT_VERSION=made-up")).await, None);
+        assert_eq!(version(TestRequest::default().to_http_request()).await, None);
+        assert_eq!(version(request("/shop/api", "QPACKT_VERSION=blue; QPACKT_EXPERIMENTS=1:red")).await.as_deref(), Some("red"));
+        assert_eq!(version(request("/api", "QPACKT_VERSION=blue; QPACKT_EXPERIMENTS=1:red")).await.as_deref(), Some("blue"));
+        assert_eq!(version(request("/shop/api", "QPACKT_VERSION=blue; QPACKT_EXPERIMENTS=1:old")).await.as_deref(), Some("blue"));
+    }
+
+    #[test]
+    fn replaces_qpackt_cookies_with_version_header() {
+        let request = TestRequest::default()
+            .insert_header((header::COOKIE, "a=1; QPACKT_VERSION=blue; QPACKT_EXPERIMENTS=1:red; b=2"))
+            .insert_header(("x-qpackt-version", "spoofed"))
+            .to_http_request();
+        let mut headers = request.headers().clone();
+        set_qpackt_headers(Some(&"blue".to_string().into()), &mut headers);
+        assert_eq!(headers.get(header::COOKIE).unwrap(), "a=1; b=2");
+        assert_eq!(headers.get_all("x-qpackt-version").collect::<Vec<_>>(), vec!["blue"]);
+        let mut headers = request.headers().clone();
+        set_qpackt_headers(None, &mut headers);
+        assert!(!headers.contains_key("x-qpackt-version"));
+
+        let request = TestRequest::default().insert_header((header::COOKIE, "QPACKT_VERSION=blue")).insert_header((header::HOST, "qpackt.com")).to_http_request();
+        let mut headers = request.headers().clone();
+        set_qpackt_headers(None, &mut headers);
+        assert!(!headers.contains_key(header::COOKIE));
+        set_target_host(&Url::parse("http://localhost:9999/api").unwrap(), &mut headers);
+        assert_eq!(headers.get(header::HOST).unwrap(), "localhost:9999");
+    }
 }
@@ -0,0 +1,125 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Active health checks of [ReverseProxy] targets. Every target of a proxy with a health check path gets
+//! `GET <target><path>` periodically, anything other than 2xx/3xx marks it unhealthy until the next successful check.
+//! Checks are sent with the same [UpstreamClient] as proxied requests, so they use TLS and the DNS cache the same way.
+
+use std::time::Duration;
+
+use actix_web::dev::RequestHead;
+use actix_web::http::header::{HeaderValue, USER_AGENT};
+use actix_web::rt::System;
+use actix_web::web::Data;
+use futures::future::join_all;
+use log::{debug, info, warn};
+use tokio::time::{sleep, timeout};
+use url::Url;
+
+use crate::proxy::upstream::{UpstreamClient, UpstreamPolicy};
+use crate::reverse_proxy::{ReverseProxies, ReverseProxy, Target};
+
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Starts a background thread that every [HEALTH_CHECK_INTERVAL] checks targets of all proxies.
+/// `awc::Client` can't be sent between threads, so the thread runs its own actix system.
+pub(crate) fn spawn_health_check_loop(reverse_proxies: Data<ReverseProxies>, policy: UpstreamPolicy) {
+    std::thread::spawn(move || {
+        System::new().block_on(async move {
+            debug!("Started health check task");
+            let client = policy.build_client();
+            loop {
+                let proxies = reverse_proxies.list();
+                join_all(proxies.iter().map(|proxy| check_proxy(&client, proxy))).await;
+                sleep(HEALTH_CHECK_INTERVAL).await;
+            }
+        })
+    });
+}
+
+async fn check_proxy(client: &UpstreamClient, proxy: &ReverseProxy) {
+    let Some(path) = &proxy.health_check_path else {
+        return;
+    };
+    join_all(proxy.targets.iter().map(|target| check_target(client, target, path))).await;
+}
+
+async fn check_target(client: &UpstreamClient, target: &Target, path: &str) {
+    let healthy = is_healthy(client, &target.url, path).await;
+    if healthy != target.state.is_healthy() {
+        if healthy {
+            info!("Target {} is healthy again", target.url);
+        } else {
+            warn!("Target {} failed health check", target.url);
+        }
+    }
+    target.state.set_healthy(healthy);
+}
+
+async fn is_healthy(client: &UpstreamClient, target: &str, path: &str) -> bool {
+    let Ok(mut url) = Url::parse(target) else {
+        return false;
+    };
+    url.set_path(&format!("{}{}", url.path().trim_end_matches('/'), path));
+    let mut head = RequestHead::default();
+    head.headers.insert(USER_AGENT, HeaderValue::from_static("qpackt-health-check"));
+    match timeout(HEALTH_CHECK_TIMEOUT, client.fetch(&head, &url)).await {
+        Ok(Ok(response)) => response.status().is_success() || response.status().is_redirection(),
+        Ok(Err(status)) => {
+            debug!("Health check of {} failed with {}", target, status);
+            false
+        }
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    use crate::proxy::health::is_healthy;
+    use crate::proxy::upstream::UpstreamPolicy;
+
+    /// Answers a single request with `status` and returns the request line.
+    async fn serve_once(listener: TcpListener, status: &'static str) -> String {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = vec![0; 1024];
+        let read = stream.read(&mut request).await.unwrap();
+        stream.write_all(format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).as_bytes()).await.unwrap();
+        String::from_utf8_lossy(&request[..read]).lines().next().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn checks_status_of_health_path() {
+        let client = UpstreamPolicy::default().build_client();
+        for (status, healthy) in [("200 OK", true), ("503 Service Unavailable", false)] {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let target = format!("http://{}/api/", listener.local_addr().unwrap());
+            let server = tokio::spawn(serve_once(listener, status));
+            assert_eq!(is_healthy(&client, &target, "/health").await, healthy);
+            assert_eq!(server.await.unwrap(), "GET /api/health HTTP/1.1");
+        }
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let target = format!("http://{}", listener.local_addr().unwrap());
+        drop(listener);
+        assert!(!is_healthy(&client, &target, "/health").await);
+    }
+}
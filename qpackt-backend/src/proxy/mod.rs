@@ -26,17 +26,32 @@ use rustls::ServerConfig;
 
 use crate::analytics::event_writer::EventWriter;
 use crate::analytics::http_request_log_writer::HttpRequestLogWriter;
+use crate::config::QpacktConfig;
 use crate::dao::Dao;
+use crate::experiment::Experiments;
 use crate::https_redirect::CheckHttpsRedirect;
 use crate::proxy::event::{collect_event, QPACKT_EVENT_URI, send_event_script};
 use crate::proxy::handler::proxy_handler;
+use crate::proxy::cache::ResponseCache;
+use crate::proxy::rate_limit::{CheckRateLimit, RateLimiter};
 use crate::reverse_proxy::ReverseProxies;
+use crate::response_headers::HeaderRules;
+use crate::rewrite::{CheckRewrites, Rewrites};
 use crate::server::Versions;
 use crate::ssl::challenge::AcmeChallenge;
 
+pub(super) mod cookie;
 pub(super) mod handler;
+pub(super) mod proxy_protocol;
+pub(super) mod tunnel;
+pub(super) mod upstream;
 pub(super) mod event;
+pub(super) mod forwarded;
+pub(super) mod health;
+pub(super) mod cache;
+pub(super) mod rate_limit;
 
+#[allow(clippy::too_many_arguments)]
 pub(super) fn start_proxy_http(
     addr: &str,
     dao: Data<Dao>,
@@ -44,44 +59,72 @@ pub(super) fn start_proxy_http(
     writer: Data<HttpRequestLogWriter>,
     ssl_challenge: Data<AcmeChallenge>,
     reverse_proxies: Data<ReverseProxies>,
+    experiments: Data<Experiments>,
     event_writer: Data<EventWriter>,
+    config: Data<QpacktConfig>,
+    cache: Data<ResponseCache>,
+    limiter: Data<RateLimiter>,
+    rewrites: Data<Rewrites>,
 ) {
-    tokio::spawn(
-        HttpServer::new(move || {
-            App::new()
-                .wrap(CheckHttpsRedirect {})
-                .app_data(dao.clone())
-                .app_data(versions.clone())
-                .app_data(writer.clone())
-                .app_data(ssl_challenge.clone())
-                .app_data(reverse_proxies.clone())
-                .app_data(event_writer.clone())
-                .service(web::resource("/.well-known/acme-challenge/{token}").route(web::get().to(serve_challenge)))
-                .service(web::resource(QPACKT_EVENT_URI).post(collect_event))
-                .service(web::resource("/qpackt/event/send_event.js").get(send_event_script))
-                .default_service(web::to(proxy_handler))
-        })
-            .bind(addr)
-            .unwrap()
-            .run(),
-    );
+    let proxy_protocol = config.http_proxy_protocol();
+    let app = move || {
+        App::new()
+            .wrap(CheckRewrites {})
+            .wrap(CheckRateLimit {})
+            .wrap(CheckHttpsRedirect {})
+            .app_data(dao.clone())
+            .app_data(versions.clone())
+            .app_data(writer.clone())
+            .app_data(ssl_challenge.clone())
+            .app_data(reverse_proxies.clone())
+            .app_data(experiments.clone())
+            .app_data(event_writer.clone())
+            .app_data(Data::new(config.upstream_policy().build_client()))
+            .app_data(config.clone())
+            .app_data(cache.clone())
+            .app_data(limiter.clone())
+            .app_data(rewrites.clone())
+            .app_data(Data::new(HeaderRules::new(&[config.response_headers()])))
+            .service(web::resource("/.well-known/acme-challenge/{token}").route(web::get().to(serve_challenge)))
+            .service(web::resource(QPACKT_EVENT_URI).post(collect_event))
+            .service(web::resource("/qpackt/event/send_event.js").get(send_event_script))
+            .default_service(web::to(proxy_handler))
+    };
+    if proxy_protocol {
+        tokio::spawn(proxy_protocol::listen(addr, app, None).unwrap());
+    } else {
+        tokio::spawn(HttpServer::new(app).bind(addr).unwrap().run());
+    }
 }
 
-pub(super) fn start_proxy_https(addr: &str, dao: Data<Dao>, versions: Data<Versions>, writer: Data<HttpRequestLogWriter>, tls_config: ServerConfig, reverse_proxies: Data<ReverseProxies>, event_writer: Data<EventWriter>) {
-    tokio::spawn(
-        HttpServer::new(move || App::new()
+#[allow(clippy::too_many_arguments)]
+pub(super) fn start_proxy_https(addr: &str, dao: Data<Dao>, versions: Data<Versions>, writer: Data<HttpRequestLogWriter>, tls_config: ServerConfig, reverse_proxies: Data<ReverseProxies>, experiments: Data<Experiments>, event_writer: Data<EventWriter>, config: Data<QpacktConfig>, cache: Data<ResponseCache>, limiter: Data<RateLimiter>, rewrites: Data<Rewrites>) {
+    let proxy_protocol = config.https_proxy_protocol();
+    let app = move || {
+        App::new()
+            .wrap(CheckRewrites {})
+            .wrap(CheckRateLimit {})
             .app_data(versions.clone())
             .app_data(dao.clone())
             .app_data(writer.clone())
             .app_data(reverse_proxies.clone())
+            .app_data(experiments.clone())
             .app_data(event_writer.clone())
+            .app_data(Data::new(config.upstream_policy().build_client()))
+            .app_data(config.clone())
+            .app_data(cache.clone())
+            .app_data(limiter.clone())
+            .app_data(rewrites.clone())
+            .app_data(Data::new(HeaderRules::new(&[config.response_headers()])))
             .service(web::resource(QPACKT_EVENT_URI).post(collect_event))
             .service(web::resource("/qpackt/event/send_event.js").get(send_event_script))
-            .default_service(web::to(proxy_handler)))
-            .bind_rustls_021(addr, tls_config)
-            .unwrap()
-            .run(),
-    );
+            .default_service(web::to(proxy_handler))
+    };
+    if proxy_protocol {
+        tokio::spawn(proxy_protocol::listen(addr, app, Some(tls_config)).unwrap());
+    } else {
+        tokio::spawn(HttpServer::new(app).bind_rustls_021(addr, tls_config).unwrap().run());
+    }
 }
 
 async fn serve_challenge(token: Path<String>, ssl_challenge: Data<AcmeChallenge>) -> HttpResponse {
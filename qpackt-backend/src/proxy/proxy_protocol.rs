@@ -0,0 +1,245 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! HAProxy PROXY protocol (v1 and v2) for listeners behind TCP load balancers. The balancer sends client's
+//! address in a header before any HTTP or TLS data, it becomes request's peer address (and so feeds visitor hashes).
+//! Spec: <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::fmt::Debug;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_http::error::DispatchError;
+use actix_http::{HttpService, Protocol, Request};
+use actix_service::{map_config, IntoServiceFactory, ServiceFactoryExt};
+use actix_web::body::MessageBody;
+use actix_web::dev::{fn_service, AppConfig, Response, Server, Service, ServiceFactory};
+use actix_web::rt::net::TcpStream;
+use actix_web::Error;
+use log::{debug, warn};
+use rustls::ServerConfig;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::time::timeout;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::either::Either;
+
+/// First 12 bytes of every v2 header.
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest v1 header including `\r\n`.
+const V1_MAX_LENGTH: usize = 107;
+/// Shortest v1 header (`PROXY UNKNOWN\r\n`), read before telling the versions apart.
+const V1_MIN_LENGTH: usize = 15;
+/// How long a connection may take to send the header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Marks connections that came over TLS, since [AppConfig] of these listeners can't say so.
+pub(crate) struct TlsConnection;
+
+/// Starts a listener that requires PROXY protocol header on every connection, followed by TLS if `tls` is set.
+/// Connections without a valid header are dropped.
+pub(super) fn listen<F, I, S, B>(addr: &str, factory: F, tls: Option<ServerConfig>) -> io::Result<Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<Error> + 'static,
+    S::InitError: Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let acceptor = tls.map(|mut config| {
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        TlsAcceptor::from(Arc::new(config))
+    });
+    Ok(Server::build()
+        .bind("qpackt-proxy-protocol", addr, move || {
+            let app = factory().into_factory().map_err(|e| e.into().error_response());
+            let http = HttpService::build()
+                .on_connect_ext(|io: &Either<TcpStream, _>, extensions| {
+                    if let Either::Right(_) = io {
+                        extensions.insert(TlsConnection);
+                    }
+                })
+                .finish(map_config(app, |_| AppConfig::default()));
+            let acceptor = acceptor.clone();
+            fn_service(move |io| accept(io, acceptor.clone())).and_then(http)
+        })?
+        .run())
+}
+
+/// Reads PROXY header and completes TLS handshake if needed.
+async fn accept(
+    mut io: TcpStream,
+    acceptor: Option<TlsAcceptor>,
+) -> Result<(Either<TcpStream, tokio_rustls::server::TlsStream<TcpStream>>, Protocol, Option<SocketAddr>), DispatchError> {
+    let source = match timeout(HEADER_TIMEOUT, read_header(&mut io)).await {
+        Ok(Ok(source)) => source.or(io.peer_addr().ok()),
+        Ok(Err(e)) => {
+            warn!("Invalid PROXY protocol header from {:?}: {}", io.peer_addr(), e);
+            return Err(DispatchError::Io(e));
+        }
+        Err(_) => return Err(DispatchError::Io(io::ErrorKind::TimedOut.into())),
+    };
+    debug!("PROXY protocol connection from {:?}", source);
+    let Some(acceptor) = acceptor else {
+        return Ok((Either::Left(io), Protocol::Http1, source));
+    };
+    let tls = acceptor.accept(io).await.map_err(DispatchError::Io)?;
+    let protocol = if tls.get_ref().1.alpn_protocol() == Some(b"h2") { Protocol::Http2 } else { Protocol::Http1 };
+    Ok((Either::Right(tls), protocol, source))
+}
+
+/// Reads exactly the header, leaving everything after it in the stream.
+/// Returns client's address, or `None` for health checks (`LOCAL`/`UNKNOWN`) and non-IP connections.
+async fn read_header(io: &mut (impl AsyncRead + Unpin)) -> io::Result<Option<SocketAddr>> {
+    let mut header = vec![0; V1_MIN_LENGTH];
+    io.read_exact(&mut header).await?;
+    if header.starts_with(b"PROXY ") {
+        while !header.ends_with(b"\r\n") {
+            if header.len() >= V1_MAX_LENGTH {
+                return Err(invalid("v1 header too long"));
+            }
+            header.push(io.read_u8().await?);
+        }
+        return parse_v1(&header);
+    }
+    if header.starts_with(V2_SIGNATURE) {
+        header.push(io.read_u8().await?);
+        let mut addresses = vec![0; u16::from_be_bytes([header[14], header[15]]) as usize];
+        io.read_exact(&mut addresses).await?;
+        return parse_v2(header[12], header[13], &addresses);
+    }
+    Err(invalid("missing header"))
+}
+
+/// Parses `PROXY TCP4 <source> <destination> <source port> <destination port>\r\n`.
+fn parse_v1(header: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let header = std::str::from_utf8(header).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let parts = header.trim_end_matches("\r\n").split(' ').collect::<Vec<_>>();
+    match parts[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip = source.parse::<IpAddr>().map_err(|_| invalid("invalid v1 source address"))?;
+            let port = port.parse::<u16>().map_err(|_| invalid("invalid v1 source port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("invalid v1 header")),
+    }
+}
+
+/// Parses v2 source address from version/command and family bytes followed by address block.
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+    match version_command & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unsupported v2 command")),
+    }
+    let port = |offset: usize| u16::from_be_bytes([addresses[offset], addresses[offset + 1]]);
+    match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::new(addresses[0], addresses[1], addresses[2], addresses[3]);
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        2 if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(32))))
+        }
+        1 | 2 => Err(invalid("v2 address block too short")),
+        _ => Ok(None),
+    }
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::SocketAddr;
+
+    use actix_web::{web, App, HttpRequest};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use crate::proxy::proxy_protocol::{listen, parse_v1, V2_SIGNATURE};
+
+    /// Starts a listener answering with request's peer address, sends raw `header` and a request, returns the body.
+    async fn peer_seen_by_server(header: &[u8]) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let app = || App::new().default_service(web::to(|request: HttpRequest| async move { format!("{:?}", request.peer_addr()) }));
+        actix_web::rt::spawn(listen(&addr, app, None).unwrap());
+
+        let mut stream = loop {
+            if let Ok(stream) = TcpStream::connect(&addr).await {
+                break stream;
+            }
+            actix_web::rt::time::sleep(std::time::Duration::from_millis(10)).await;
+        };
+        stream.write_all(header).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: qpackt.com\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        let _ = stream.read_to_string(&mut response).await;
+        response.split("\r\n\r\n").nth(1).unwrap_or_default().to_string()
+    }
+
+    fn v2_header(command: u8, family: u8, addresses: &[u8]) -> Vec<u8> {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family);
+        header.extend_from_slice(&(addresses.len() as u16).to_be_bytes());
+        header.extend_from_slice(addresses);
+        header
+    }
+
+    #[test]
+    fn parses_v1() {
+        let source: SocketAddr = "[2001:db8::1]:4711".parse().unwrap();
+        assert_eq!(parse_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 4711 443\r\n").unwrap(), Some(source));
+        assert_eq!(parse_v1(b"PROXY UNKNOWN\r\n").unwrap(), None);
+        assert!(parse_v1(b"PROXY TCP4 1.2.3.4\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 x 5.6.7.8 1 2\r\n").is_err());
+    }
+
+    #[actix_web::test]
+    async fn takes_peer_from_v1_header() {
+        assert_eq!(peer_seen_by_server(b"PROXY TCP4 1.2.3.4 5.6.7.8 4711 80\r\n").await, "Some(1.2.3.4:4711)");
+    }
+
+    #[actix_web::test]
+    async fn takes_peer_from_v2_header() {
+        let addresses = [1, 2, 3, 4, 5, 6, 7, 8, 0x12, 0x67, 0, 80, 0x03, 0, 1, 0xff];
+        assert_eq!(peer_seen_by_server(&v2_header(1, 0x11, &addresses)).await, "Some(1.2.3.4:4711)");
+        // LOCAL command (health checks) keeps balancer's address.
+        assert!(peer_seen_by_server(&v2_header(0, 0x00, &[])).await.starts_with("Some(127.0.0.1:"));
+    }
+
+    #[actix_web::test]
+    async fn drops_connections_without_header() {
+        assert_eq!(peer_seen_by_server(b"").await, "");
+    }
+}
@@ -0,0 +1,376 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Token bucket rate limiting of visitors, configured per route group in qpackt.yaml. Every client (IP address or
+//! [VisitorHash]) gets a bucket of `burst` requests refilled at `requests_per_second`. Buckets are kept for at most
+//! `rate_limit_max_clients` clients, full ones are dropped first and then the least recently used.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{self, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header;
+use actix_web::web::Data;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use log::debug;
+
+use crate::analytics::hash::VisitorHash;
+use crate::config::QpacktConfig;
+use crate::error::QpacktError;
+use crate::proxy::event::QPACKT_EVENT_URI;
+use crate::proxy::forwarded::TrustedProxies;
+use crate::proxy::handler::{calculate_visitor_hash, match_reverse_proxy};
+use crate::reverse_proxy::ReverseProxies;
+
+/// Default number of clients whose buckets are remembered.
+const DEFAULT_MAX_CLIENTS: usize = 100_000;
+
+/// Requests limited together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub(crate) enum RouteGroup {
+    /// Events sent to [QPACKT_EVENT_URI].
+    Event,
+    /// Requests matching one of reverse proxies.
+    ReverseProxy,
+    /// Everything else, mostly files of the site's versions.
+    Site,
+}
+
+impl Display for RouteGroup {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RouteGroup::Event => write!(f, "event"),
+            RouteGroup::ReverseProxy => write!(f, "reverse_proxy"),
+            RouteGroup::Site => write!(f, "site"),
+        }
+    }
+}
+
+impl FromStr for RouteGroup {
+    type Err = QpacktError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "event" => Ok(RouteGroup::Event),
+            "reverse_proxy" => Ok(RouteGroup::ReverseProxy),
+            "site" => Ok(RouteGroup::Site),
+            _ => Err(QpacktError::InvalidConfig(format!("Invalid route group `{}`, expected event, reverse_proxy or site", value))),
+        }
+    }
+}
+
+/// What identifies a client.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) enum ClientKey {
+    /// Client's address, see [TrustedProxies::client_ip].
+    #[default]
+    Ip,
+    /// Client's address and User-Agent, so that visitors behind one NAT don't share their limit.
+    Visitor,
+}
+
+impl Display for ClientKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientKey::Ip => write!(f, "ip"),
+            ClientKey::Visitor => write!(f, "visitor"),
+        }
+    }
+}
+
+impl FromStr for ClientKey {
+    type Err = QpacktError;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "ip" => Ok(ClientKey::Ip),
+            "visitor" => Ok(ClientKey::Visitor),
+            _ => Err(QpacktError::InvalidConfig(format!("Invalid rate limit key `{}`, expected ip or visitor", value))),
+        }
+    }
+}
+
+/// Limit of a single route group.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RateLimit {
+    pub(crate) requests_per_second: f64,
+    /// Requests a client can make at once, after being idle.
+    pub(crate) burst: u32,
+    pub(crate) key: ClientKey,
+}
+
+impl RateLimit {
+    pub(crate) fn validate(&self) -> crate::error::Result<()> {
+        if !self.requests_per_second.is_finite() || self.requests_per_second <= 0.0 || self.burst == 0 {
+            return Err(QpacktError::InvalidConfig("Rate limit needs positive `requests_per_second` and `burst`".into()));
+        }
+        Ok(())
+    }
+}
+
+/// Limits configured in qpackt.yaml, route groups without a limit are not limited.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct RateLimits {
+    pub(crate) groups: BTreeMap<RouteGroup, RateLimit>,
+    /// Clients whose buckets are remembered, per all groups.
+    pub(crate) max_clients: usize,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self { groups: BTreeMap::new(), max_clients: DEFAULT_MAX_CLIENTS }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+enum Client {
+    Ip(IpAddr),
+    Visitor(VisitorHash),
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl Bucket {
+    fn tokens(&self, limit: &RateLimit, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * limit.requests_per_second).min(limit.burst as f64)
+    }
+}
+
+/// Buckets of all clients, shared by all workers of both listeners.
+pub(crate) struct RateLimiter {
+    limits: RateLimits,
+    buckets: Mutex<HashMap<(RouteGroup, Client), Bucket>>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limits: RateLimits) -> Self {
+        Self { limits, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes a token from client's bucket. Returns how long the client has to wait if the bucket is empty.
+    fn acquire(&self, group: RouteGroup, client: Client, now: Instant) -> Result<(), Duration> {
+        let Some(limit) = self.limits.groups.get(&group) else {
+            return Ok(());
+        };
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= self.limits.max_clients && !buckets.contains_key(&(group, client)) {
+            self.evict(&mut buckets, now);
+        }
+        let bucket = buckets.entry((group, client)).or_insert(Bucket { tokens: limit.burst as f64, updated: now });
+        bucket.tokens = bucket.tokens(limit, now);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / limit.requests_per_second))
+        }
+    }
+
+    /// Drops buckets that are full again, they are the same as missing ones. If that's not enough, the least recently
+    /// used tenth is dropped, so that eviction doesn't run on every request of a flood from many addresses.
+    fn evict(&self, buckets: &mut HashMap<(RouteGroup, Client), Bucket>, now: Instant) {
+        let groups = &self.limits.groups;
+        buckets.retain(|(group, _), bucket| groups.get(group).is_some_and(|limit| bucket.tokens(limit, now) < limit.burst as f64));
+        if buckets.len() < self.limits.max_clients {
+            return;
+        }
+        let mut updated = buckets.values().map(|b| b.updated).collect::<Vec<_>>();
+        let index = buckets.len() / 10;
+        let (_, oldest, _) = updated.select_nth_unstable(index);
+        let oldest = *oldest;
+        buckets.retain(|_, bucket| bucket.updated > oldest);
+        debug!("Evicted rate limits, {} clients left", buckets.len());
+    }
+
+    fn route_group(request: &HttpRequest) -> RouteGroup {
+        if request.path() == QPACKT_EVENT_URI {
+            return RouteGroup::Event;
+        }
+        let reverse_proxy = request.app_data::<Data<ReverseProxies>>().is_some_and(|proxies| match_reverse_proxy(request, proxies));
+        if reverse_proxy {
+            RouteGroup::ReverseProxy
+        } else {
+            RouteGroup::Site
+        }
+    }
+
+    fn client(&self, group: RouteGroup, request: &HttpRequest) -> Client {
+        let default = TrustedProxies::default();
+        let config = request.app_data::<Data<QpacktConfig>>();
+        let trusted_proxies = config.map(|c| c.trusted_proxies()).unwrap_or(&default);
+        match self.limits.groups.get(&group).map(|limit| limit.key).unwrap_or_default() {
+            ClientKey::Ip => Client::Ip(trusted_proxies.client_ip(request)),
+            ClientKey::Visitor => Client::Visitor(calculate_visitor_hash(request, trusted_proxies)),
+        }
+    }
+}
+
+/// Answers with 429 and `Retry-After` when client exceeds its limit. Needs [RateLimiter] in app data.
+pub(crate) struct CheckRateLimit;
+
+impl<S, B> Transform<S, ServiceRequest> for CheckRateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CheckRateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CheckRateLimitMiddleware { service }))
+    }
+}
+pub(crate) struct CheckRateLimitMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for CheckRateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, request: ServiceRequest) -> Self::Future {
+        if let Some(limiter) = request.app_data::<Data<RateLimiter>>() {
+            let group = RateLimiter::route_group(request.request());
+            let client = limiter.client(group, request.request());
+            if let Err(wait) = limiter.acquire(group, client, Instant::now()) {
+                debug!("Rate limit of {} exceeded by {:?}", group, client);
+                let (request, _) = request.into_parts();
+                let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                let response =
+                    HttpResponse::TooManyRequests().insert_header((header::RETRY_AFTER, retry_after)).finish().map_into_right_body();
+                return Box::pin(async { Ok(ServiceResponse::new(request, response)) });
+            }
+        }
+        let res = self.service.call(request);
+        Box::pin(async move { res.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::net::{IpAddr, Ipv4Addr};
+    use std::time::{Duration, Instant};
+
+    use actix_web::http::{header, Method, StatusCode};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::web::Data;
+    use actix_web::{web, App, HttpResponse};
+
+    use crate::proxy::event::QPACKT_EVENT_URI;
+    use crate::proxy::rate_limit::{CheckRateLimit, Client, ClientKey, RateLimit, RateLimiter, RateLimits, RouteGroup};
+
+    fn limiter(max_clients: usize) -> RateLimiter {
+        let limit = RateLimit { requests_per_second: 2.0, burst: 3, key: ClientKey::Ip };
+        RateLimiter::new(RateLimits { groups: BTreeMap::from([(RouteGroup::Event, limit)]), max_clients })
+    }
+
+    fn client(last: u8) -> Client {
+        Client::Ip(IpAddr::V4(Ipv4Addr::new(10, 0, 0, last)))
+    }
+
+    #[test]
+    fn refills_buckets() {
+        let limiter = limiter(10);
+        let now = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.acquire(RouteGroup::Event, client(1), now).is_ok());
+        }
+        assert_eq!(limiter.acquire(RouteGroup::Event, client(1), now), Err(Duration::from_millis(500)));
+        assert!(limiter.acquire(RouteGroup::Event, client(2), now).is_ok());
+        assert!(limiter.acquire(RouteGroup::Site, client(1), now).is_ok());
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.acquire(RouteGroup::Event, client(1), later).is_ok());
+        assert!(limiter.acquire(RouteGroup::Event, client(1), later).is_err());
+    }
+
+    #[test]
+    fn evicts_full_buckets() {
+        let limiter = limiter(10);
+        let now = Instant::now();
+        for i in 0..10 {
+            limiter.acquire(RouteGroup::Event, client(i), now).unwrap();
+        }
+        limiter.acquire(RouteGroup::Event, client(100), now + Duration::from_secs(10)).unwrap();
+        assert_eq!(limiter.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_buckets() {
+        let limiter = limiter(10);
+        let now = Instant::now();
+        for i in 0..10 {
+            limiter.acquire(RouteGroup::Event, client(i), now + Duration::from_millis(i as u64)).unwrap();
+        }
+        limiter.acquire(RouteGroup::Event, client(100), now + Duration::from_millis(20)).unwrap();
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(buckets.len() < 10);
+        assert!(!buckets.contains_key(&(RouteGroup::Event, client(0))));
+        assert!(buckets.contains_key(&(RouteGroup::Event, client(9))));
+        assert!(buckets.contains_key(&(RouteGroup::Event, client(100))));
+    }
+
+    #[actix_web::test]
+    async fn answers_too_many_requests() {
+        let app = init_service(
+            App::new()
+                .wrap(CheckRateLimit {})
+                .app_data(Data::new(limiter(10)))
+                .route(QPACKT_EVENT_URI, web::post().to(HttpResponse::Ok))
+                .route("/", web::get().to(HttpResponse::Ok)),
+        )
+        .await;
+        let request = |last: u8, uri: &str, method| {
+            TestRequest::default().method(method).uri(uri).peer_addr(format!("10.0.0.{}:1234", last).parse().unwrap()).to_request()
+        };
+        for _ in 0..3 {
+            assert_eq!(call_service(&app, request(1, QPACKT_EVENT_URI, Method::POST)).await.status(), StatusCode::OK);
+        }
+        let response = call_service(&app, request(1, QPACKT_EVENT_URI, Method::POST)).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+        assert_eq!(call_service(&app, request(2, QPACKT_EVENT_URI, Method::POST)).await.status(), StatusCode::OK);
+        assert_eq!(call_service(&app, request(1, "/", Method::GET)).await.status(), StatusCode::OK);
+    }
+}
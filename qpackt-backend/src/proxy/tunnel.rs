@@ -0,0 +1,337 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Tunnelling of WebSocket connections to [crate::reverse_proxy::ReverseProxy] targets. The handshake is passed to the
+//! target over a new connection (TLS for `https` targets), after `101 Switching Protocols` bytes are copied both ways
+//! until either side closes or nothing is sent in any direction for the idle timeout. Actix passes the rest of the
+//! connection as payload only for `Upgrade: websocket`, so other upgrades (e.g. `h2c`) are proxied as regular requests.
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_service::Service;
+use actix_tls::connect::rustls_0_21::webpki_roots_cert_store;
+use actix_tls::connect::{ConnectInfo, Connector as TcpConnector, Resolver};
+
+use actix_web::dev::RequestHead;
+use actix_web::http::header::{HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING, UPGRADE};
+use actix_web::http::StatusCode;
+use actix_web::web::Payload;
+use actix_web::{HttpRequest, HttpResponse};
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use log::{debug, warn};
+use rustls::{ClientConfig, ServerName};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
+use tokio::time::{timeout, timeout_at, Instant};
+use tokio_rustls::TlsConnector;
+use url::Url;
+
+/// Largest accepted response head of the target.
+const MAX_HEAD_SIZE: usize = 16 * 1024;
+const MAX_HEADERS: usize = 64;
+
+/// Timeouts of a single tunnel.
+pub(super) struct TunnelTimeouts {
+    pub(super) connect: Duration,
+    /// Max time to wait for target's handshake response.
+    pub(super) read: Duration,
+    /// Tunnel is closed when no data goes in either direction for this long.
+    pub(super) idle: Duration,
+}
+
+pub(super) fn is_websocket_upgrade(request: &HttpRequest) -> bool {
+    request.head().upgrade() && request.headers().get(UPGRADE).is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"))
+}
+
+/// Sends the handshake to the target and, if accepted, tunnels the connection.
+pub(super) async fn open(
+    payload: Payload,
+    head: &RequestHead,
+    destination: &Url,
+    timeouts: TunnelTimeouts,
+    connector: &TunnelConnector,
+    slot: TunnelSlot,
+) -> Result<HttpResponse, StatusCode> {
+    let mut stream = match timeout(timeouts.connect, connector.connect(destination)).await {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(e)) => {
+            warn!("Unable to connect to {}: {}", destination, e);
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+        Err(_) => return Err(StatusCode::GATEWAY_TIMEOUT),
+    };
+    stream.write_all(&request_head(head, destination)).await.map_err(|_| StatusCode::BAD_GATEWAY)?;
+    let (response, rest) = match timeout(timeouts.read, read_response_head(&mut stream)).await {
+        Ok(Ok(response)) => response,
+        Ok(Err(e)) => {
+            warn!("Invalid handshake response from {}: {}", destination, e);
+            return Err(StatusCode::BAD_GATEWAY);
+        }
+        Err(_) => return Err(StatusCode::GATEWAY_TIMEOUT),
+    };
+    if response.status != StatusCode::SWITCHING_PROTOCOLS {
+        debug!("{} refused upgrade with {}", destination, response.status);
+        return Ok(refused(response, rest, stream, timeouts.read).await);
+    }
+    debug!("Tunnelling to {}", destination);
+    let mut builder = HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS);
+    builder.upgrade("websocket");
+    for (name, value) in response.headers.into_iter().filter(|(name, _)| name != CONNECTION && name != UPGRADE) {
+        builder.append_header((name, value));
+    }
+    let (read, write) = tokio::io::split(stream);
+    let activity = Rc::new(Cell::new(Instant::now()));
+    actix_web::rt::spawn(copy_to_target(payload, write, activity.clone(), timeouts.idle));
+    Ok(builder.streaming(copy_from_target(rest, read, activity, timeouts.idle, slot)))
+}
+
+/// Connection to a target, plain or TLS.
+trait TunnelStream: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> TunnelStream for T {}
+
+/// Opens tunnels' connections with the same resolver (and so DNS cache) as the worker's [awc::Client]s and
+/// limits their number per target the way the clients limit pooled connections.
+pub(super) struct TunnelConnector {
+    resolver: Resolver,
+    tls: TlsConnector,
+    /// Open tunnels by [crate::proxy::upstream::UpstreamPolicy::targets] key, `""` for other targets.
+    open: Rc<RefCell<HashMap<String, usize>>>,
+}
+
+impl TunnelConnector {
+    pub(super) fn new(resolver: Resolver) -> Self {
+        let config = ClientConfig::builder().with_safe_defaults().with_root_certificates(webpki_roots_cert_store()).with_no_client_auth();
+        Self { resolver, tls: TlsConnector::from(Arc::new(config)), open: Rc::default() }
+    }
+
+    /// Reserves a tunnel of the target, `None` if it already has `max` open ones.
+    pub(super) fn reserve(&self, key: String, max: usize) -> Option<TunnelSlot> {
+        let mut open = self.open.borrow_mut();
+        let count = open.entry(key.clone()).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(TunnelSlot { key, open: self.open.clone() })
+    }
+
+    /// `http` targets get plain connections, `https` ones TLS.
+    async fn connect(&self, destination: &Url) -> std::io::Result<Box<dyn TunnelStream>> {
+        let (Some(host), Some(port)) = (destination.host_str(), destination.port_or_known_default()) else {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "no host"));
+        };
+        let connection = TcpConnector::new(self.resolver.clone())
+            .service()
+            .call(ConnectInfo::new(host.to_string()).set_port(port))
+            .await
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        let (stream, _) = connection.into_parts();
+        match destination.scheme() {
+            "http" => Ok(Box::new(stream)),
+            "https" => {
+                let name = ServerName::try_from(host).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                Ok(Box::new(self.tls.connect(name, stream).await?))
+            }
+            scheme => Err(std::io::Error::new(std::io::ErrorKind::Unsupported, format!("unsupported scheme {}", scheme))),
+        }
+    }
+}
+
+/// Open tunnel counted by [TunnelConnector], released when dropped.
+pub(super) struct TunnelSlot {
+    key: String,
+    open: Rc<RefCell<HashMap<String, usize>>>,
+}
+
+impl Drop for TunnelSlot {
+    fn drop(&mut self) {
+        if let Some(count) = self.open.borrow_mut().get_mut(&self.key) {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// Request line and headers as received from the client (including `Host`).
+fn request_head(head: &RequestHead, destination: &Url) -> Vec<u8> {
+    let path = match destination.query() {
+        Some(query) => format!("{}?{}", destination.path(), query),
+        None => destination.path().to_string(),
+    };
+    let mut request = format!("{} {} HTTP/1.1\r\n", head.method, path).into_bytes();
+    for (name, value) in head.headers.iter().filter(|(name, _)| *name != CONNECTION) {
+        request.extend_from_slice(name.as_str().as_bytes());
+        request.extend_from_slice(b": ");
+        request.extend_from_slice(value.as_bytes());
+        request.extend_from_slice(b"\r\n");
+    }
+    request.extend_from_slice(b"connection: upgrade\r\n\r\n");
+    request
+}
+
+pub(super) struct ResponseHead {
+    pub(super) status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+/// Reads target's response head. Returns it with bytes that came after it.
+async fn read_response_head(stream: &mut (impl AsyncRead + Unpin)) -> std::io::Result<(ResponseHead, BytesMut)> {
+    let mut buffer = BytesMut::with_capacity(1024);
+    loop {
+        if stream.read_buf(&mut buffer).await? == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        let mut headers = [httparse::EMPTY_HEADER; MAX_HEADERS];
+        let mut response = httparse::Response::new(&mut headers);
+        let status = response.parse(&buffer).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?;
+        if let httparse::Status::Complete(length) = status {
+            let head = ResponseHead {
+                status: response.code.and_then(|code| StatusCode::from_u16(code).ok()).unwrap_or(StatusCode::BAD_GATEWAY),
+                headers: response
+                    .headers
+                    .iter()
+                    .filter_map(|h| Some((HeaderName::from_bytes(h.name.as_bytes()).ok()?, HeaderValue::from_bytes(h.value).ok()?)))
+                    .collect(),
+            };
+            return Ok((head, buffer.split_off(length)));
+        }
+        if buffer.len() >= MAX_HEAD_SIZE {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "response head too large"));
+        }
+    }
+}
+
+/// Passes target's refusal (e.g. 401 or 404) to the client, with body up to `Content-Length`.
+async fn refused(response: ResponseHead, mut body: BytesMut, mut stream: Box<dyn TunnelStream>, read_timeout: Duration) -> HttpResponse {
+    let length = response.headers.iter().find(|(name, _)| name == CONTENT_LENGTH).and_then(|(_, v)| v.to_str().ok()?.parse::<usize>().ok());
+    if let Some(length) = length.filter(|l| *l <= MAX_HEAD_SIZE) {
+        while body.len() < length {
+            match timeout(read_timeout, stream.read_buf(&mut body)).await {
+                Ok(Ok(read)) if read > 0 => {}
+                _ => break,
+            }
+        }
+        body.truncate(length);
+    } else {
+        body.clear();
+    }
+    let mut builder = HttpResponse::build(response.status);
+    // Body is sent as read, chunked bodies are dropped, so target's framing headers don't apply to it.
+    for (name, value) in
+        response.headers.into_iter().filter(|(name, _)| name != CONNECTION && name != CONTENT_LENGTH && name != TRANSFER_ENCODING)
+    {
+        builder.append_header((name, value));
+    }
+    builder.body(body.freeze())
+}
+
+/// Copies client's data to the target. Closes target's side when the client is done or the tunnel is idle.
+async fn copy_to_target(mut payload: Payload, mut write: WriteHalf<Box<dyn TunnelStream>>, activity: Rc<Cell<Instant>>, idle: Duration) {
+    loop {
+        match timeout_at(activity.get() + idle, payload.next()).await {
+            Ok(Some(Ok(bytes))) => {
+                activity.set(Instant::now());
+                if write.write_all(&bytes).await.is_err() {
+                    break;
+                }
+            }
+            Ok(_) => break,
+            // The other direction might have been active in the meantime.
+            Err(_) if activity.get().elapsed() < idle => {}
+            Err(_) => {
+                debug!("Closing idle tunnel");
+                break;
+            }
+        }
+    }
+    let _ = write.shutdown().await;
+}
+
+/// Streams target's data to the client, starting with bytes read together with the handshake response.
+/// The tunnel's slot is released when the stream is dropped.
+fn copy_from_target(
+    rest: BytesMut,
+    read: ReadHalf<Box<dyn TunnelStream>>,
+    activity: Rc<Cell<Instant>>,
+    idle: Duration,
+    slot: TunnelSlot,
+) -> impl futures::Stream<Item = Result<Bytes, std::io::Error>> {
+    let first = (!rest.is_empty()).then(|| Ok(rest.freeze()));
+    let rest = futures::stream::unfold((read, activity, slot), move |(mut read, activity, slot)| async move {
+        loop {
+            let mut buffer = BytesMut::with_capacity(8 * 1024);
+            match timeout_at(activity.get() + idle, read.read_buf(&mut buffer)).await {
+                Ok(Ok(0)) | Ok(Err(_)) => return None,
+                Ok(Ok(_)) => {
+                    activity.set(Instant::now());
+                    return Some((Ok(buffer.freeze()), (read, activity, slot)));
+                }
+                Err(_) if activity.get().elapsed() < idle => {}
+                Err(_) => return None,
+            }
+        }
+    });
+    futures::stream::iter(first).chain(rest)
+}
+
+#[cfg(test)]
+mod test {
+    use actix_tls::connect::Resolver;
+    use actix_web::http::header::{HeaderValue, CONNECTION, HOST, UPGRADE};
+    use actix_web::test::TestRequest;
+    use url::Url;
+
+    use crate::proxy::tunnel::{is_websocket_upgrade, request_head, TunnelConnector};
+
+    #[test]
+    fn detects_websocket_upgrades() {
+        let request = TestRequest::default().insert_header((CONNECTION, "Upgrade")).insert_header((UPGRADE, "WebSocket")).to_http_request();
+        assert!(is_websocket_upgrade(&request));
+        let request = TestRequest::default().insert_header((CONNECTION, "Upgrade")).insert_header((UPGRADE, "h2c")).to_http_request();
+        assert!(!is_websocket_upgrade(&request));
+        assert!(!is_websocket_upgrade(&TestRequest::default().insert_header((UPGRADE, "websocket")).to_http_request()));
+    }
+
+    #[test]
+    fn writes_request_head() {
+        let mut request = TestRequest::default().to_http_request().head().clone();
+        request.headers.insert(HOST, HeaderValue::from_static("qpackt.com"));
+        request.headers.insert(UPGRADE, HeaderValue::from_static("websocket"));
+        let head = request_head(&request, &Url::parse("http://localhost:9998/ws/echo?a=1").unwrap());
+        let head = String::from_utf8(head).unwrap();
+        assert!(head.starts_with("GET /ws/echo?a=1 HTTP/1.1\r\n"));
+        assert!(head.contains("\r\nhost: qpackt.com\r\n"));
+        assert!(head.contains("\r\nupgrade: websocket\r\n"));
+        assert!(head.ends_with("\r\nconnection: upgrade\r\n\r\n"));
+    }
+
+    #[test]
+    fn limits_open_tunnels() {
+        let connector = TunnelConnector::new(Resolver::default());
+        let first = connector.reserve("a:80".into(), 1).unwrap();
+        assert!(connector.reserve("a:80".into(), 1).is_none());
+        assert!(connector.reserve("b:80".into(), 1).is_some());
+        drop(first);
+        assert!(connector.reserve("a:80".into(), 1).is_some());
+    }
+}
@@ -0,0 +1,353 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Sending requests to [crate::reverse_proxy::ReverseProxy] targets: connection pooling, timeouts, retries
+//! and mapping failures to responses.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix_tls::connect::{Connector as TcpConnector, Resolve, Resolver};
+use actix_web::dev::RequestHead;
+use actix_web::http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use actix_web::http::{Method, StatusCode};
+use actix_web::web::Payload;
+use actix_web::HttpResponse;
+use awc::error::{ConnectError, SendRequestError};
+use awc::{Client, ClientResponse, Connector};
+use futures::future::LocalBoxFuture;
+use log::{debug, warn};
+use url::Url;
+
+use crate::proxy::tunnel;
+use crate::proxy::tunnel::{TunnelConnector, TunnelTimeouts};
+
+/// Largest request body that is buffered so that the request can be retried.
+const MAX_RETRY_BODY_SIZE: usize = 1024 * 1024;
+
+/// Timeouts, retries and connection pool settings for upstream requests, configured in qpackt.yaml.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct UpstreamPolicy {
+    /// Max time to establish connection (including DNS resolution).
+    pub(crate) connect_timeout: Duration,
+    /// Max time to get response headers: connecting, sending the request and waiting for the answer. WebSocket tunnels
+    /// wait this long for the upgrade response after connecting.
+    pub(crate) read_timeout: Duration,
+    /// How many times idempotent requests are repeated after a failure.
+    pub(crate) retries: u32,
+    /// Max number of simultaneous connections of a worker.
+    pub(crate) max_connections: usize,
+    /// How long idle connections are kept open for reuse.
+    pub(crate) keep_alive: Duration,
+    /// How long a WebSocket tunnel may stay without traffic in any direction.
+    pub(crate) idle_timeout: Duration,
+    /// How long resolved addresses are reused. Zero turns off caching.
+    pub(crate) dns_cache_ttl: Duration,
+    /// Settings overriding the ones above for particular targets, by `host:port`.
+    pub(crate) targets: BTreeMap<String, TargetPolicy>,
+}
+
+/// Connector settings of a single target. Missing values are taken from [UpstreamPolicy].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct TargetPolicy {
+    pub(crate) connect_timeout: Option<Duration>,
+    pub(crate) read_timeout: Option<Duration>,
+    pub(crate) max_connections: Option<usize>,
+    pub(crate) keep_alive: Option<Duration>,
+}
+
+impl Default for UpstreamPolicy {
+    fn default() -> Self {
+        Self {
+            connect_timeout: Duration::from_secs(5),
+            read_timeout: Duration::from_secs(5),
+            retries: 1,
+            max_connections: 100,
+            keep_alive: Duration::from_secs(15),
+            idle_timeout: Duration::from_secs(300),
+            dns_cache_ttl: Duration::from_secs(60),
+            targets: BTreeMap::new(),
+        }
+    }
+}
+
+impl UpstreamPolicy {
+    /// Builds clients for a single worker. `awc::Client` can't be shared between threads,
+    /// so every worker keeps its own connection pools and DNS cache.
+    pub(crate) fn build_client(&self) -> UpstreamClient {
+        let resolver =
+            if self.dns_cache_ttl.is_zero() { Resolver::default() } else { Resolver::custom(CachingResolver::new(self.dns_cache_ttl)) };
+        let client = |target: &TargetPolicy| {
+            let connector = Connector::new()
+                .connector(TcpConnector::new(resolver.clone()).service())
+                .timeout(target.connect_timeout.unwrap_or(self.connect_timeout))
+                .limit(target.max_connections.unwrap_or(self.max_connections))
+                .conn_keep_alive(target.keep_alive.unwrap_or(self.keep_alive));
+            Client::builder().connector(connector).timeout(target.read_timeout.unwrap_or(self.read_timeout)).finish()
+        };
+        UpstreamClient {
+            policy: self.clone(),
+            default: client(&TargetPolicy::default()),
+            targets: self.targets.iter().map(|(target, policy)| (target.clone(), client(policy))).collect(),
+            tunnels: TunnelConnector::new(resolver),
+        }
+    }
+}
+
+/// Pooled clients of a single worker, see [UpstreamPolicy::build_client].
+pub(crate) struct UpstreamClient {
+    policy: UpstreamPolicy,
+    default: Client,
+    targets: HashMap<String, Client>,
+    tunnels: TunnelConnector,
+}
+
+impl UpstreamClient {
+    /// Sends request upstream. Idempotent requests with no or small body are retried on failure,
+    /// everything else is streamed once. Failures are returned as status to respond with.
+    pub(crate) async fn send(
+        &self,
+        payload: Payload,
+        head: &RequestHead,
+        destination: &Url,
+    ) -> Result<ClientResponse<impl futures::Stream<Item = Result<bytes::Bytes, awc::error::PayloadError>>>, StatusCode> {
+        self.request(Some(payload), head, destination).await
+    }
+
+    /// Sends a request without body (e.g. cache revalidation), retrying like [UpstreamClient::send].
+    pub(crate) async fn fetch(
+        &self,
+        head: &RequestHead,
+        destination: &Url,
+    ) -> Result<ClientResponse<impl futures::Stream<Item = Result<bytes::Bytes, awc::error::PayloadError>>>, StatusCode> {
+        self.request(None, head, destination).await
+    }
+
+    async fn request(
+        &self,
+        payload: Option<Payload>,
+        head: &RequestHead,
+        destination: &Url,
+    ) -> Result<ClientResponse<impl futures::Stream<Item = Result<bytes::Bytes, awc::error::PayloadError>>>, StatusCode> {
+        let client = self.client_for(destination);
+        let body = match payload {
+            Some(payload) if self.policy.retries == 0 || !is_retryable(head) => {
+                let request = client.request_from(destination.as_str(), head).no_decompress();
+                return request.send_stream(payload).await.map_err(|e| failure_status(destination, &e));
+            }
+            Some(payload) => match payload.to_bytes_limited(MAX_RETRY_BODY_SIZE).await {
+                Ok(Ok(body)) => body,
+                _ => {
+                    warn!("Unable to read request body for {}", destination);
+                    return Err(StatusCode::BAD_REQUEST);
+                }
+            },
+            None => bytes::Bytes::new(),
+        };
+        let mut attempt = 0;
+        loop {
+            match client.request_from(destination.as_str(), head).no_decompress().send_body(body.clone()).await {
+                Ok(response) => return Ok(response),
+                Err(e) if attempt < self.policy.retries => {
+                    attempt += 1;
+                    warn!("Request to {} failed: {}, retrying ({}/{})", destination, e, attempt, self.policy.retries);
+                }
+                Err(e) => return Err(failure_status(destination, &e)),
+            }
+        }
+    }
+
+    /// Tunnels a WebSocket connection to the destination, see [crate::proxy::tunnel]. Open tunnels are limited
+    /// by `max_connections` too, separately from pooled connections.
+    pub(crate) async fn tunnel(&self, payload: Payload, head: &RequestHead, destination: &Url) -> Result<HttpResponse, StatusCode> {
+        let key = target_key(destination).filter(|key| self.policy.targets.contains_key(key));
+        let target = key.as_ref().and_then(|key| self.policy.targets.get(key));
+        let timeouts = TunnelTimeouts {
+            connect: target.and_then(|t| t.connect_timeout).unwrap_or(self.policy.connect_timeout),
+            read: target.and_then(|t| t.read_timeout).unwrap_or(self.policy.read_timeout),
+            idle: self.policy.idle_timeout,
+        };
+        let max_connections = target.and_then(|t| t.max_connections).unwrap_or(self.policy.max_connections);
+        let Some(slot) = self.tunnels.reserve(key.unwrap_or_default(), max_connections) else {
+            warn!("Too many tunnels to {}", destination);
+            return Err(StatusCode::SERVICE_UNAVAILABLE);
+        };
+        tunnel::open(payload, head, destination, timeouts, &self.tunnels, slot).await
+    }
+
+    fn client_for(&self, destination: &Url) -> &Client {
+        target_key(destination).and_then(|key| self.targets.get(&key)).unwrap_or(&self.default)
+    }
+}
+
+/// `host:port` of the destination, as used in [UpstreamPolicy::targets].
+fn target_key(destination: &Url) -> Option<String> {
+    Some(format!("{}:{}", destination.host_str()?, destination.port_or_known_default()?))
+}
+
+/// Addresses of a host with the time they were resolved at.
+type ResolvedAddresses = (Instant, Vec<SocketAddr>);
+
+/// Resolves target hosts and reuses the addresses for `ttl`, instead of asking the system resolver on every new connection.
+#[derive(Clone)]
+struct CachingResolver {
+    ttl: Duration,
+    cache: Rc<RefCell<HashMap<(String, u16), ResolvedAddresses>>>,
+}
+
+impl CachingResolver {
+    fn new(ttl: Duration) -> Self {
+        Self { ttl, cache: Rc::default() }
+    }
+
+    fn cached(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+        let cache = self.cache.borrow();
+        let (time, addresses) = cache.get(&(host.to_string(), port))?;
+        (time.elapsed() < self.ttl).then(|| addresses.clone())
+    }
+}
+
+impl Resolve for CachingResolver {
+    fn lookup<'a>(&'a self, host: &'a str, port: u16) -> LocalBoxFuture<'a, Result<Vec<SocketAddr>, Box<dyn Error>>> {
+        Box::pin(async move {
+            if let Some(addresses) = self.cached(host, port) {
+                return Ok(addresses);
+            }
+            let addresses = tokio::net::lookup_host((host, port)).await?.collect::<Vec<_>>();
+            debug!("Resolved {}:{} to {:?}", host, port, addresses);
+            self.cache.borrow_mut().insert((host.to_string(), port), (Instant::now(), addresses.clone()));
+            Ok(addresses)
+        })
+    }
+}
+
+/// Only idempotent methods are retried, and only when their body is known to be small enough to buffer.
+fn is_retryable(head: &RequestHead) -> bool {
+    if !matches!(head.method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE | Method::PUT | Method::DELETE) {
+        return false;
+    }
+    match head.headers.get(CONTENT_LENGTH) {
+        Some(length) => length.to_str().ok().and_then(|l| l.parse::<usize>().ok()).is_some_and(|l| l <= MAX_RETRY_BODY_SIZE),
+        None => !head.headers.contains_key(TRANSFER_ENCODING),
+    }
+}
+
+/// Timeouts are reported as 504 Gateway Timeout, other failures as 502 Bad Gateway.
+fn failure_status(destination: &Url, error: &SendRequestError) -> StatusCode {
+    warn!("Request to {} failed: {}", destination, error);
+    match error {
+        SendRequestError::Timeout | SendRequestError::Connect(ConnectError::Timeout) => StatusCode::GATEWAY_TIMEOUT,
+        _ => StatusCode::BAD_GATEWAY,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use actix_tls::connect::Resolve;
+    use actix_web::dev::RequestHead;
+    use actix_web::http::header::{HeaderValue, CONTENT_LENGTH, TRANSFER_ENCODING};
+    use actix_web::http::Method;
+    use actix_web::{web, App, HttpResponse, HttpServer};
+    use awc::error::{ConnectError, SendRequestError};
+    use awc::http::StatusCode;
+    use awc::Client;
+    use url::Url;
+
+    use crate::proxy::upstream::{failure_status, is_retryable, target_key, CachingResolver, TargetPolicy, UpstreamPolicy};
+
+    fn head(method: Method, header: Option<(actix_web::http::header::HeaderName, &'static str)>) -> RequestHead {
+        let mut head = RequestHead::default();
+        head.method = method;
+        if let Some((name, value)) = header {
+            head.headers.insert(name, HeaderValue::from_static(value));
+        }
+        head
+    }
+
+    #[test]
+    fn retries_only_idempotent_small_requests() {
+        assert!(is_retryable(&head(Method::GET, None)));
+        assert!(is_retryable(&head(Method::PUT, Some((CONTENT_LENGTH, "100")))));
+        assert!(!is_retryable(&head(Method::POST, None)));
+        assert!(!is_retryable(&head(Method::PUT, Some((CONTENT_LENGTH, "100000000")))));
+        assert!(!is_retryable(&head(Method::PUT, Some((TRANSFER_ENCODING, "chunked")))));
+    }
+
+    #[test]
+    fn maps_failures_to_statuses() {
+        let url = Url::parse("http://localhost:1").unwrap();
+        assert_eq!(failure_status(&url, &SendRequestError::Timeout), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(failure_status(&url, &SendRequestError::Connect(ConnectError::Timeout)), StatusCode::GATEWAY_TIMEOUT);
+        assert_eq!(failure_status(&url, &SendRequestError::Connect(ConnectError::NoRecords)), StatusCode::BAD_GATEWAY);
+    }
+
+    #[test]
+    fn picks_client_by_target() {
+        assert_eq!(target_key(&Url::parse("http://localhost:8080/api").unwrap()).unwrap(), "localhost:8080");
+        assert_eq!(target_key(&Url::parse("https://qpackt.com").unwrap()).unwrap(), "qpackt.com:443");
+    }
+
+    #[actix_web::test]
+    async fn caches_resolved_addresses() {
+        let resolver = CachingResolver::new(Duration::from_secs(60));
+        let addresses = resolver.lookup("localhost", 80).await.unwrap();
+        assert!(!addresses.is_empty());
+        assert_eq!(resolver.cached("localhost", 80).unwrap(), addresses);
+        assert!(CachingResolver::new(Duration::ZERO).cached("localhost", 80).is_none());
+    }
+
+    /// Compares a new client per request (as before pooling) with a shared one.
+    /// Run with `cargo test upstream_throughput -- --ignored --nocapture`.
+    #[actix_web::test]
+    #[ignore]
+    async fn upstream_throughput() {
+        const REQUESTS: u32 = 2_000;
+        let server = HttpServer::new(|| App::new().default_service(web::to(|| async { HttpResponse::Ok().body("ok") })))
+            .workers(1)
+            .bind("127.0.0.1:0")
+            .unwrap();
+        let url = Url::parse(&format!("http://{}/", server.addrs()[0])).unwrap();
+        actix_web::rt::spawn(server.run());
+        let head = RequestHead::default();
+
+        let start = Instant::now();
+        for _ in 0..REQUESTS {
+            Client::default().request_from(url.as_str(), &head).send().await.unwrap().body().await.unwrap();
+        }
+        let unpooled = REQUESTS as f64 / start.elapsed().as_secs_f64();
+
+        let mut policy = UpstreamPolicy::default();
+        policy.targets.insert(target_key(&url).unwrap(), TargetPolicy { max_connections: Some(1), ..Default::default() });
+        let client = policy.build_client().client_for(&url).clone();
+        let start = Instant::now();
+        for _ in 0..REQUESTS {
+            client.request_from(url.as_str(), &head).send().await.unwrap().body().await.unwrap();
+        }
+        let pooled = REQUESTS as f64 / start.elapsed().as_secs_f64();
+
+        println!("new client per request: {:.0} req/s, shared client: {:.0} req/s ({:.1}x)", unpooled, pooled, pooled / unpooled);
+        assert!(pooled > unpooled);
+    }
+}
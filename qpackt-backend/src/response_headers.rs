@@ -0,0 +1,252 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Custom headers of versions' files, e.g. `Content-Security-Policy`. Headers are layered: global ones from
+//! qpackt.yaml, then version's `_headers` file (Netlify's format), then version's [crate::server::ServingProfile],
+//! each layer replacing headers of the same name set by the previous one. A variant can try a new CSP this way.
+//! `Vary` keeps `cookie` (and `accept-encoding`) that qpackt needs, they are appended to custom values.
+
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use log::warn;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::cache_rules::glob_regex;
+use crate::error::{QpacktError, Result};
+
+/// Common sets of security headers.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HeaderPreset {
+    /// Safe for any site: no MIME sniffing, no framing by other sites, origin only in cross-origin referrers.
+    Basic,
+    /// [HeaderPreset::Basic] with HSTS, no framing at all, no referrers and a CSP allowing only same-origin resources.
+    Strict,
+}
+
+impl HeaderPreset {
+    fn headers(&self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            HeaderPreset::Basic => &[
+                ("x-content-type-options", "nosniff"),
+                ("x-frame-options", "SAMEORIGIN"),
+                ("referrer-policy", "strict-origin-when-cross-origin"),
+            ],
+            HeaderPreset::Strict => &[
+                ("x-content-type-options", "nosniff"),
+                ("x-frame-options", "DENY"),
+                ("referrer-policy", "no-referrer"),
+                ("strict-transport-security", "max-age=31536000; includeSubDomains"),
+                ("content-security-policy", "default-src 'self'; base-uri 'self'; object-src 'none'; frame-ancestors 'none'"),
+            ],
+        }
+    }
+}
+
+impl Display for HeaderPreset {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HeaderPreset::Basic => write!(f, "basic"),
+            HeaderPreset::Strict => write!(f, "strict"),
+        }
+    }
+}
+
+impl FromStr for HeaderPreset {
+    type Err = QpacktError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "basic" => Ok(HeaderPreset::Basic),
+            "strict" => Ok(HeaderPreset::Strict),
+            _ => Err(QpacktError::InvalidConfig(format!("Unknown header preset `{}`", s))),
+        }
+    }
+}
+
+/// Headers of paths matching the glob, same globs as in [crate::cache_rules::CacheRule].
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct HeaderRule {
+    pub(crate) pattern: String,
+    pub(crate) headers: BTreeMap<String, String>,
+}
+
+impl HeaderRule {
+    pub(crate) fn validate(&self) -> Result<()> {
+        glob_regex(&self.pattern)?;
+        for (name, value) in &self.headers {
+            parse_header(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+/// Preset and rules of one layer. Rules are applied after the preset, so they can override its headers.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct ResponseHeaders {
+    pub(crate) preset: Option<HeaderPreset>,
+    pub(crate) rules: Vec<HeaderRule>,
+}
+
+impl ResponseHeaders {
+    pub(crate) fn validate(&self) -> Result<()> {
+        for rule in &self.rules {
+            rule.validate()?;
+        }
+        Ok(())
+    }
+}
+
+type Headers = Vec<(HeaderName, HeaderValue)>;
+
+/// Compiled [ResponseHeaders] layers, in order of application. Presets have no glob.
+#[derive(Debug, Default)]
+pub(crate) struct HeaderRules(Vec<(Option<Regex>, bool, Headers)>);
+
+impl HeaderRules {
+    /// Invalid rules (rejected when saved anyway) are skipped.
+    pub(crate) fn new(layers: &[&ResponseHeaders]) -> Self {
+        let mut compiled = Vec::new();
+        for layer in layers {
+            if let Some(preset) = layer.preset {
+                let headers =
+                    preset.headers().iter().map(|(name, value)| (HeaderName::from_static(name), HeaderValue::from_static(value))).collect();
+                compiled.push((None, false, headers));
+            }
+            for rule in &layer.rules {
+                let headers = rule.headers.iter().map(|(name, value)| parse_header(name, value)).collect::<Result<Vec<_>>>();
+                match (glob_regex(&rule.pattern), headers) {
+                    (Ok(regex), Ok(headers)) => compiled.push((Some(regex), !rule.pattern.trim_start_matches('/').contains('/'), headers)),
+                    _ => warn!("Skipping invalid header rule {:?}", rule),
+                }
+            }
+        }
+        Self(compiled)
+    }
+
+    /// Sets headers of a served file (path inside web root), later rules replace headers set by earlier ones.
+    pub(crate) fn apply(&self, path: &str, headers: &mut HeaderMap) {
+        let path = path.trim_start_matches('/');
+        let name = path.rsplit('/').next().unwrap_or(path);
+        for (regex, by_name, values) in &self.0 {
+            if regex.as_ref().is_none_or(|regex| regex.is_match(if *by_name { name } else { path })) {
+                for (name, value) in values {
+                    headers.insert(name.clone(), value.clone());
+                }
+            }
+        }
+    }
+}
+
+fn parse_header(name: &str, value: &str) -> Result<(HeaderName, HeaderValue)> {
+    let name = HeaderName::from_str(name).map_err(|_| QpacktError::InvalidRequest(format!("Invalid header name `{}`", name)))?;
+    let value = HeaderValue::from_str(value).map_err(|_| QpacktError::InvalidRequest(format!("Invalid value of header `{}`", name)))?;
+    Ok((name, value))
+}
+
+/// Parses a `_headers` file: a path on its own line followed by indented `Name: value` lines, `#` starts a comment.
+/// Netlify's trailing splat (`/assets/*`) matches everything below the directory. Invalid lines are skipped.
+/// Unlike on Netlify, paths are matched against served files, so `/index.html` covers `/` as well.
+pub(crate) fn parse_headers_file(content: &str) -> Vec<HeaderRule> {
+    let mut rules: Vec<HeaderRule> = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let trimmed = line.trim();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        if !line.starts_with(char::is_whitespace) {
+            let pattern = match trimmed.strip_suffix("/*") {
+                Some(directory) => format!("{}/**", directory),
+                None => trimmed.to_string(),
+            };
+            rules.push(HeaderRule { pattern, headers: BTreeMap::new() });
+            continue;
+        }
+        match (rules.last_mut(), trimmed.split_once(':')) {
+            (Some(rule), Some((name, value))) => {
+                rule.headers.insert(name.trim().to_string(), value.trim().to_string());
+            }
+            _ => warn!("Skipping invalid line {} of _headers: {}", number + 1, line),
+        }
+    }
+    rules.retain(|rule| match rule.validate() {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("Skipping _headers rule for `{}`: {}", rule.pattern, e);
+            false
+        }
+    });
+    rules
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+
+    use actix_web::http::header::HeaderMap;
+
+    use crate::response_headers::{parse_headers_file, HeaderPreset, HeaderRule, HeaderRules, ResponseHeaders};
+
+    fn headers(rules: &HeaderRules, path: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        rules.apply(path, &mut headers);
+        headers
+    }
+
+    #[test]
+    fn layers_presets_and_rules() {
+        let global = ResponseHeaders { preset: Some(HeaderPreset::Basic), rules: vec![] };
+        let csp = BTreeMap::from([("Content-Security-Policy".to_string(), "default-src 'none'".to_string())]);
+        let version = ResponseHeaders {
+            preset: None,
+            rules: vec![
+                HeaderRule { pattern: "*.html".into(), headers: csp },
+                HeaderRule { pattern: "admin/**".into(), headers: BTreeMap::from([("X-Frame-Options".to_string(), "DENY".to_string())]) },
+            ],
+        };
+        let rules = HeaderRules::new(&[&global, &version]);
+        let index = headers(&rules, "/docs/index.html");
+        assert_eq!(index.get("content-security-policy").unwrap(), "default-src 'none'");
+        assert_eq!(index.get("x-frame-options").unwrap(), "SAMEORIGIN");
+        let admin = headers(&rules, "/admin/users");
+        assert_eq!(admin.get("x-frame-options").unwrap(), "DENY");
+        assert!(admin.get("content-security-policy").is_none());
+        assert!(HeaderRule { pattern: "*".into(), headers: BTreeMap::from([("bad name".to_string(), "x".to_string())]) }
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn parses_headers_files() {
+        let rules = parse_headers_file(
+            "# comment\n/*\n  X-Frame-Options: DENY\n  Referrer-Policy: no-referrer\n\n/assets/*\n  Cache-Control: immutable\n/bad\n  Bad Header: x\n",
+        );
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].pattern, "/**");
+        assert_eq!(rules[0].headers.len(), 2);
+        let rules = HeaderRules::new(&[&ResponseHeaders { preset: None, rules }]);
+        assert_eq!(headers(&rules, "/assets/js/app.js").get("cache-control").unwrap(), "immutable");
+        assert_eq!(headers(&rules, "/").get("x-frame-options").unwrap(), "DENY");
+    }
+}
@@ -17,15 +17,414 @@
    along with this program.  If not, see <https://www.gnu.org/licenses/>.
 */
 
-use actix_web::http::Uri;
+//! Reverse proxies: requests matching proxy's rule are sent to a pool of targets. Targets failing health checks
+//! or failing too many requests in a row get no traffic until they recover.
+
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+use actix_web::http::Method;
+use actix_web::HttpResponse;
 use arc_swap::ArcSwap;
+use bytes::Bytes;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::fmt::{Display, Formatter};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::dao::version::VersionName;
+use crate::error::QpacktError;
+use crate::manager::assignment::salted_hash;
+
+/// How long a target gets no traffic after too many failures in a row.
+const EJECTION_TIME: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 pub(crate) struct ReverseProxy {
     pub(crate) id: i32,
+    /// Path prefix, matches whole segments only: `/api` matches `/api` and `/api/x` but not `/apiv2`.
+    /// Stripped from the path sent to a target. Empty prefix matches all paths.
     pub(crate) prefix: String,
-    pub(crate) target: String,
+    pub(crate) matcher: Matcher,
+    pub(crate) headers: ProxyHeaders,
+    pub(crate) cache: ProxyCache,
+    pub(crate) targets: Vec<Target>,
+    pub(crate) balancing: Balancing,
+    /// Path (e.g. `/health`) checked periodically on every target. No active checks if missing.
+    pub(crate) health_check_path: Option<String>,
+    /// Failures in a row after which a target is ejected for [EJECTION_TIME].
+    pub(crate) max_failures: u32,
+    next: Arc<AtomicUsize>,
+}
+
+/// Additional conditions of a [ReverseProxy] rule. Missing ones match every request.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Matcher {
+    /// Host without port, `*.qpackt.com` matches all subdomains of `qpackt.com`.
+    pub(crate) host: Option<String>,
+    /// Empty matches all methods.
+    pub(crate) methods: Vec<Method>,
+    /// Used instead of the prefix if set. Path is sent to a target unchanged, unless there is a `rewrite`.
+    pub(crate) path_regex: Option<Regex>,
+    /// Path sent to a target for `path_regex` rules, `$1` or `$name` are replaced with capture groups.
+    pub(crate) rewrite: Option<String>,
+    /// Rules with higher priority are checked first, then the more specific ones, see [ReverseProxy::specificity].
+    pub(crate) priority: i32,
+}
+
+impl Matcher {
+    pub(crate) fn new(host: Option<String>, methods: &str, path_regex: Option<&str>, rewrite: Option<String>, priority: i32) -> Result<Self, QpacktError> {
+        let methods = methods
+            .split(',')
+            .map(str::trim)
+            .filter(|m| !m.is_empty())
+            .map(|m| Method::from_str(&m.to_ascii_uppercase()).map_err(|_| QpacktError::InvalidRequest(format!("Invalid method `{}`", m))))
+            .collect::<Result<Vec<_>, _>>()?;
+        let path_regex = path_regex
+            .map(|r| Regex::new(r).map_err(|e| QpacktError::InvalidRequest(format!("Invalid path regex `{}`: {}", r, e))))
+            .transpose()?;
+        if rewrite.is_some() && path_regex.is_none() {
+            return Err(QpacktError::InvalidRequest("Rewrite needs a path regex".into()));
+        }
+        Ok(Self { host: host.map(|h| h.to_ascii_lowercase()), methods, path_regex, rewrite, priority })
+    }
+
+    /// Methods as stored in the database, e.g. `GET,POST`.
+    pub(crate) fn methods_string(&self) -> String {
+        self.methods.iter().map(Method::as_str).collect::<Vec<_>>().join(",")
+    }
+
+    fn matches_host(&self, host: Option<&str>) -> bool {
+        let Some(expected) = &self.host else {
+            return true;
+        };
+        let Some(host) = host.map(|h| h.to_ascii_lowercase()) else {
+            return false;
+        };
+        match expected.strip_prefix("*.") {
+            Some(domain) => host.strip_suffix(domain).is_some_and(|sub| sub.len() > 1 && sub.ends_with('.')),
+            None => host == *expected,
+        }
+    }
+}
+
+/// Headers changed on the way to and from targets of a [ReverseProxy].
+#[derive(Clone, Debug)]
+pub(crate) struct ProxyHeaders {
+    /// Sends client's `Host` to targets if true, target's host otherwise.
+    pub(crate) preserve_host: bool,
+    /// Applied in order, after qpackt's own headers are set.
+    pub(crate) rules: Vec<HeaderRule>,
+}
+
+impl Default for ProxyHeaders {
+    fn default() -> Self {
+        Self { preserve_host: true, rules: vec![] }
+    }
+}
+
+impl ProxyHeaders {
+    pub(crate) fn apply(&self, direction: HeaderDirection, headers: &mut HeaderMap) {
+        for rule in self.rules.iter().filter(|r| r.direction == direction) {
+            rule.apply(headers);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub(crate) struct HeaderRule {
+    pub(crate) direction: HeaderDirection,
+    pub(crate) action: HeaderAction,
+    pub(crate) name: String,
+    /// Not used by [HeaderAction::Remove].
+    #[serde(default)]
+    pub(crate) value: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HeaderDirection {
+    /// Sent to a target.
+    Request,
+    /// Sent back to the client.
+    Response,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum HeaderAction {
+    /// Replaces all values of the header.
+    Set,
+    /// Keeps existing values.
+    Add,
+    Remove,
+}
+
+impl HeaderRule {
+    pub(crate) fn validate(&self) -> Result<(), QpacktError> {
+        HeaderName::from_str(&self.name).map_err(|_| QpacktError::InvalidRequest(format!("Invalid header name `{}`", self.name)))?;
+        match (self.action, &self.value) {
+            (HeaderAction::Remove, _) => Ok(()),
+            (_, Some(value)) => HeaderValue::from_str(value).map(|_| ()).map_err(|_| QpacktError::InvalidRequest(format!("Invalid value of header `{}`", self.name))),
+            (_, None) => Err(QpacktError::InvalidRequest(format!("Missing value of header `{}`", self.name))),
+        }
+    }
+
+    /// Invalid rules are skipped, see [HeaderRule::validate].
+    fn apply(&self, headers: &mut HeaderMap) {
+        let Ok(name) = HeaderName::from_str(&self.name) else {
+            return;
+        };
+        let value = self.value.as_deref().and_then(|v| HeaderValue::from_str(v).ok());
+        match (self.action, value) {
+            (HeaderAction::Set, Some(value)) => {
+                headers.insert(name, value);
+            }
+            (HeaderAction::Add, Some(value)) => headers.append(name, value),
+            (HeaderAction::Remove, _) => {
+                headers.remove(name);
+            }
+            (_, None) => {}
+        }
+    }
+}
+
+/// Caching of proxy's GET responses, see [crate::proxy::cache].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ProxyCache {
+    pub(crate) enabled: bool,
+    /// Overrides lifetime from responses' `Cache-Control` and `Expires`.
+    pub(crate) ttl: Option<Duration>,
+}
+
+/// How requests are spread between targets of a [ReverseProxy].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Balancing {
+    #[default]
+    RoundRobin,
+    /// Target with the fewest requests in flight.
+    LeastConnections,
+    /// Same visitor goes to the same target as long as it's available.
+    Hash,
+}
+
+impl Display for Balancing {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Balancing::RoundRobin => "round_robin",
+            Balancing::LeastConnections => "least_connections",
+            Balancing::Hash => "hash",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Balancing {
+    type Err = QpacktError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round_robin" => Ok(Balancing::RoundRobin),
+            "least_connections" => Ok(Balancing::LeastConnections),
+            "hash" => Ok(Balancing::Hash),
+            _ => Err(QpacktError::InvalidRequest(format!("Unknown balancing `{}`", s))),
+        }
+    }
+}
+
+/// Member of a [ReverseProxy] pool. State is shared between copies, so it survives reloading proxies.
+#[derive(Clone)]
+pub(crate) struct Target {
+    pub(crate) id: i32,
+    pub(crate) url: String,
+    /// Target gets only visitors of this version. Targets without a version get visitors of versions that
+    /// have no targets of their own and visitors without a version.
+    pub(crate) version: Option<VersionName>,
+    pub(crate) state: Arc<TargetState>,
+}
+
+pub(crate) struct TargetState {
+    /// Result of the last active health check.
+    healthy: AtomicBool,
+    /// Failed requests in a row.
+    failures: AtomicU32,
+    /// Unix time (seconds) until which the target is ejected.
+    ejected_until: AtomicU64,
+    /// Requests in flight, counted until their responses are sent.
+    connections: AtomicUsize,
+}
+
+impl Default for TargetState {
+    fn default() -> Self {
+        Self { healthy: AtomicBool::new(true), failures: AtomicU32::default(), ejected_until: AtomicU64::default(), connections: AtomicUsize::default() }
+    }
+}
+
+impl TargetState {
+    pub(crate) fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_healthy(&self, healthy: bool) {
+        self.healthy.store(healthy, Ordering::Relaxed)
+    }
+
+    pub(crate) fn is_ejected(&self) -> bool {
+        self.ejected_until.load(Ordering::Relaxed) > now()
+    }
+
+    pub(crate) fn failures(&self) -> u32 {
+        self.failures.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    fn is_available(&self) -> bool {
+        self.is_healthy() && !self.is_ejected()
+    }
+
+    /// Counts a request's outcome. After `max_failures` failures in a row the target is ejected.
+    /// Returns true if it was ejected just now.
+    pub(crate) fn record(&self, success: bool, max_failures: u32) -> bool {
+        if success {
+            self.failures.store(0, Ordering::Relaxed);
+            return false;
+        }
+        if self.failures.fetch_add(1, Ordering::Relaxed) + 1 < max_failures {
+            return false;
+        }
+        self.failures.store(0, Ordering::Relaxed);
+        self.ejected_until.store(now() + EJECTION_TIME.as_secs(), Ordering::Relaxed);
+        true
+    }
+
+    /// Counts a request in flight until the guard is dropped.
+    pub(crate) fn connect(self: &Arc<Self>) -> ConnectionGuard {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self.clone())
+    }
+}
+
+pub(crate) struct ConnectionGuard(Arc<TargetState>);
+
+impl ConnectionGuard {
+    /// Keeps counting the request until response's body is sent (or the tunnel is closed), not just its head.
+    pub(crate) fn hold(self, response: HttpResponse) -> HttpResponse {
+        response.map_body(|_, body| BoxBody::new(GuardedBody { body, _connection: self }))
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Body that releases its [ConnectionGuard] when dropped.
+struct GuardedBody {
+    body: BoxBody,
+    _connection: ConnectionGuard,
+}
+
+impl MessageBody for GuardedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+impl ReverseProxy {
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn new(
+        id: i32,
+        prefix: String,
+        matcher: Matcher,
+        headers: ProxyHeaders,
+        cache: ProxyCache,
+        targets: Vec<Target>,
+        balancing: Balancing,
+        health_check_path: Option<String>,
+        max_failures: u32,
+    ) -> Self {
+        Self { id, prefix, matcher, headers, cache, targets, balancing, health_check_path, max_failures, next: Arc::default() }
+    }
+
+    /// `host` comes without port.
+    pub(crate) fn matches(&self, host: Option<&str>, method: &Method, path: &str) -> bool {
+        if !self.matcher.matches_host(host) || !(self.matcher.methods.is_empty() || self.matcher.methods.contains(method)) {
+            return false;
+        }
+        match &self.matcher.path_regex {
+            Some(regex) => regex.is_match(path),
+            None => self.strip_prefix(path).is_some(),
+        }
+    }
+
+    /// Path to append to target's path.
+    pub(crate) fn upstream_path(&self, path: &str) -> String {
+        match (&self.matcher.path_regex, &self.matcher.rewrite) {
+            (Some(regex), Some(rewrite)) => match regex.captures(path) {
+                Some(captures) => {
+                    let mut rewritten = String::new();
+                    captures.expand(rewrite, &mut rewritten);
+                    rewritten
+                }
+                None => path.to_string(),
+            },
+            (Some(_), None) => path.to_string(),
+            (None, _) => self.strip_prefix(path).unwrap_or(path).to_string(),
+        }
+    }
+
+    fn strip_prefix<'a>(&self, path: &'a str) -> Option<&'a str> {
+        path.strip_prefix(self.prefix.trim_end_matches('/')).filter(|rest| rest.is_empty() || rest.starts_with('/'))
+    }
+
+    /// Within the same priority rules for a host are more specific than rules for any host, then longer prefixes win,
+    /// then rules limited to some methods. `path_regex` rules have no prefix, they are checked after prefix rules of
+    /// the same priority, so they need a higher one to take precedence.
+    fn specificity(&self) -> (i32, bool, usize, bool) {
+        (self.matcher.priority, self.matcher.host.is_some(), self.prefix.trim_end_matches('/').len(), !self.matcher.methods.is_empty())
+    }
+
+    /// Picks one of available targets of visitor's `version`, see [Target::version]. `key` identifies the visitor
+    /// for [Balancing::Hash]. Visitors of a version are never sent to other version's or fallback targets, even if
+    /// their own ones are down.
+    pub(crate) fn pick(&self, key: u64, version: Option<&VersionName>) -> Option<&Target> {
+        let version = version.filter(|v| self.targets.iter().any(|t| t.version.as_ref() == Some(*v)));
+        let available = self
+            .targets
+            .iter()
+            .filter(|t| match (&t.version, version) {
+                (Some(target_version), Some(version)) => target_version == version,
+                (None, None) => true,
+                _ => false,
+            })
+            .filter(|t| t.state.is_available())
+            .collect::<Vec<_>>();
+        if available.is_empty() {
+            return None;
+        }
+        let target = match self.balancing {
+            Balancing::RoundRobin => available[self.next.fetch_add(1, Ordering::Relaxed) % available.len()],
+            Balancing::LeastConnections => available.into_iter().min_by_key(|t| t.state.connections())?,
+            Balancing::Hash => available.into_iter().max_by_key(|t| salted_hash(key, t.url.as_bytes()))?,
+        };
+        Some(target)
+    }
 }
 
 #[derive(Default)]
@@ -34,18 +433,194 @@ pub(crate) struct ReverseProxies {
 }
 
 impl ReverseProxies {
-    pub(crate) async fn set(&self, list: Vec<ReverseProxy>) {
+    /// Replaces current proxies, ordered by [ReverseProxy::specificity], older ones first if it's the same.
+    /// Targets that were already known keep their health state.
+    pub(crate) async fn set(&self, mut list: Vec<ReverseProxy>) {
+        list.sort_by_key(|p| (Reverse(p.specificity()), p.id));
+        let current = self.list.load();
+        let known = current.iter().flat_map(|p| p.targets.iter()).collect::<Vec<_>>();
+        for target in list.iter_mut().flat_map(|p| p.targets.iter_mut()) {
+            if let Some(known) = known.iter().find(|t| t.id == target.id) {
+                target.state = known.state.clone();
+            }
+        }
         let new_list = Arc::new(list);
         self.list.store(new_list);
     }
 
-    pub(crate) fn find_by_uri(&self, uri: &Uri) -> Option<ReverseProxy> {
-        let list = self.list.load();
-        for rp in list.iter() {
-            if uri.path().starts_with(&rp.prefix) {
-                return Some(rp.clone());
-            }
+    /// Finds the first proxy whose rule matches the request. `host` comes without port.
+    pub(crate) fn find(&self, host: Option<&str>, method: &Method, path: &str) -> Option<ReverseProxy> {
+        self.list.load().iter().find(|rp| rp.matches(host, method, path)).cloned()
+    }
+
+    pub(crate) fn list(&self) -> Arc<Vec<ReverseProxy>> {
+        self.list.load_full()
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use actix_web::body::to_bytes;
+    use actix_web::http::header::{HeaderMap, HeaderName, HeaderValue};
+    use actix_web::http::Method;
+    use actix_web::HttpResponse;
+
+    use crate::dao::version::VersionName;
+    use crate::reverse_proxy::{
+        Balancing, HeaderAction, HeaderDirection, HeaderRule, Matcher, ProxyCache, ProxyHeaders, ReverseProxies, ReverseProxy, Target, TargetState,
+    };
+
+    fn proxy(balancing: Balancing) -> ReverseProxy {
+        let targets = (1..=3).map(|id| Target { id, url: format!("http://localhost:{}", 9000 + id), version: None, state: Arc::default() }).collect();
+        ReverseProxy::new(1, "/api".into(), Matcher::default(), ProxyHeaders::default(), ProxyCache::default(), targets, balancing, None, 2)
+    }
+
+    fn rule(id: i32, prefix: &str, matcher: Matcher) -> ReverseProxy {
+        ReverseProxy::new(id, prefix.into(), matcher, ProxyHeaders::default(), ProxyCache::default(), vec![], Balancing::RoundRobin, None, 3)
+    }
+
+    #[test]
+    fn skips_unavailable_targets() {
+        let proxy = proxy(Balancing::RoundRobin);
+        let picked = (0..6).map(|_| proxy.pick(0, None).unwrap().id).collect::<Vec<_>>();
+        assert_eq!(picked, vec![1, 2, 3, 1, 2, 3]);
+        proxy.targets[0].state.set_healthy(false);
+        assert!(!proxy.targets[1].state.record(false, 2));
+        assert!(proxy.targets[1].state.record(false, 2));
+        assert!((0..6).all(|_| proxy.pick(0, None).unwrap().id == 3));
+        proxy.targets[2].state.set_healthy(false);
+        assert!(proxy.pick(0, None).is_none());
+    }
+
+    #[test]
+    fn balances_by_connections_and_hash() {
+        let proxy = proxy(Balancing::LeastConnections);
+        let _first = proxy.targets[0].state.connect();
+        let _second = proxy.targets[1].state.connect();
+        assert_eq!(proxy.pick(0, None).unwrap().id, 3);
+
+        let proxy = self::proxy(Balancing::Hash);
+        for key in 0..100 {
+            let picked = proxy.pick(key, None).unwrap().id;
+            assert_eq!(proxy.pick(key, None).unwrap().id, picked);
         }
-        None
+        assert!((0..100).any(|key| proxy.pick(key, None).unwrap().id != proxy.pick(0, None).unwrap().id));
+    }
+
+    #[actix_web::test]
+    async fn counts_connections_until_body_is_sent() {
+        let state = Arc::new(TargetState::default());
+        let response = state.connect().hold(HttpResponse::Ok().body("body"));
+        assert_eq!(state.connections(), 1);
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "body");
+        assert_eq!(state.connections(), 0);
+    }
+
+    #[actix_web::test]
+    async fn keeps_state_of_known_targets() {
+        let proxies = ReverseProxies::default();
+        let first = proxy(Balancing::RoundRobin);
+        first.targets[0].state.set_healthy(false);
+        proxies.set(vec![first]).await;
+        proxies.set(vec![proxy(Balancing::RoundRobin)]).await;
+        let state: &TargetState = &proxies.list()[0].targets[0].state;
+        assert!(!state.is_healthy());
+    }
+
+    #[test]
+    fn matches_prefix_host_and_methods() {
+        let api = rule(1, "/api/", Matcher::default());
+        assert!(api.matches(None, &Method::GET, "/api"));
+        assert!(api.matches(None, &Method::GET, "/api/x"));
+        assert!(!api.matches(None, &Method::GET, "/apiv2/x"));
+        assert_eq!(api.upstream_path("/api/x"), "/x");
+
+        let matcher = Matcher::new(Some("*.Qpackt.com".into()), "get, post", None, None, 0).unwrap();
+        let hosted = rule(2, "", matcher);
+        assert!(hosted.matches(Some("app.qpackt.com"), &Method::POST, "/x"));
+        assert!(!hosted.matches(Some("qpackt.com"), &Method::POST, "/x"));
+        assert!(!hosted.matches(Some("evilqpackt.com"), &Method::POST, "/x"));
+        assert!(!hosted.matches(None, &Method::GET, "/x"));
+        assert!(!hosted.matches(Some("app.qpackt.com"), &Method::DELETE, "/x"));
+        assert_eq!(hosted.matcher.methods_string(), "GET,POST");
+    }
+
+    #[test]
+    fn rewrites_regex_paths() {
+        let matcher = Matcher::new(None, "", Some(r"^/users/(?P<id>\d+)/avatar$"), Some("/avatars/$id.png".into()), 0).unwrap();
+        let avatars = rule(1, "", matcher);
+        assert!(avatars.matches(None, &Method::GET, "/users/12/avatar"));
+        assert!(!avatars.matches(None, &Method::GET, "/users/me/avatar"));
+        assert_eq!(avatars.upstream_path("/users/12/avatar"), "/avatars/12.png");
+        assert!(Matcher::new(None, "", Some("("), None, 0).is_err());
+        assert!(Matcher::new(None, "", None, Some("/x".into()), 0).is_err());
+    }
+
+    #[actix_web::test]
+    async fn checks_rules_by_priority_and_prefix_length() {
+        let proxies = ReverseProxies::default();
+        let priority = Matcher { priority: 1, ..Matcher::default() };
+        proxies.set(vec![rule(1, "/api", Matcher::default()), rule(2, "/api/v2", Matcher::default()), rule(3, "/api/v2/admin", priority.clone()), rule(4, "/", priority)]).await;
+        let found = |path: &str| proxies.find(None, &Method::GET, path).unwrap().id;
+        assert_eq!(found("/api/v2/admin"), 3);
+        assert_eq!(found("/api/v2/x"), 4);
+        proxies.set(vec![rule(1, "/api", Matcher::default()), rule(2, "/api/v2", Matcher::default())]).await;
+        assert_eq!(found("/api/v2/x"), 2);
+        assert_eq!(found("/api/v2x"), 1);
+        assert!(proxies.find(None, &Method::GET, "/apiv2").is_none());
+        let host = Matcher { host: Some("api.qpackt.com".into()), ..Matcher::default() };
+        let get = Matcher { methods: vec![Method::GET], ..Matcher::default() };
+        proxies.set(vec![rule(3, "/api", Matcher::default()), rule(2, "/api", get), rule(5, "/", host), rule(1, "/api", Matcher::default())]).await;
+        assert_eq!(found("/api/x"), 2);
+        assert_eq!(proxies.find(None, &Method::POST, "/api/x").unwrap().id, 1);
+        assert_eq!(proxies.find(Some("api.qpackt.com"), &Method::GET, "/api/x").unwrap().id, 5);
+    }
+
+    #[test]
+    fn applies_header_rules_in_order() {
+        let rule = |direction, action, name: &str, value: Option<&str>| HeaderRule { direction, action, name: name.into(), value: value.map(String::from) };
+        let headers = ProxyHeaders {
+            preserve_host: true,
+            rules: vec![
+                rule(HeaderDirection::Request, HeaderAction::Set, "x-env", Some("prod")),
+                rule(HeaderDirection::Request, HeaderAction::Add, "x-env", Some("eu")),
+                rule(HeaderDirection::Request, HeaderAction::Remove, "authorization", None),
+                rule(HeaderDirection::Response, HeaderAction::Remove, "server", None),
+            ],
+        };
+        let mut map = HeaderMap::new();
+        map.insert(HeaderName::from_static("x-env"), HeaderValue::from_static("dev"));
+        map.insert(HeaderName::from_static("authorization"), HeaderValue::from_static("secret"));
+        map.insert(HeaderName::from_static("server"), HeaderValue::from_static("nginx"));
+        headers.apply(HeaderDirection::Request, &mut map);
+        assert_eq!(map.get_all("x-env").collect::<Vec<_>>(), vec!["prod", "eu"]);
+        assert!(!map.contains_key("authorization"));
+        assert!(map.contains_key("server"));
+        headers.apply(HeaderDirection::Response, &mut map);
+        assert!(!map.contains_key("server"));
+
+        assert!(rule(HeaderDirection::Request, HeaderAction::Set, "x-env", None).validate().is_err());
+        assert!(rule(HeaderDirection::Request, HeaderAction::Set, "bad name", Some("x")).validate().is_err());
+        assert!(rule(HeaderDirection::Request, HeaderAction::Remove, "x-env", None).validate().is_ok());
+    }
+
+    #[test]
+    fn routes_versions_to_their_targets() {
+        let mut proxy = proxy(Balancing::RoundRobin);
+        let (blue, red) = (VersionName::from("blue".to_string()), VersionName::from("red".to_string()));
+        proxy.targets[1].version = Some(blue.clone());
+        proxy.targets[2].version = Some(blue.clone());
+        assert!((0..4).all(|key| proxy.pick(key, None).unwrap().id == 1));
+        assert!((0..4).all(|key| proxy.pick(key, Some(&red)).unwrap().id == 1));
+        assert!((0..4).all(|key| proxy.pick(key, Some(&blue)).unwrap().id != 1));
+        proxy.targets[1].state.set_healthy(false);
+        proxy.targets[2].state.set_healthy(false);
+        assert!(proxy.pick(0, Some(&blue)).is_none());
     }
 }
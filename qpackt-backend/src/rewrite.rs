@@ -0,0 +1,325 @@
+// SPDX-License-Identifier: AGPL-3.0
+/*
+   qpackt: Web & Analytics Server
+   Copyright (C) 2023 Łukasz Wojtów
+
+   This program is free software: you can redistribute it and/or modify
+   it under the terms of the GNU Affero General Public License as
+   published by the Free Software Foundation, either version 3 of the
+   License.
+
+   This program is distributed in the hope that it will be useful,
+   but WITHOUT ANY WARRANTY; without even the implied warranty of
+   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+   GNU Affero General Public License for more details.
+
+   You should have received a copy of the GNU Affero General Public License
+   along with this program.  If not, see <https://www.gnu.org/licenses/>.
+*/
+
+//! Redirects and internal rewrites of old URLs. Site-wide rules are kept in the database and applied by
+//! [CheckRewrites] before reverse proxies and static files, because an internal rewrite has to change request's URI
+//! before it's routed. Paths answered by qpackt itself (ACME challenges, events) are never rewritten. Versions can bring their own rules in a `_redirects` file (Netlify's format), those are applied
+//! when the version's files are served, see [crate::server::serve_file].
+
+use std::cmp::Reverse;
+use std::future::{ready, Ready};
+use std::sync::Arc;
+
+use actix_web::body::EitherBody;
+use actix_web::dev::{self, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, StatusCode, Uri};
+use actix_web::web::Data;
+use actix_web::{Error, HttpResponse};
+use arc_swap::ArcSwap;
+use futures_util::future::LocalBoxFuture;
+use log::{debug, warn};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::error::QpacktError;
+use crate::proxy::event::QPACKT_EVENT_URI;
+
+/// Status of internal rewrites, same as in `_redirects` files.
+const REWRITE_STATUS: u16 = 200;
+/// Status of `_redirects` rules without one.
+const DEFAULT_REDIRECT_STATUS: u16 = 301;
+/// Prefixes of paths served by qpackt, a catch-all rule must not take them from it.
+const QPACKT_PATHS: [&str; 2] = ["/.well-known/acme-challenge/", QPACKT_EVENT_URI];
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct Rewrite {
+    pub(crate) id: i32,
+    /// Regex matched against request's path, e.g. `^/blog/(\d+)$`.
+    pub(crate) source: String,
+    /// Path or URL with captures of the source (`$1`, `${name}`).
+    pub(crate) destination: String,
+    /// 301, 302, 307 or 308 for redirects, 200 for internal rewrites.
+    pub(crate) status: u16,
+    /// Whether request's query is added to the destination.
+    pub(crate) preserve_query: bool,
+    /// Rules with higher priority are checked first.
+    pub(crate) priority: i32,
+    /// Whether the rule applies even if a file exists at the path. Only `_redirects` rules without `!` are shadowed.
+    #[serde(skip)]
+    pub(crate) force: bool,
+    #[serde(skip)]
+    regex: Regex,
+}
+
+/// Result of a matching [Rewrite].
+#[derive(Debug, PartialEq)]
+pub(crate) enum Rewritten {
+    Redirect(StatusCode, String),
+    /// Request is served as if it was made to this path (and query).
+    Internal(Uri),
+}
+
+impl Rewritten {
+    pub(crate) fn redirect_response(status: StatusCode, location: &str) -> HttpResponse {
+        HttpResponse::build(status).insert_header((header::LOCATION, location)).finish()
+    }
+}
+
+impl Rewrite {
+    pub(crate) fn new(
+        id: i32,
+        source: String,
+        destination: String,
+        status: u16,
+        preserve_query: bool,
+        priority: i32,
+    ) -> crate::error::Result<Self> {
+        let regex = Regex::new(&source).map_err(|e| QpacktError::InvalidRequest(format!("Invalid rewrite source `{}`: {}", source, e)))?;
+        if !matches!(status, REWRITE_STATUS | 301 | 302 | 307 | 308) {
+            return Err(QpacktError::InvalidRequest(format!("Invalid rewrite status {}, expected 200, 301, 302, 307 or 308", status)));
+        }
+        if status == REWRITE_STATUS && !destination.starts_with('/') {
+            return Err(QpacktError::InvalidRequest(format!("Internal rewrite destination `{}` must be a path", destination)));
+        }
+        Ok(Self { id, source, destination, status, preserve_query, priority, force: true, regex })
+    }
+
+    /// Destination for the path, if it matches.
+    pub(crate) fn apply(&self, path: &str, query: Option<&str>) -> Option<Rewritten> {
+        let captures = self.regex.captures(path)?;
+        let mut destination = String::new();
+        captures.expand(&self.destination, &mut destination);
+        if let Some(query) = query.filter(|q| self.preserve_query && !q.is_empty()) {
+            destination.push(if destination.contains('?') { '&' } else { '?' });
+            destination.push_str(query);
+        }
+        if self.status == REWRITE_STATUS {
+            match destination.parse::<Uri>() {
+                Ok(uri) => Some(Rewritten::Internal(uri)),
+                Err(e) => {
+                    warn!("Invalid rewrite of {} to {}: {}", path, destination, e);
+                    None
+                }
+            }
+        } else {
+            Some(Rewritten::Redirect(StatusCode::from_u16(self.status).ok()?, destination))
+        }
+    }
+}
+
+/// Applies the first matching rule.
+pub(crate) fn first_match(rules: &[Rewrite], path: &str, query: Option<&str>) -> Option<Rewritten> {
+    rules.iter().find_map(|rule| rule.apply(path, query))
+}
+
+/// Parses a `_redirects` file: `from to [status]` per line, `#` starts a comment. `*` in `from` is available as
+/// `:splat` in `to`, `:name` segments are available as `:name`. Queries are preserved. Invalid lines are skipped.
+/// Like on Netlify, a rule is shadowed by an existing file unless its status ends with `!`.
+pub(crate) fn parse_redirects(content: &str) -> Vec<Rewrite> {
+    let mut rules = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        let mut parts = line.split_whitespace();
+        let (Some(from), Some(to)) = (parts.next(), parts.next()) else {
+            continue;
+        };
+        let status = parts.next();
+        let force = status.is_some_and(|s| s.ends_with('!'));
+        let status = match status.map(|s| s.trim_end_matches('!').parse::<u16>()) {
+            None => Ok(DEFAULT_REDIRECT_STATUS),
+            Some(status) => status,
+        };
+        let rule = status
+            .map_err(|_| QpacktError::InvalidRequest("invalid status".into()))
+            .and_then(|status| Rewrite::new(number as i32, source_regex(from), destination_template(to), status, true, 0))
+            .map(|rule| Rewrite { force, ..rule });
+        match rule {
+            Ok(rule) => rules.push(rule),
+            Err(e) => warn!("Skipping line {} of _redirects: {}", number + 1, e),
+        }
+    }
+    rules
+}
+
+/// `/blog/:year/*` -> `^/blog/(?P<year>[^/]+)/(?P<splat>.*)$`
+fn source_regex(from: &str) -> String {
+    let segments = from.split('/').map(|segment| {
+        if let Some(name) = segment.strip_prefix(':') {
+            format!("(?P<{}>[^/]+)", name)
+        } else if let Some(prefix) = segment.strip_suffix('*') {
+            format!("{}(?P<splat>.*)", regex::escape(prefix))
+        } else {
+            regex::escape(segment)
+        }
+    });
+    format!("^{}$", segments.collect::<Vec<_>>().join("/"))
+}
+
+/// `/news/:year/:splat` -> `/news/${year}/${splat}`
+fn destination_template(to: &str) -> String {
+    let placeholder = Regex::new(r":([A-Za-z_][A-Za-z0-9_]*)").unwrap();
+    placeholder.replace_all(&to.replace('$', "$$"), "$${$1}").into_owned()
+}
+
+/// Site-wide rules, replaced when changed in the panel.
+#[derive(Default)]
+pub(crate) struct Rewrites {
+    list: ArcSwap<Vec<Rewrite>>,
+}
+
+impl Rewrites {
+    /// Replaces current rules. Higher priorities are checked first, older rules first within the same priority.
+    pub(crate) async fn set(&self, mut list: Vec<Rewrite>) {
+        list.sort_by_key(|r| (Reverse(r.priority), r.id));
+        self.list.store(Arc::new(list));
+    }
+
+    pub(crate) fn find(&self, path: &str, query: Option<&str>) -> Option<Rewritten> {
+        first_match(&self.list.load(), path, query)
+    }
+}
+
+/// Answers with redirects and changes URI of rewritten requests. Needs [Rewrites] in app data.
+pub(crate) struct CheckRewrites;
+
+impl<S, B> Transform<S, ServiceRequest> for CheckRewrites
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = CheckRewritesMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(CheckRewritesMiddleware { service }))
+    }
+}
+pub(crate) struct CheckRewritesMiddleware<S> {
+    service: S,
+}
+
+impl<S, B> Service<ServiceRequest> for CheckRewritesMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    dev::forward_ready!(service);
+
+    fn call(&self, mut request: ServiceRequest) -> Self::Future {
+        let rewritten = request
+            .app_data::<Data<Rewrites>>()
+            .filter(|_| !QPACKT_PATHS.iter().any(|prefix| request.path().starts_with(prefix)))
+            .and_then(|rewrites| rewrites.find(request.path(), request.uri().query()));
+        match rewritten {
+            Some(Rewritten::Redirect(status, location)) => {
+                debug!("Redirecting {} to {}", request.uri(), location);
+                let (request, _) = request.into_parts();
+                let response = Rewritten::redirect_response(status, &location).map_into_right_body();
+                return Box::pin(async { Ok(ServiceResponse::new(request, response)) });
+            }
+            Some(Rewritten::Internal(uri)) => {
+                debug!("Rewriting {} to {}", request.uri(), uri);
+                request.match_info_mut().get_mut().update(&uri);
+                request.head_mut().uri = uri;
+            }
+            None => {}
+        }
+        let res = self.service.call(request);
+        Box::pin(async move { res.await.map(ServiceResponse::map_into_left_body) })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use actix_web::http::{header, StatusCode};
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::web::Data;
+    use actix_web::{web, App, HttpRequest};
+
+    use crate::rewrite::{first_match, parse_redirects, CheckRewrites, Rewrite, Rewrites, Rewritten};
+
+    fn redirect(status: StatusCode, location: &str) -> Option<Rewritten> {
+        Some(Rewritten::Redirect(status, location.into()))
+    }
+
+    #[test]
+    fn applies_rules_with_captures_and_queries() {
+        let rule = Rewrite::new(1, r"^/blog/(\d+)$".into(), "/posts/$1".into(), 308, true, 0).unwrap();
+        assert_eq!(rule.apply("/blog/42", Some("ref=x")), redirect(StatusCode::PERMANENT_REDIRECT, "/posts/42?ref=x"));
+        assert_eq!(rule.apply("/blog/new", None), None);
+        let rule = Rewrite::new(2, "^/old$".into(), "https://example.com/new?a=1".into(), 302, true, 0).unwrap();
+        assert_eq!(rule.apply("/old", Some("b=2")), redirect(StatusCode::FOUND, "https://example.com/new?a=1&b=2"));
+        let rule = Rewrite::new(3, "^/app/.*$".into(), "/index.html".into(), 200, false, 0).unwrap();
+        assert_eq!(rule.apply("/app/users", Some("b=2")), Some(Rewritten::Internal("/index.html".parse().unwrap())));
+        assert!(Rewrite::new(4, "^/a$".into(), "https://example.com".into(), 200, false, 0).is_err());
+        assert!(Rewrite::new(5, "^/a$".into(), "/b".into(), 303, false, 0).is_err());
+    }
+
+    #[test]
+    fn parses_redirects_files() {
+        let rules = parse_redirects(
+            "# Old blog\n/blog/:year/*  /news/:year/:splat  301!\n/home / 302\n/api/*  /v2/api/:splat  200\n\ninvalid\n/a /b 999\n",
+        );
+        assert_eq!(rules.len(), 3);
+        assert_eq!(first_match(&rules, "/blog/2020/a/b", None), redirect(StatusCode::MOVED_PERMANENTLY, "/news/2020/a/b"));
+        assert_eq!(first_match(&rules, "/home", Some("x=1")), redirect(StatusCode::FOUND, "/?x=1"));
+        assert_eq!(first_match(&rules, "/api/users", None), Some(Rewritten::Internal("/v2/api/users".parse().unwrap())));
+        assert_eq!(first_match(&rules, "/other", None), None);
+        assert_eq!(rules.iter().map(|r| r.force).collect::<Vec<_>>(), [true, false, false]);
+    }
+
+    #[actix_web::test]
+    async fn keeps_qpackt_paths_from_catch_all_rules() {
+        let rewrites = Rewrites::default();
+        rewrites
+            .set(vec![
+                Rewrite::new(1, "^/old$".into(), "/new".into(), 301, false, 1).unwrap(),
+                Rewrite::new(2, "^/.*$".into(), "/index.html".into(), 200, false, 0).unwrap(),
+            ])
+            .await;
+        let app = init_service(
+            App::new()
+                .wrap(CheckRewrites {})
+                .app_data(Data::new(rewrites))
+                .service(web::resource("/.well-known/acme-challenge/{token}").to(|| async { "challenge" }))
+                .service(web::resource("/qpackt/event").to(|| async { "event" }))
+                .default_service(web::to(|request: HttpRequest| async move { request.path().to_string() })),
+        )
+        .await;
+        let body = |uri: &'static str| {
+            let app = &app;
+            async move { read_body(call_service(app, TestRequest::get().uri(uri).to_request()).await).await }
+        };
+        assert_eq!(body("/.well-known/acme-challenge/token").await, "challenge");
+        assert_eq!(body("/qpackt/event").await, "event");
+        assert_eq!(body("/about").await, "/index.html");
+        let response = call_service(&app, TestRequest::get().uri("/old").to_request()).await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/new");
+    }
+}
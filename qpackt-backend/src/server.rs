@@ -1,64 +1,188 @@
+use crate::cache_rules::{etag, is_not_modified, last_modified, CacheRule, CacheRules};
+use crate::compression::{is_compressible, negotiate, CompressionCache, FileKey, MAX_COMPRESSED_SIZE, MIN_COMPRESSED_SIZE};
 use crate::constants::VERSIONS_SUBDIRECTORY;
 use crate::dao::version::{Version, VersionName};
 use crate::error::{QpacktError, Result};
+use crate::manager::assignment::{rendezvous_score, Assignment};
+use crate::manager::bandit::{Allocations, BANDIT_TOTAL_WEIGHT};
 use crate::manager::strategy::Strategy;
-use actix_files::Files;
-use actix_web::dev::{Server, ServerHandle};
-use actix_web::{App, HttpServer};
-use log::{debug, error, info, warn};
+use crate::proxy::forwarded::TrustedProxies;
+use crate::response_headers::{parse_headers_file, HeaderRules, ResponseHeaders};
+use crate::rewrite::{parse_redirects, Rewrite, Rewritten};
+use actix_files::NamedFile;
+use actix_web::http::header::{ContentType, HeaderMap, HeaderValue, HttpDate};
+use actix_web::http::{header, Method, StatusCode, Uri};
+use actix_web::web::Data;
+use actix_web::{HttpRequest, HttpResponse};
+use log::{debug, error, warn};
+use arc_swap::ArcSwap;
+use percent_encoding::percent_decode_str;
 use rand::{thread_rng, Rng};
-use std::net::{Ipv4Addr, SocketAddrV4};
+use serde::{Deserialize, Serialize};
+use std::fs::Metadata;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use tokio::fs;
 use tokio::sync::RwLock;
-use url::Url;
 
-/// All servers for versions will be started on localhost:port where starting port is the value below.
-const START_PORT: u16 = 10_000;
+/// File served when the request points to a directory.
+const INDEX_FILE: &str = "index.html";
+/// Custom pages looked up in uploaded archives.
+const NOT_FOUND_PAGE: &str = "404.html";
+const SERVER_ERROR_PAGE: &str = "50x.html";
+/// Version's redirects and rewrites in Netlify's format, see [parse_redirects].
+const REDIRECTS_FILE: &str = "_redirects";
+/// Version's custom headers in Netlify's format, see [parse_headers_file].
+const HEADERS_FILE: &str = "_headers";
 
-/// Contains details for various versions' servers.  
+/// How files of a version are served. Stored with the [Version].
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct ServingProfile {
+    /// Unknown paths without file extension get `index.html`, so that deep links of single page applications work.
+    pub(crate) spa_fallback: bool,
+    /// Page (relative to web root) served with 404 status, `404.html` if the archive has one.
+    pub(crate) not_found_page: Option<String>,
+    /// Page (relative to web root) served with 500 status, `50x.html` if the archive has one.
+    pub(crate) server_error_page: Option<String>,
+    pub(crate) trailing_slash: TrailingSlash,
+    /// `/about` serves `/about.html` if there is no `/about`.
+    pub(crate) clean_urls: bool,
+    /// `Cache-Control` of files, e.g. `assets/**` -> `public, max-age=31536000, immutable`.
+    pub(crate) cache_rules: Vec<CacheRule>,
+    /// Headers of served files, applied after global ones and version's [HEADERS_FILE].
+    pub(crate) headers: ResponseHeaders,
+}
+
+/// Whether paths are redirected to their variant with or without trailing slash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum TrailingSlash {
+    /// Paths are served as requested.
+    #[default]
+    Keep,
+    /// Directories are redirected to `/dir/`.
+    Add,
+    /// Every path is redirected to the one without trailing slash.
+    Remove,
+}
+
+impl ServingProfile {
+    /// Default profile with custom error pages found in web root.
+    pub(crate) fn detect(web_root: &Path) -> Self {
+        let page = |name: &str| web_root.join(name).is_file().then(|| name.to_string());
+        Self { not_found_page: page(NOT_FOUND_PAGE), server_error_page: page(SERVER_ERROR_PAGE), ..Default::default() }
+    }
+
+    /// Checks that error pages stay inside web root and cache rules are valid.
+    pub(crate) fn validate(&self) -> Result<()> {
+        for page in [&self.not_found_page, &self.server_error_page].into_iter().flatten() {
+            if page.is_empty() || resolve_path(Path::new(""), &format!("/{}", page)).is_none() {
+                return Err(QpacktError::InvalidRequest(format!("Invalid error page `{}`", page)));
+            }
+        }
+        for rule in &self.cache_rules {
+            rule.validate()?;
+        }
+        self.headers.validate()
+    }
+
+    /// Location to redirect to according to [TrailingSlash], query is kept.
+    fn redirect(&self, uri: &Uri, is_dir: bool) -> Option<String> {
+        let path = uri.path();
+        let location = match self.trailing_slash {
+            TrailingSlash::Add if is_dir && !path.ends_with('/') => format!("{}/", path),
+            TrailingSlash::Remove if path.ends_with('/') && !path.trim_end_matches('/').is_empty() => path.trim_end_matches('/').to_string(),
+            _ => return None,
+        };
+        Some(match uri.query() {
+            Some(query) => format!("{}?{}", location, query),
+            None => location,
+        })
+    }
+}
+
+/// Version's files and how they are served.
+pub(crate) struct WebRoot {
+    version: VersionName,
+    /// Absolute path to the directory with version's files.
+    pub(crate) path: PathBuf,
+    pub(crate) profile: ServingProfile,
+    /// Compiled [ServingProfile::cache_rules].
+    cache_rules: CacheRules,
+    /// Files compressed on the fly.
+    compressed: CompressionCache,
+    /// Rules from version's [REDIRECTS_FILE].
+    redirects: Vec<Rewrite>,
+    /// Compiled [HEADERS_FILE] and [ServingProfile::headers].
+    headers: HeaderRules,
+}
+
+impl WebRoot {
+    pub(crate) fn new(version: VersionName, path: PathBuf, profile: ServingProfile) -> Self {
+        let cache_rules = CacheRules::new(&profile.cache_rules);
+        let redirects = std::fs::read_to_string(path.join(REDIRECTS_FILE)).map(|content| parse_redirects(&content)).unwrap_or_default();
+        let headers_file = std::fs::read_to_string(path.join(HEADERS_FILE)).map(|content| parse_headers_file(&content)).unwrap_or_default();
+        let headers = HeaderRules::new(&[&ResponseHeaders { preset: None, rules: headers_file }, &profile.headers]);
+        Self { version, path, profile, cache_rules, compressed: CompressionCache::default(), redirects, headers }
+    }
+}
+
+/// Contains details for various versions' web roots.
 pub(crate) struct Versions {
-    versions: RwLock<Vec<VersionServer>>,
+    versions: RwLock<Vec<VersionRoot>>,
+    assignment: Assignment,
+    /// Current weights of [Strategy::Bandit] versions. Recomputed in the background, so picking stays cheap.
+    allocations: ArcSwap<Allocations>,
 }
 
-pub(crate) struct VersionServer {
+pub(crate) struct VersionRoot {
     pub(crate) version: Version,
-    port: u16,
-    upstream: Arc<Url>,
-    server_handle: Option<ServerHandle>,
+    web_root: Arc<WebRoot>,
 }
 
 impl Versions {
-    pub(super) async fn start(versions: Vec<Version>, run_dir: &Path) -> Self {
-        let mut versions = build_version_servers(versions);
-        start_version_servers(&mut versions, run_dir).await;
-        Self { versions: RwLock::new(versions) }
+    pub(super) fn new(versions: Vec<Version>, run_dir: &Path, assignment: Assignment) -> Self {
+        let versions = versions.into_iter().map(|v| build_version_root(v, run_dir)).collect();
+        Self { versions: RwLock::new(versions), assignment, allocations: ArcSwap::default() }
     }
 
-    /// Tries to pick a new [Url] and [VersionName] for request based on [Strategy] and request query.
-    /// First try url param matching,
-    /// then calculate total weights and pick some version proportionally.
-    pub(super) async fn pick_upstream(&self, query: &str) -> Result<(Arc<Url>, VersionName)> {
+    /// Calculates the key used to pick a version for a new visitor (see [Assignment]).
+    pub(super) fn assignment_key(&self, request: &HttpRequest, trusted_proxies: &TrustedProxies) -> Option<u64> {
+        if self.assignment == Assignment::Random {
+            return None;
+        }
+        self.assignment.key(request, trusted_proxies.client_ip(request))
+    }
+
+    /// Tries to pick a new web root and [VersionName] for request based on [Strategy].
+    /// First try targeting rules (url param, header, language, device, cookie) in versions' order,
+    /// then pick some version proportionally to weights: by the assignment key if present, randomly otherwise.
+    pub(super) async fn pick_upstream(&self, request: &HttpRequest, key: Option<u64>) -> Result<(Arc<WebRoot>, VersionName)> {
         let versions = self.versions.read().await;
-        // Try UrlParam matching first.
-        for v in versions.iter() {
-            if let Strategy::UrlParam(needle) = &v.version.strategy {
-                if query.contains(needle) {
-                    debug!("Picking version {} by UrlParam (needle: {}, query: {})", v.version.name, needle, query);
-                    return Ok((v.upstream.clone(), v.version.name.clone()));
-                }
+        // Try targeting rules first.
+        for v in versions.iter().filter(|v| !v.version.drain) {
+            if v.version.strategy.matches(request) {
+                debug!("Picking version {} by {:?}", v.version.name, v.version.strategy);
+                return Ok((v.web_root.clone(), v.version.name.clone()));
             }
         }
+        let allocations = self.allocations.load();
+        let weights = weights(&versions, &allocations);
+        if let Some(key) = key {
+            return pick_by_key(&versions, &weights, key);
+        }
         // Add up all weights.
-        let sum_weights = versions.iter().map(|v| if let Strategy::Weight(w) = v.version.strategy { w as i32 } else { 0 }).sum::<i32>();
+        let sum_weights = weights.iter().map(|w| *w as i32).sum::<i32>();
         // Pick some version proportionally.
         let mut cut = thread_rng().gen_range(0..sum_weights + 1);
-        for v in versions.iter() {
-            if let Strategy::Weight(w) = v.version.strategy {
-                cut -= w as i32;
+        for (v, w) in versions.iter().zip(&weights) {
+            if *w > 0 {
+                cut -= *w as i32;
                 if cut <= 0 {
                     debug!("Picking version {} by Weight", v.version.name);
-                    return Ok((v.upstream.clone(), v.version.name.clone()));
+                    return Ok((v.web_root.clone(), v.version.name.clone()));
                 }
             }
         }
@@ -66,7 +190,7 @@ impl Versions {
         Err(QpacktError::ProxyError)
     }
 
-    /// Loops over current versions and updates their strategies with new ones.
+    /// Loops over current versions and updates their strategies, drain flags and serving profiles with new ones.
     /// Will not create/delete a [Version].
     pub(super) async fn update_strategies(&self, new: &[Version]) {
         let mut versions = self.versions.write().await;
@@ -74,82 +198,537 @@ impl Versions {
             for updated in new {
                 if updated.name == current_version.version.name {
                     current_version.version.strategy = updated.strategy.clone();
+                    current_version.version.drain = updated.drain;
+                    if current_version.web_root.profile != updated.profile {
+                        let path = current_version.web_root.path.clone();
+                        current_version.version.profile = updated.profile.clone();
+                        current_version.web_root = Arc::new(WebRoot::new(updated.name.clone(), path, updated.profile.clone()));
+                    }
                     break;
                 }
             }
         }
     }
 
-    /// Deletes [Version] and stops its task.
+    /// Lists [Strategy::Bandit] versions with their goal events.
+    pub(super) async fn bandit_goals(&self) -> Vec<(VersionName, String)> {
+        let versions = self.versions.read().await;
+        versions
+            .iter()
+            .filter(|v| !v.version.drain)
+            .filter_map(|v| match &v.version.strategy {
+                Strategy::Bandit { goal_event } => Some((v.version.name.clone(), goal_event.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Lists drained versions.
+    pub(super) async fn drained(&self) -> Vec<VersionName> {
+        let versions = self.versions.read().await;
+        versions.iter().filter(|v| v.version.drain).map(|v| v.version.name.clone()).collect()
+    }
+
+    /// Replaces weights of [Strategy::Bandit] versions.
+    pub(super) fn set_allocations(&self, allocations: Allocations) {
+        self.allocations.store(Arc::new(allocations));
+    }
+
+    /// Deletes [Version] so it's no longer served.
     pub(super) async fn delete_version(&self, name: &VersionName) {
         let mut versions = self.versions.write().await;
-        for (i, v) in versions.iter().enumerate() {
-            if &v.version.name == name {
-                let version = versions.remove(i);
-                if let Some(server_handle) = version.server_handle {
-                    server_handle.stop(true).await;
-                } else {
-                    warn!("No running task for version `{}` that's being removed", name);
-                }
-                break;
-            }
-        }
+        versions.retain(|v| &v.version.name != name);
     }
 
-    /// Adds [Version] and starts [VersionServer] for it.
+    /// Adds [Version] so it can be served.
     pub(super) async fn add_version(&self, version: Version, run_dir: &Path) {
         let mut versions = self.versions.write().await;
-        let next_port = versions.iter().map(|v| v.port).max().unwrap_or(START_PORT) + 1;
-        let mut server = build_version_server(version, next_port);
-        start_version_server(&mut server, run_dir).await;
-        versions.push(server);
+        versions.push(build_version_root(version, run_dir));
     }
 
-    /// Gets [Url] for cookie.
-    pub(super) async fn get_url_for_cookie(&self, cookie: &str) -> Option<(Arc<Url>, VersionName)> {
+    /// Gets web root for cookie. Drained versions are not returned, so that their visitors get reassigned.
+    pub(super) async fn get_root_for_cookie(&self, cookie: &str) -> Option<(Arc<WebRoot>, VersionName)> {
         let versions = self.versions.read().await;
-        versions.iter().find(|v| v.version.name.matches(cookie)).map(|found| (found.upstream.clone(), found.version.name.clone()))
+        versions.iter().find(|v| !v.version.drain && v.version.name.matches(cookie)).map(|found| (found.web_root.clone(), found.version.name.clone()))
+    }
+}
+
+/// Weight of every version in the same order. Targeting rules and drained versions weigh 0. [Strategy::Bandit] versions
+/// without allocation yet (e.g. just switched to bandit) split [BANDIT_TOTAL_WEIGHT] equally.
+fn weights(versions: &[VersionRoot], allocations: &Allocations) -> Vec<u16> {
+    let bandits = versions.iter().filter(|v| !v.version.drain && matches!(v.version.strategy, Strategy::Bandit { .. })).count() as u16;
+    versions
+        .iter()
+        .map(|v| match v.version.strategy {
+            _ if v.version.drain => 0,
+            Strategy::Weight(w) => w,
+            Strategy::Bandit { .. } => allocations.get(&v.version.name).copied().unwrap_or(BANDIT_TOTAL_WEIGHT / bandits),
+            _ => 0,
+        })
+        .collect()
+}
+
+/// Picks the version with the highest rendezvous score, so the same key always gets the same version.
+pub(crate) fn pick_by_key(versions: &[VersionRoot], weights: &[u16], key: u64) -> Result<(Arc<WebRoot>, VersionName)> {
+    let mut best: Option<(f64, &VersionRoot)> = None;
+    for (v, w) in versions.iter().zip(weights) {
+        if *w == 0 {
+            continue;
+        }
+        let score = rendezvous_score(key, &v.version.name, *w);
+        if best.is_none_or(|(best_score, _)| score > best_score) {
+            best = Some((score, v));
+        }
+    }
+    let Some((_, v)) = best else {
+        error!("Unable to find working version");
+        return Err(QpacktError::ProxyError);
+    };
+    debug!("Picking version {} by Weight for key {}", v.version.name, key);
+    Ok((v.web_root.clone(), v.version.name.clone()))
+}
+
+pub(crate) fn build_version_root(version: Version, run_dir: &Path) -> VersionRoot {
+    let path = run_dir.join(VERSIONS_SUBDIRECTORY).join(&version.web_root);
+    let web_root = Arc::new(WebRoot::new(version.name.clone(), path, version.profile.clone()));
+    VersionRoot { version, web_root }
+}
+
+/// Serves a file from version's web root according to its [ServingProfile], [REDIRECTS_FILE] and [HEADERS_FILE].
+/// Directories are served with their `index.html`. Only GET and HEAD are allowed, same as for [actix_files::Files].
+/// [REDIRECTS_FILE] and [HEADERS_FILE] themselves are not found. Custom headers are set on responses with a file.
+pub(crate) async fn serve_file(request: &HttpRequest, web_root: &WebRoot) -> HttpResponse {
+    let uri = match find_redirect(web_root, request.uri()).await {
+        Some(Rewritten::Redirect(status, location)) => return Rewritten::redirect_response(status, &location),
+        Some(Rewritten::Internal(uri)) => uri,
+        None => request.uri().clone(),
+    };
+    if !matches!(*request.method(), Method::GET | Method::HEAD) {
+        return HttpResponse::new(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    let Some(path) = resolve_path(&web_root.path, uri.path()) else {
+        debug!("Invalid path requested: {}", uri.path());
+        return HttpResponse::new(StatusCode::BAD_REQUEST);
+    };
+    if path == web_root.path.join(REDIRECTS_FILE) || path == web_root.path.join(HEADERS_FILE) {
+        debug!("Refusing to serve configuration file {:?}", path);
+        return serve_error_page(request, web_root, web_root.profile.not_found_page.as_deref(), StatusCode::NOT_FOUND).await;
+    }
+    let profile = &web_root.profile;
+    let is_dir = fs::metadata(&path).await.is_ok_and(|m| m.is_dir());
+    if let Some(location) = profile.redirect(&uri, is_dir) {
+        return HttpResponse::PermanentRedirect().insert_header((header::LOCATION, location)).finish();
+    }
+    let mut path = if is_dir { path.join(INDEX_FILE) } else { path };
+    let mut opened = open_file(request, web_root, &path).await;
+    if profile.clean_urls && opened.as_ref().is_err_and(|e| e.kind() == ErrorKind::NotFound) {
+        path = html_path(path);
+        opened = open_file(request, web_root, &path).await;
+    }
+    match opened {
+        Ok(response) => response,
+        Err(e) if e.kind() == ErrorKind::NotFound => {
+            debug!("Unable to open {:?}: {}", path, e);
+            serve_not_found(request, &uri, web_root).await
+        }
+        Err(e) => {
+            warn!("Unable to open {:?}: {}", path, e);
+            serve_error_page(request, web_root, profile.server_error_page.as_deref(), StatusCode::INTERNAL_SERVER_ERROR).await
+        }
+    }
+}
+
+/// Serves the file compressed if the client accepts it: from precompressed sibling (unless it's older than the file),
+/// or compressed on the fly. Range requests get the uncompressed file, so that ranges are honoured.
+/// Preconditions are checked against version aware ETag, `Cache-Control` comes from version's [CacheRules].
+async fn open_file(request: &HttpRequest, web_root: &WebRoot, path: &Path) -> std::io::Result<HttpResponse> {
+    let file = NamedFile::open_async(path).await?.use_etag(false).use_last_modified(false);
+    let content_type = file.content_type().clone();
+    let metadata = file.metadata().clone();
+    let compressible = is_compressible(content_type.essence_str());
+    let encoding = negotiate(request.headers()).filter(|_| compressible && !request.headers().contains_key(header::RANGE));
+    let sibling = match encoding {
+        Some(encoding) => NamedFile::open_async(encoding.sibling(path)).await.ok().filter(|sibling| is_up_to_date(sibling.metadata(), &metadata)),
+        None => None,
+    };
+    let encoding = encoding.filter(|_| sibling.is_some() || (MIN_COMPRESSED_SIZE..=MAX_COMPRESSED_SIZE).contains(&metadata.len()));
+    let etag = etag(&web_root.version, &metadata, encoding);
+    let last_modified = last_modified(&metadata);
+    let mut response = match (encoding, sibling) {
+        _ if is_not_modified(request, &etag, last_modified) => HttpResponse::NotModified().finish(),
+        (Some(encoding), Some(sibling)) => sibling
+            .use_etag(false)
+            .use_last_modified(false)
+            .set_content_type(content_type)
+            .set_content_encoding(encoding.content_encoding())
+            .into_response(request),
+        (Some(encoding), None) => {
+            let content = web_root.compressed.get(FileKey::new(path, &metadata), encoding).await?;
+            HttpResponse::Ok().content_type(content_type).insert_header((header::CONTENT_ENCODING, encoding.content_encoding())).body(content)
+        }
+        (None, _) => file.into_response(request),
+    };
+    let headers = response.headers_mut();
+    if let Ok(etag) = HeaderValue::from_str(&etag.to_string()) {
+        headers.insert(header::ETAG, etag);
+    }
+    if let Some(Ok(last_modified)) = last_modified.map(|t| HeaderValue::from_str(&HttpDate::from(t).to_string())) {
+        headers.insert(header::LAST_MODIFIED, last_modified);
+    }
+    let relative = relative_path(web_root, path);
+    if let Some(cache_control) = web_root.cache_rules.cache_control(relative) {
+        headers.insert(header::CACHE_CONTROL, cache_control.clone());
     }
+    apply_header_rules(request, web_root, relative, headers);
+    // Version is picked by cookie, shared caches must not serve one version's file to visitors of another.
+    let vary = if compressible { "accept-encoding, cookie" } else { "cookie" };
+    let vary = match headers.get(header::VARY).and_then(|v| v.to_str().ok()) {
+        Some(custom) => merge_vary(custom, vary),
+        None => vary.to_string(),
+    };
+    if let Ok(vary) = HeaderValue::from_str(&vary) {
+        headers.insert(header::VARY, vary);
+    }
+    Ok(response)
+}
+
+/// Appends qpackt's `Vary` values to the ones set by header rules, unless they are already there.
+fn merge_vary(custom: &str, required: &str) -> String {
+    let mut vary = custom.to_string();
+    for value in required.split(", ") {
+        if !custom.split(',').any(|v| v.trim().eq_ignore_ascii_case(value) || v.trim() == "*") {
+            vary.push_str(", ");
+            vary.push_str(value);
+        }
+    }
+    vary
+}
+
+/// Path of the file inside web root, e.g. `docs/index.html`.
+fn relative_path<'a>(web_root: &WebRoot, path: &'a Path) -> &'a str {
+    path.strip_prefix(&web_root.path).ok().and_then(|p| p.to_str()).unwrap_or_default()
+}
+
+/// Global headers, then version's ones. Rules match the served file, so `/` gets headers of `index.html`.
+fn apply_header_rules(request: &HttpRequest, web_root: &WebRoot, relative: &str, headers: &mut HeaderMap) {
+    if let Some(global) = request.app_data::<Data<HeaderRules>>() {
+        global.apply(relative, headers);
+    }
+    web_root.headers.apply(relative, headers);
+}
+
+/// Whether precompressed sibling was written after the file, so it has the same content.
+fn is_up_to_date(sibling: &Metadata, file: &Metadata) -> bool {
+    match (sibling.modified(), file.modified()) {
+        (Ok(sibling), Ok(file)) => sibling >= file,
+        _ => false,
+    }
+}
+
+/// The first [REDIRECTS_FILE] rule applying to the URI. Non-forced rules are skipped if there's a file for the path.
+async fn find_redirect(web_root: &WebRoot, uri: &Uri) -> Option<Rewritten> {
+    let mut shadowed = None;
+    for rule in &web_root.redirects {
+        let Some(rewritten) = rule.apply(uri.path(), uri.query()) else {
+            continue;
+        };
+        if rule.force {
+            return Some(rewritten);
+        }
+        if shadowed.is_none() {
+            shadowed = Some(has_file(web_root, uri.path()).await);
+        }
+        if shadowed == Some(false) {
+            return Some(rewritten);
+        }
+    }
+    None
 }
 
-/// Starts a normal 'Files' server to serve a version.
-async fn start_version_servers(handlers: &mut Vec<VersionServer>, run_dir: &Path) {
-    for handler in handlers {
-        info!("Starting version {} on port {}", handler.version.name, handler.port);
-        start_version_server(handler, run_dir).await;
+/// Whether a file would be served for the path without [REDIRECTS_FILE], such a file shadows its non-forced rules.
+async fn has_file(web_root: &WebRoot, uri_path: &str) -> bool {
+    let Some(path) = resolve_path(&web_root.path, uri_path) else {
+        return false;
+    };
+    let is_file = |path: PathBuf| async move { fs::metadata(path).await.is_ok_and(|m| m.is_file()) };
+    let path = if fs::metadata(&path).await.is_ok_and(|m| m.is_dir()) { path.join(INDEX_FILE) } else { path };
+    is_file(path.clone()).await || (web_root.profile.clean_urls && is_file(html_path(path)).await)
+}
+
+/// `index.html` for [ServingProfile::spa_fallback], 404 page otherwise.
+async fn serve_not_found(request: &HttpRequest, uri: &Uri, web_root: &WebRoot) -> HttpResponse {
+    if web_root.profile.spa_fallback && !is_asset(uri.path()) {
+        if let Ok(response) = open_file(request, web_root, &web_root.path.join(INDEX_FILE)).await {
+            return response;
+        }
     }
+    serve_error_page(request, web_root, web_root.profile.not_found_page.as_deref(), StatusCode::NOT_FOUND).await
 }
 
-async fn start_version_server(version_handler: &mut VersionServer, run_dir: &Path) {
-    let path = run_dir.join(VERSIONS_SUBDIRECTORY).join(&version_handler.version.web_root);
-    let port = version_handler.port;
-    let server = build_server(path, port);
-    let handle = server.handle();
-    tokio::spawn(server);
-    version_handler.server_handle = Some(handle);
+/// Serves the page with given status, or just the status if there is no page.
+async fn serve_error_page(request: &HttpRequest, web_root: &WebRoot, page: Option<&str>, status: StatusCode) -> HttpResponse {
+    let Some(path) = page.and_then(|page| resolve_path(&web_root.path, &format!("/{}", page))) else {
+        return HttpResponse::new(status);
+    };
+    match fs::read(&path).await {
+        Ok(content) => {
+            let mut builder = HttpResponse::build(status);
+            builder.content_type(ContentType::html());
+            let mut response = if request.method() == Method::HEAD { builder.finish() } else { builder.body(content) };
+            apply_header_rules(request, web_root, relative_path(web_root, &path), response.headers_mut());
+            response
+        }
+        Err(e) => {
+            warn!("Unable to read error page {:?}: {}", path, e);
+            HttpResponse::new(status)
+        }
+    }
+}
+
+/// Paths whose last segment has an extension point to files (scripts, images etc.), not to SPA routes.
+fn is_asset(uri_path: &str) -> bool {
+    uri_path.rsplit('/').next().is_some_and(|segment| segment.contains('.'))
 }
 
-fn build_server(path: PathBuf, port: u16) -> Server {
-    HttpServer::new(move || App::new().service(Files::new("/", path.clone()).index_file("index.html")))
-        .bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, port))
-        .unwrap()
-        .run()
+/// `/about` -> `/about.html`
+fn html_path(path: PathBuf) -> PathBuf {
+    let mut path = path.into_os_string();
+    path.push(".html");
+    path.into()
 }
 
-fn build_version_servers(versions: Vec<Version>) -> Vec<VersionServer> {
-    let mut handlers = Vec::with_capacity(versions.len());
-    for (id, version) in versions.into_iter().enumerate() {
-        let port = START_PORT + id as u16;
-        handlers.push(build_version_server(version, port))
+/// Turns request's path into a file system path inside web root.
+/// Returns `None` for paths that try to escape web root or point to hidden files.
+fn resolve_path(web_root: &Path, uri_path: &str) -> Option<PathBuf> {
+    let decoded = percent_decode_str(uri_path).decode_utf8().ok()?;
+    // Encoded slashes (`%2F`) would change segments after decoding.
+    if decoded.matches('/').count() != uri_path.matches('/').count() {
+        return None;
     }
-    handlers
+    let mut path = web_root.to_path_buf();
+    for segment in decoded.split('/').filter(|s| !s.is_empty()) {
+        if segment.starts_with('.') || segment.contains('\\') {
+            return None;
+        }
+        path.push(segment);
+    }
+    Some(path)
 }
 
-fn build_version_server(version: Version, port: u16) -> VersionServer {
-    VersionServer {
-        port,
-        upstream: Arc::new(Url::parse(format!("http://localhost:{}", port).as_str()).unwrap()),
-        version,
-        server_handle: None,
+#[cfg(test)]
+mod test {
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use actix_web::body::{to_bytes, MessageBody};
+    use actix_web::http::{header, Method, StatusCode, Uri};
+    use actix_web::test::TestRequest;
+    use actix_web::web::Data;
+    use tmpdir::TmpDir;
+
+    use crate::cache_rules::CacheRule;
+    use crate::response_headers::{HeaderPreset, HeaderRule, HeaderRules, ResponseHeaders};
+    use crate::server::{is_asset, resolve_path, serve_file, ServingProfile, TrailingSlash, WebRoot};
+
+    #[test]
+    fn resolves_paths_inside_root() {
+        let root = Path::new("/srv/site");
+        assert_eq!(resolve_path(root, "/"), Some(PathBuf::from("/srv/site")));
+        assert_eq!(resolve_path(root, "/css/main.css"), Some(PathBuf::from("/srv/site/css/main.css")));
+        assert_eq!(resolve_path(root, "/a%20b.html"), Some(PathBuf::from("/srv/site/a b.html")));
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        let root = Path::new("/srv/site");
+        assert_eq!(resolve_path(root, "/../etc/passwd"), None);
+        assert_eq!(resolve_path(root, "/%2e%2e/etc/passwd"), None);
+        assert_eq!(resolve_path(root, "/a%2F..%2F..%2Fetc"), None);
+        assert_eq!(resolve_path(root, "/.git/config"), None);
+    }
+
+    #[test]
+    fn redirects_trailing_slashes() {
+        let profile = |trailing_slash| ServingProfile { trailing_slash, ..Default::default() };
+        let uri = |uri: &str| uri.parse::<Uri>().unwrap();
+        assert_eq!(profile(TrailingSlash::Keep).redirect(&uri("/docs"), true), None);
+        assert_eq!(profile(TrailingSlash::Add).redirect(&uri("/docs?a=1"), true), Some("/docs/?a=1".into()));
+        assert_eq!(profile(TrailingSlash::Add).redirect(&uri("/docs.html"), false), None);
+        assert_eq!(profile(TrailingSlash::Remove).redirect(&uri("/docs/"), true), Some("/docs".into()));
+        assert_eq!(profile(TrailingSlash::Remove).redirect(&uri("/"), true), None);
+    }
+
+    #[test]
+    fn tells_assets_from_routes() {
+        assert!(is_asset("/js/app.3f2a.js"));
+        assert!(!is_asset("/users/42"));
+        assert!(!is_asset("/"));
+    }
+
+    #[actix_web::test]
+    async fn serves_spa_fallback_clean_urls_and_error_pages() {
+        let dir = TmpDir::new("site").await.unwrap();
+        for (name, content) in [("index.html", "app"), ("about.html", "about"), ("404.html", "missing")] {
+            tokio::fs::write(dir.to_path_buf().join(name), content).await.unwrap();
+        }
+        let profile = ServingProfile::detect(&dir.to_path_buf());
+        assert_eq!(profile.not_found_page.as_deref(), Some("404.html"));
+        assert_eq!(profile.server_error_page, None);
+        let serve = |profile: &ServingProfile, uri: &str| {
+            let web_root = WebRoot::new("v1".to_string().into(), dir.to_path_buf(), profile.clone());
+            let request = TestRequest::get().uri(uri).to_http_request();
+            async move {
+                let response = serve_file(&request, &web_root).await;
+                let status = response.status();
+                (status, response.into_body().try_into_bytes().unwrap_or_default())
+            }
+        };
+        assert_eq!(serve(&profile, "/users/42").await, (StatusCode::NOT_FOUND, "missing".into()));
+        assert_eq!(serve(&profile, "/about").await.0, StatusCode::NOT_FOUND);
+        let head = TestRequest::default().method(Method::HEAD).uri("/users/42").to_http_request();
+        let response = serve_file(&head, &WebRoot::new("v1".to_string().into(), dir.to_path_buf(), profile.clone())).await;
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "text/html; charset=utf-8");
+        assert!(response.into_body().try_into_bytes().unwrap().is_empty());
+
+        let profile = ServingProfile { spa_fallback: true, clean_urls: true, ..profile };
+        assert_eq!(serve(&profile, "/users/42").await.0, StatusCode::OK);
+        assert_eq!(serve(&profile, "/app.js").await, (StatusCode::NOT_FOUND, "missing".into()));
+        assert_eq!(serve(&profile, "/about").await.0, StatusCode::OK);
+        let web_root = WebRoot::new("v1".to_string().into(), dir.to_path_buf(), ServingProfile { trailing_slash: TrailingSlash::Remove, ..profile });
+        let response = serve_file(&TestRequest::get().uri("/about/").to_http_request(), &web_root).await;
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/about");
+    }
+
+    #[actix_web::test]
+    async fn serves_compressed_files() {
+        let dir = TmpDir::new("site").await.unwrap();
+        let path = dir.to_path_buf();
+        tokio::fs::write(path.join("app.js"), "console.log(1);".repeat(100)).await.unwrap();
+        tokio::fs::write(path.join("app.js.br"), "precompressed").await.unwrap();
+        tokio::fs::write(path.join("style.css"), "body {}\n".repeat(100)).await.unwrap();
+        let web_root = WebRoot::new("v1".to_string().into(), path, ServingProfile::default());
+        let serve = |uri: &str, accept: &'static str| TestRequest::get().uri(uri).insert_header((header::ACCEPT_ENCODING, accept)).to_http_request();
+
+        let response = serve_file(&serve("/app.js", "gzip, br"), &web_root).await;
+        assert_eq!(response.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "precompressed");
+        let response = serve_file(&serve("/style.css", "gzip"), &web_root).await;
+        assert_eq!(response.headers().get(header::CONTENT_ENCODING).unwrap(), "gzip");
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "text/css");
+        let response = serve_file(&serve("/style.css", "identity"), &web_root).await;
+        assert_eq!(response.headers().get(header::CONTENT_ENCODING), None);
+        assert_eq!(response.headers().get(header::VARY).unwrap(), "accept-encoding, cookie");
+    }
+
+    #[actix_web::test]
+    async fn skips_stale_siblings_and_compression_of_ranges() {
+        let dir = TmpDir::new("site").await.unwrap();
+        let path = dir.to_path_buf();
+        tokio::fs::write(path.join("app.js"), "console.log(1);".repeat(100)).await.unwrap();
+        tokio::fs::write(path.join("app.js.br"), "stale").await.unwrap();
+        let old = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        std::fs::File::options().write(true).open(path.join("app.js.br")).unwrap().set_modified(old).unwrap();
+        let web_root = WebRoot::new("v1".to_string().into(), path, ServingProfile::default());
+
+        let request = TestRequest::get().uri("/app.js").insert_header((header::ACCEPT_ENCODING, "br")).to_http_request();
+        let response = serve_file(&request, &web_root).await;
+        assert_eq!(response.headers().get(header::CONTENT_ENCODING).unwrap(), "br");
+        assert_ne!(to_bytes(response.into_body()).await.unwrap(), "stale");
+        let request = TestRequest::get()
+            .uri("/app.js")
+            .insert_header((header::ACCEPT_ENCODING, "br"))
+            .insert_header((header::RANGE, "bytes=0-6"))
+            .to_http_request();
+        let response = serve_file(&request, &web_root).await;
+        assert_eq!(response.status(), StatusCode::PARTIAL_CONTENT);
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none_or(|encoding| encoding == "identity"));
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "console");
+    }
+
+    #[actix_web::test]
+    async fn serves_cache_headers_and_not_modified() {
+        let dir = TmpDir::new("site").await.unwrap();
+        let path = dir.to_path_buf();
+        tokio::fs::create_dir(path.join("assets")).await.unwrap();
+        tokio::fs::write(path.join("assets/app.1a2b.png"), "png").await.unwrap();
+        tokio::fs::write(path.join("index.html"), "index").await.unwrap();
+        let rules = vec![
+            CacheRule { pattern: "assets/**".into(), cache_control: "public, max-age=31536000, immutable".into() },
+            CacheRule { pattern: "*.html".into(), cache_control: "no-cache".into() },
+        ];
+        let profile = ServingProfile { cache_rules: rules, ..Default::default() };
+        let web_root = WebRoot::new("v1".to_string().into(), path.clone(), profile.clone());
+
+        let response = serve_file(&TestRequest::get().uri("/assets/app.1a2b.png").to_http_request(), &web_root).await;
+        assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "public, max-age=31536000, immutable");
+        assert_eq!(response.headers().get(header::VARY).unwrap(), "cookie");
+        let response = serve_file(&TestRequest::get().uri("/").to_http_request(), &web_root).await;
+        assert_eq!(response.headers().get(header::CACHE_CONTROL).unwrap(), "no-cache");
+        let etag = response.headers().get(header::ETAG).unwrap().clone();
+        let last_modified = response.headers().get(header::LAST_MODIFIED).unwrap().clone();
+
+        let request = TestRequest::get().uri("/").insert_header((header::IF_NONE_MATCH, etag.clone())).to_http_request();
+        assert_eq!(serve_file(&request, &web_root).await.status(), StatusCode::NOT_MODIFIED);
+        let request = TestRequest::get().uri("/").insert_header((header::IF_MODIFIED_SINCE, last_modified)).to_http_request();
+        assert_eq!(serve_file(&request, &web_root).await.status(), StatusCode::NOT_MODIFIED);
+        // The same file of another version doesn't match.
+        let other = WebRoot::new("v2".to_string().into(), path, profile);
+        let request = TestRequest::get().uri("/").insert_header((header::IF_NONE_MATCH, etag)).to_http_request();
+        assert_eq!(serve_file(&request, &other).await.status(), StatusCode::OK);
+    }
+
+    #[actix_web::test]
+    async fn applies_redirects_file() {
+        let dir = TmpDir::new("site").await.unwrap();
+        let path = dir.to_path_buf();
+        tokio::fs::write(path.join("index.html"), "index").await.unwrap();
+        tokio::fs::write(path.join("about.html"), "about").await.unwrap();
+        let redirects = "/old /index.html 200\n/blog/* /news/:splat 301\n/index.html /home 302\n/about.html /about-us 302!\n";
+        tokio::fs::write(path.join("_redirects"), redirects).await.unwrap();
+        tokio::fs::write(path.join("_headers"), "/*\n  X-Test: file\n").await.unwrap();
+        let web_root = WebRoot::new("v1".to_string().into(), path, ServingProfile::default());
+
+        let response = serve_file(&TestRequest::get().uri("/old").to_http_request(), &web_root).await;
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "index");
+        let response = serve_file(&TestRequest::get().uri("/blog/a?b=1").to_http_request(), &web_root).await;
+        assert_eq!(response.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/news/a?b=1");
+        // Existing files shadow rules unless they are forced.
+        let response = serve_file(&TestRequest::get().uri("/index.html").to_http_request(), &web_root).await;
+        assert_eq!(to_bytes(response.into_body()).await.unwrap(), "index");
+        let response = serve_file(&TestRequest::get().uri("/about.html").to_http_request(), &web_root).await;
+        assert_eq!(response.headers().get(header::LOCATION).unwrap(), "/about-us");
+        for uri in ["/_redirects", "/_headers"] {
+            let response = serve_file(&TestRequest::get().uri(uri).to_http_request(), &web_root).await;
+            assert_eq!(response.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[actix_web::test]
+    async fn layers_custom_headers() {
+        let dir = TmpDir::new("site").await.unwrap();
+        let path = dir.to_path_buf();
+        tokio::fs::write(path.join("index.html"), "index").await.unwrap();
+        tokio::fs::write(path.join("_headers"), "/*\n  X-Frame-Options: SAMEORIGIN\n  X-Test: file\n  Vary: Origin, Cookie\n").await.unwrap();
+        let csp = BTreeMap::from([("content-security-policy".to_string(), "default-src 'none'".to_string())]);
+        let headers = ResponseHeaders { preset: None, rules: vec![HeaderRule { pattern: "*.html".into(), headers: csp }] };
+        let web_root = WebRoot::new("v1".to_string().into(), path, ServingProfile { headers, ..Default::default() });
+        let global = ResponseHeaders { preset: Some(HeaderPreset::Strict), rules: vec![] };
+        let request = TestRequest::get().uri("/index.html").app_data(Data::new(HeaderRules::new(&[&global]))).to_http_request();
+
+        let response = serve_file(&request, &web_root).await;
+        let headers = response.headers();
+        assert_eq!(headers.get("content-security-policy").unwrap(), "default-src 'none'");
+        assert_eq!(headers.get("x-frame-options").unwrap(), "SAMEORIGIN");
+        assert_eq!(headers.get("x-test").unwrap(), "file");
+        assert_eq!(headers.get("referrer-policy").unwrap(), "no-referrer");
+        assert_eq!(headers.get(header::VARY).unwrap(), "Origin, Cookie, accept-encoding");
+        // Rules match the served file, not the request's path.
+        let request = TestRequest::get().uri("/").to_http_request();
+        let response = serve_file(&request, &web_root).await;
+        assert_eq!(response.headers().get("content-security-policy").unwrap(), "default-src 'none'");
     }
 }
@@ -19,6 +19,8 @@
 
 use crate::tests::build_config_and_run_app;
 use crate::tests::token::get_token;
+use actix_http::ws;
+use actix_http::ws::{Frame, Message};
 use actix_web::dev::Server;
 use actix_web::http::StatusCode;
 use actix_web::web::{Data, Json};
@@ -26,8 +28,12 @@ use actix_web::{web, App, HttpResponse, HttpServer};
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
+use futures::{SinkExt, StreamExt};
 use std::net::{Ipv4Addr, SocketAddrV4};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
 use tokio::sync::mpsc::{channel, Sender};
+use tokio_util::codec::Framed;
 
 /// Gets token
 /// Creates reverse proxy
@@ -70,6 +76,57 @@ fn build_app_server(sender: Sender<SomePayload>) -> Server {
         .run()
 }
 
+/// Gets token
+/// Creates reverse proxy for websockets
+/// Starts websocket echo server
+/// Sends a message through the proxy and waits for the echo
+#[actix_web::test]
+async fn test_reverse_proxy_websocket() {
+    let _dir = build_config_and_run_app().await;
+    let token = get_token().await;
+    let client = Client::new();
+    let request = client
+        .post("http://localhost:9080/proxy")
+        .header("Authorization", format!("Bearer {}", token))
+        .json(&json!({"prefix":"/api/ws", "target":"http://localhost:9998/ws"}));
+    let result = request.send().await.unwrap();
+    assert_eq!(result.status(), StatusCode::OK);
+    let listener = TcpListener::bind(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9998)).await.unwrap();
+    let echo = tokio::spawn(websocket_echo(listener));
+    let (response, mut connection) = awc::Client::new().ws("ws://localhost:8080/api/ws/echo").connect().await.unwrap();
+    assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+    for text in ["hello", "world"] {
+        connection.send(Message::Text(text.into())).await.unwrap();
+        match connection.next().await.unwrap().unwrap() {
+            Frame::Text(received) => assert_eq!(received, text.as_bytes()),
+            other => panic!("Unexpected frame {:?}", other),
+        }
+    }
+    connection.send(Message::Close(None)).await.unwrap();
+    echo.await.unwrap();
+}
+
+/// Accepts a single websocket connection and sends back every text message until it's closed.
+async fn websocket_echo(listener: TcpListener) {
+    let (mut stream, _) = listener.accept().await.unwrap();
+    let mut head = Vec::new();
+    while !head.ends_with(b"\r\n\r\n") {
+        head.push(stream.read_u8().await.unwrap());
+    }
+    let head = String::from_utf8(head).unwrap();
+    assert!(head.starts_with("GET /ws/echo HTTP/1.1\r\n"));
+    let key = head.lines().find_map(|line| line.split_once(": ").filter(|(name, _)| name.eq_ignore_ascii_case("sec-websocket-key"))).unwrap().1;
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        String::from_utf8(ws::hash_key(key.trim().as_bytes()).to_vec()).unwrap()
+    );
+    stream.write_all(response.as_bytes()).await.unwrap();
+    let mut framed = Framed::new(stream, ws::Codec::new());
+    while let Some(Ok(Frame::Text(text))) = framed.next().await {
+        framed.send(Message::Text(String::from_utf8(text.to_vec()).unwrap().into())).await.unwrap();
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
 struct SomePayload {
     var1: String,